@@ -0,0 +1,87 @@
+//! Compare the time to call a virtual function on every element of a ThinVec with mixed types,
+//! before and after `ThinVec::sort_by_type`
+//!
+//! Run with `cargo bench --bench sort_by_type`
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use vptr::*;
+
+trait Shape {
+    fn area(&self) -> f32;
+}
+
+#[vptr(Shape)]
+struct Square(f32);
+impl Shape for Square {
+    fn area(&self) -> f32 {
+        self.0 * self.0
+    }
+}
+
+#[vptr(Shape)]
+struct Rectangle(f32, f32);
+impl Shape for Rectangle {
+    fn area(&self) -> f32 {
+        self.0 * self.1
+    }
+}
+
+#[vptr(Shape)]
+struct Circle(f32);
+impl Shape for Circle {
+    fn area(&self) -> f32 {
+        std::f32::consts::PI * self.0 * self.0
+    }
+}
+
+#[vptr(Shape)]
+struct Triangle(f32, f32);
+impl Shape for Triangle {
+    fn area(&self) -> f32 {
+        self.0 * self.1 / 2.
+    }
+}
+
+const LEN: usize = 1_000_000;
+const ROUNDS: usize = 20;
+
+fn mixed_vec() -> ThinVec<dyn Shape> {
+    let mut v = ThinVec::new();
+    // Simple linear congruential generator, so the order of the types is not predictable
+    let mut seed = 12345u32;
+    for i in 0..LEN {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        let x = (i % 100) as f32;
+        match (seed >> 16) % 4 {
+            0 => v.push(Square(x, VPtr::new())),
+            1 => v.push(Rectangle(x, 2., VPtr::new())),
+            2 => v.push(Circle(x, VPtr::new())),
+            _ => v.push(Triangle(x, 3., VPtr::new())),
+        }
+    }
+    v
+}
+
+/// Returns the fastest of the rounds
+fn time_areas(v: &ThinVec<dyn Shape>) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            let total: f32 = black_box(v).iter().map(|x| x.area()).sum();
+            black_box(total);
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let mut v = mixed_vec();
+    let unsorted = time_areas(&v);
+    v.sort_by_type();
+    let sorted = time_areas(&v);
+    println!("{} elements of 4 types, fastest of {} rounds", LEN, ROUNDS);
+    println!("unsorted:     {:?}", unsorted);
+    println!("sort_by_type: {:?}", sorted);
+}
@@ -0,0 +1,193 @@
+//! Round-trip structs with various layouts through the thin pointers, and check that the
+//! object seen through them is the original one.
+//!
+//! Run with `cargo +nightly fuzz run roundtrip` from the root of the repository.
+
+#![no_main]
+#![allow(dead_code)]
+
+use libfuzzer_sys::fuzz_target;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use vptr::*;
+
+trait Fields {
+    /// Hash of the fields of the object
+    fn fields_hash(&self) -> u64;
+    /// Modify the fields
+    fn bump(&mut self);
+}
+
+trait Other {
+    fn size(&self) -> usize;
+}
+
+fn hash_of(x: &impl Hash) -> u64 {
+    let mut h = DefaultHasher::new();
+    x.hash(&mut h);
+    h.finish()
+}
+
+macro_rules! impl_traits {
+    ($ty:ident, $bump:tt) => {
+        impl Fields for $ty {
+            fn fields_hash(&self) -> u64 {
+                // The VPtr fields do not contribute to the hash
+                hash_of(self)
+            }
+            fn bump(&mut self) {
+                self.$bump = self.$bump.wrapping_add(1);
+            }
+        }
+        impl Other for $ty {
+            fn size(&self) -> usize {
+                core::mem::size_of::<Self>()
+            }
+        }
+    };
+}
+
+#[vptr(Fields, Other)]
+#[derive(Hash, Default)]
+struct Named {
+    a: u8,
+    b: u64,
+    c: u16,
+}
+impl_traits!(Named, c);
+
+#[vptr(Fields, Other)]
+#[derive(Hash)]
+struct Tuple(u8, u32, u8);
+impl_traits!(Tuple, 1);
+
+#[vptr(Other, Fields)]
+#[derive(Hash, Default)]
+struct WithZst {
+    a: (),
+    b: PhantomData<u64>,
+    c: u8,
+    d: [u16; 0],
+}
+impl_traits!(WithZst, c);
+
+#[vptr(Fields, Other)]
+#[repr(C)]
+#[derive(Hash, Default)]
+struct ReprC {
+    a: u8,
+    b: u64,
+    c: u8,
+}
+impl_traits!(ReprC, a);
+
+#[vptr(Fields, Other)]
+#[repr(C, align(64))]
+#[derive(Hash, Default)]
+struct OverAligned {
+    a: [u8; 3],
+    b: u32,
+}
+impl_traits!(OverAligned, b);
+
+#[vptr(Fields, Other)]
+#[derive(Hash, Default)]
+struct Large {
+    a: [u64; 32],
+    b: u8,
+}
+impl_traits!(Large, b);
+
+/// Reads the values of the fields from the fuzzer input, with zeros once it is exhausted
+struct Input<'a>(&'a [u8]);
+impl Input<'_> {
+    fn bytes<const N: usize>(&mut self) -> [u8; N] {
+        let mut r = [0; N];
+        let n = N.min(self.0.len());
+        r[..n].copy_from_slice(&self.0[..n]);
+        self.0 = &self.0[n..];
+        r
+    }
+    fn u8(&mut self) -> u8 {
+        self.bytes::<1>()[0]
+    }
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.bytes())
+    }
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.bytes())
+    }
+    fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.bytes())
+    }
+}
+
+fn check<T>(mut value: T)
+where
+    T: HasVPtr<dyn Fields> + HasVPtr<dyn Other> + Fields + Hash,
+{
+    let expected = hash_of(&value);
+
+    let thin: ThinRef<dyn Fields> = value.as_thin_ref();
+    assert_eq!(thin.fields_hash(), expected);
+    assert!(ThinRef::downcast_ref::<T>(thin).is_some());
+    let raw = ThinRef::into_raw(thin);
+    let thin = unsafe { ThinRef::<dyn Fields>::from_raw(raw) };
+    assert_eq!(thin.fields_hash(), expected);
+    let other: ThinRef<dyn Other> = value.as_thin_ref();
+    assert_eq!(other.size(), core::mem::size_of::<T>());
+
+    let mut thin_mut: ThinRefMut<dyn Fields> = value.as_thin_ref_mut();
+    thin_mut.bump();
+    let bumped = hash_of(&value);
+    let thin: ThinRef<dyn Fields> = value.as_thin_ref();
+    assert_eq!(thin.fields_hash(), bumped);
+
+    let b = ThinBox::<dyn Fields>::from_box(Box::new(value));
+    assert_eq!(b.fields_hash(), bumped);
+    assert_eq!(ThinBox::as_thin_ref(&b).fields_hash(), bumped);
+    let b = ThinBox::cross_cast::<T, dyn Other>(b).ok().unwrap();
+    assert_eq!(b.size(), core::mem::size_of::<T>());
+    let b = ThinBox::cross_cast::<T, dyn Fields>(b).ok().unwrap();
+    let b = ThinBox::into_box(b);
+    assert_eq!(b.fields_hash(), bumped);
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut input = Input(data);
+    match input.u8() % 6 {
+        0 => check(Named {
+            a: input.u8(),
+            b: input.u64(),
+            c: input.u16(),
+            ..Default::default()
+        }),
+        1 => check(Tuple(input.u8(), input.u32(), input.u8(), VPtr::new(), VPtr::new())),
+        2 => check(WithZst {
+            c: input.u8(),
+            ..Default::default()
+        }),
+        3 => check(ReprC {
+            a: input.u8(),
+            b: input.u64(),
+            c: input.u8(),
+            ..Default::default()
+        }),
+        4 => check(OverAligned {
+            a: input.bytes(),
+            b: input.u32(),
+            ..Default::default()
+        }),
+        _ => {
+            let mut l = Large {
+                b: input.u8(),
+                ..Default::default()
+            };
+            for x in l.a.iter_mut() {
+                *x = input.u64();
+            }
+            check(l)
+        }
+    }
+});
@@ -22,12 +22,14 @@ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 extern crate proc_macro;
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::parse::Parser;
+use syn::parse::{ParseStream, Parser};
+use syn::punctuated::Punctuated;
 use syn::{self, spanned::Spanned, AttributeArgs, ItemStruct};
 
 /// Refer to the [documentation of the `vptr` crate](../vptr/index.html#the-vptr-macro)
 #[proc_macro_attribute]
 pub fn vptr(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let attr: TokenStream = quote_bounds(strip_dyn(attr.into())).into();
     let attr = syn::parse_macro_input!(attr as AttributeArgs);
     let item = syn::parse_macro_input!(item as ItemStruct);
     match vptr_impl(attr, item) {
@@ -36,6 +38,550 @@ pub fn vptr(attr: TokenStream, item: TokenStream) -> TokenStream {
     }
 }
 
+/// Refer to the [documentation of the `vptr` crate](../vptr/index.html#foreign-types)
+#[proc_macro_attribute]
+pub fn vptr_newtype(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let attr: TokenStream = quote_bounds(strip_dyn(attr.into())).into();
+    let attr = syn::parse_macro_input!(attr as AttributeArgs);
+    let item = syn::parse_macro_input!(item as ItemStruct);
+    match vptr_newtype_impl(attr, item) {
+        Ok(x) => x,
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// The options of the `#[vptr(...)]` attribute which are not traits
+const FLAGS: &[&str] = &[
+    "eq_by_value",
+    "value_eq",
+    "register",
+    "deref",
+    "builder",
+    "init_in_place",
+    "debug_guard",
+];
+
+/// Refer to the [documentation of the `vptr` crate](../vptr/index.html#thin-reference-fields)
+#[proc_macro_attribute]
+pub fn thin_fields(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let item = syn::parse_macro_input!(item as ItemStruct);
+    if !attr.is_empty() {
+        return syn::Error::new(
+            proc_macro2::TokenStream::from(attr).span(),
+            "thin_fields does not take arguments",
+        )
+        .to_compile_error()
+        .into();
+    }
+    match thin_fields_impl(item) {
+        Ok(x) => x,
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn thin_fields_impl(mut item: ItemStruct) -> Result<TokenStream, syn::Error> {
+    // Use the first lifetime of the struct, or add one
+    let lifetime = match item.generics.lifetimes().next() {
+        Some(l) => l.lifetime.clone(),
+        None => {
+            let l = syn::Lifetime::new("'a", proc_macro2::Span::call_site());
+            item.generics.params.insert(
+                0,
+                syn::GenericParam::Lifetime(syn::LifetimeDef::new(l.clone())),
+            );
+            l
+        }
+    };
+    let mut found = false;
+    for field in item.fields.iter_mut() {
+        let len = field.attrs.len();
+        field.attrs.retain(|a| !a.path.is_ident("thin"));
+        if field.attrs.len() == len {
+            continue;
+        }
+        found = true;
+        match &field.ty {
+            syn::Type::TraitObject(t) => {
+                field.ty = syn::parse_quote!(vptr::ThinRef<#lifetime, #t>);
+            }
+            ty => {
+                return Err(syn::Error::new(
+                    ty.span(),
+                    "the type of a #[thin] field must be a trait object such as `dyn Trait`",
+                ))
+            }
+        }
+    }
+    if !found {
+        return Err(syn::Error::new(
+            item.ident.span(),
+            "thin_fields expects fields with the #[thin] attribute",
+        ));
+    }
+    Ok(quote!(#item).into())
+}
+
+fn vptr_newtype_impl(attr: AttributeArgs, item: ItemStruct) -> Result<TokenStream, syn::Error> {
+    let inner = match &item.fields {
+        syn::Fields::Unnamed(f) if f.unnamed.len() == 1 => f.unnamed[0].ty.clone(),
+        _ => {
+            return Err(syn::Error::new(
+                item.span(),
+                "vptr_newtype expects a tuple struct with a single field",
+            ))
+        }
+    };
+    if let Some(stable) = attr.iter().find(|a| match a {
+        syn::NestedMeta::Meta(syn::Meta::List(l)) => l.path.is_ident("stable"),
+        _ => false,
+    }) {
+        return Err(syn::Error::new(
+            stable.span(),
+            "the stable option is only supported on struct with named fields",
+        ));
+    }
+    if let Some(deref) = attr.iter().find(|a| match a {
+        syn::NestedMeta::Meta(syn::Meta::Path(p)) => p.is_ident("deref"),
+        _ => false,
+    }) {
+        return Err(syn::Error::new(
+            deref.span(),
+            "vptr_newtype already implements Deref to the wrapped type",
+        ));
+    }
+    let vptr_count = attr
+        .iter()
+        .filter(|a| match a {
+            syn::NestedMeta::Meta(syn::Meta::Path(p)) => !FLAGS.iter().any(|f| p.is_ident(f)),
+            _ => true,
+        })
+        .count();
+    let vptrs = (0..vptr_count).map(|_| quote!(vptr::VPtr::new()));
+    let ident = item.ident.clone();
+    let vis = item.vis.clone();
+    let generics = item.generics.clone();
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let result = proc_macro2::TokenStream::from(vptr_impl(attr, item)?);
+    Ok(quote!(#result
+        impl #impl_generics #ident #ty_generics #where_clause {
+            /// Wrap the value
+            #[allow(dead_code)]
+            #vis fn new(inner: #inner) -> Self {
+                #ident(inner, #(#vptrs),*)
+            }
+            /// Return the wrapped value
+            #[allow(dead_code)]
+            #vis fn into_inner(self) -> #inner {
+                self.0
+            }
+        }
+        impl #impl_generics core::convert::From<#inner> for #ident #ty_generics #where_clause {
+            fn from(inner: #inner) -> Self {
+                Self::new(inner)
+            }
+        }
+        impl #impl_generics core::ops::Deref for #ident #ty_generics #where_clause {
+            type Target = #inner;
+            fn deref(&self) -> &#inner {
+                &self.0
+            }
+        }
+        impl #impl_generics core::ops::DerefMut for #ident #ty_generics #where_clause {
+            fn deref_mut(&mut self) -> &mut #inner {
+                &mut self.0
+            }
+        }
+    )
+    .into())
+}
+
+/// Remove the `dyn` keywords so that `#[vptr(dyn Trait)]` can be parsed as a meta attribute
+fn strip_dyn(attr: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    // Only the `dyn` at the start of each top-level segment is removed, so that the ones in
+    // generic arguments such as `Trait<Box<dyn Any>>` are kept
+    let mut result = Vec::new();
+    let mut depth = 0;
+    let mut after_minus = false;
+    let mut segment_start = true;
+    for t in attr {
+        match &t {
+            proc_macro2::TokenTree::Ident(i) if segment_start && i == "dyn" => continue,
+            proc_macro2::TokenTree::Punct(p) => {
+                match p.as_char() {
+                    '<' => depth += 1,
+                    '>' if !after_minus => depth -= 1,
+                    ',' if depth == 0 => {
+                        result.push(t);
+                        segment_start = true;
+                        after_minus = false;
+                        continue;
+                    }
+                    _ => {}
+                }
+                after_minus = p.as_char() == '-' && p.spacing() == proc_macro2::Spacing::Joint;
+            }
+            // The traits within `cfg(...)` and `c_abi(...)`
+            proc_macro2::TokenTree::Group(g)
+                if depth == 0
+                    && matches!(result.last(), Some(proc_macro2::TokenTree::Ident(i)) if i == "cfg" || i == "c_abi") =>
+            {
+                let mut n = proc_macro2::Group::new(g.delimiter(), strip_dyn(g.stream()));
+                n.set_span(g.span());
+                result.push(proc_macro2::TokenTree::Group(n));
+                segment_start = false;
+                after_minus = false;
+                continue;
+            }
+            _ => after_minus = false,
+        }
+        segment_start = false;
+        result.push(t);
+    }
+    result.into_iter().collect()
+}
+
+/// Put in quotes the traits which cannot be parsed as a meta attribute, such as `Trait<u64>`
+/// or `Trait + Send`, so that they are handled like the quoted form
+fn quote_bounds(attr: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    let mut result = proc_macro2::TokenStream::new();
+    let mut segment = Vec::new();
+    let mut depth = 0;
+    let mut after_minus = false;
+    for t in attr {
+        if let proc_macro2::TokenTree::Punct(p) = &t {
+            match p.as_char() {
+                '<' => depth += 1,
+                // `->` is not a closing angle bracket
+                '>' if !after_minus => depth -= 1,
+                ',' if depth == 0 => {
+                    result.extend(quote_segment(std::mem::take(&mut segment)));
+                    result.extend(Some(t));
+                    continue;
+                }
+                _ => {}
+            }
+            after_minus = p.as_char() == '-' && p.spacing() == proc_macro2::Spacing::Joint;
+        } else {
+            after_minus = false;
+        }
+        segment.push(t);
+    }
+    result.extend(quote_segment(segment));
+    result
+}
+
+fn quote_segment(segment: Vec<proc_macro2::TokenTree>) -> proc_macro2::TokenStream {
+    use proc_macro2::TokenTree;
+    if let [TokenTree::Ident(i), TokenTree::Group(g)] = &segment[..] {
+        if (i == "cfg" || i == "c_abi") && g.delimiter() == proc_macro2::Delimiter::Parenthesis {
+            let mut n = proc_macro2::Group::new(g.delimiter(), quote_bounds(g.stream()));
+            n.set_span(g.span());
+            return quote!(#i #n);
+        }
+    }
+    let span = segment
+        .first()
+        .map_or_else(proc_macro2::Span::call_site, |t| t.span());
+    let tokens: proc_macro2::TokenStream = segment.into_iter().collect();
+    if syn::parse2::<syn::NestedMeta>(tokens.clone()).is_err()
+        && parse_bounds.parse2(tokens.clone()).is_ok()
+    {
+        let lit = syn::LitStr::new(&tokens.to_string(), span);
+        return quote!(#lit);
+    }
+    tokens
+}
+
+/// Replace the given lifetimes by `'static`
+///
+/// The static VTABLE cannot use the generic lifetimes of the struct, but the vtable does not
+/// depend on lifetimes.
+fn make_static(
+    tokens: proc_macro2::TokenStream,
+    lifetimes: &[&syn::Ident],
+) -> proc_macro2::TokenStream {
+    let mut result = proc_macro2::TokenStream::new();
+    let mut iter = tokens.into_iter().peekable();
+    while let Some(t) = iter.next() {
+        match t {
+            proc_macro2::TokenTree::Punct(p) if p.as_char() == '\'' => match iter.peek() {
+                Some(proc_macro2::TokenTree::Ident(i)) if lifetimes.contains(&i) => {
+                    iter.next();
+                    result.extend(quote!('static));
+                }
+                _ => result.extend(Some(proc_macro2::TokenTree::Punct(p))),
+            },
+            proc_macro2::TokenTree::Group(g) => {
+                let mut n =
+                    proc_macro2::Group::new(g.delimiter(), make_static(g.stream(), lifetimes));
+                n.set_span(g.span());
+                result.extend(Some(proc_macro2::TokenTree::Group(n)));
+            }
+            t => result.extend(Some(t)),
+        }
+    }
+    result
+}
+
+type Bounds = Punctuated<syn::TypeParamBound, syn::Token![+]>;
+
+/// A trait given to the `#[vptr(...)]` attribute
+struct TraitAttr {
+    /// The path of the trait
+    path: syn::Path,
+    /// The trait and the extra bounds, as in `dyn #bounds`
+    bounds: Bounds,
+    /// The condition from `cfg(condition, Trait)`
+    cfg: Option<syn::NestedMeta>,
+    /// The methods from `c_abi(Trait, "fn method(&self)", ...)`
+    c_abi: Vec<syn::Signature>,
+}
+
+/// Parse `Trait + Bounds`, with an optional leading `dyn`
+fn parse_bounds(input: ParseStream) -> Result<Bounds, syn::Error> {
+    let _: Option<syn::Token![dyn]> = input.parse()?;
+    Bounds::parse_separated_nonempty(input)
+}
+
+fn parse_trait(a: &syn::NestedMeta) -> Result<(syn::Path, Bounds), syn::Error> {
+    if let syn::NestedMeta::Meta(syn::Meta::Path(i)) = a {
+        Ok((i.clone(), syn::parse_quote!(#i)))
+    } else if let syn::NestedMeta::Lit(syn::Lit::Str(lit_str)) = a {
+        let bounds = lit_str.parse_with(parse_bounds)?;
+        match bounds.first() {
+            Some(syn::TypeParamBound::Trait(t)) => Ok((t.path.clone(), bounds)),
+            _ => Err(syn::Error::new(
+                lit_str.span(),
+                "the first bound must be the trait",
+            )),
+        }
+    } else {
+        Err(syn::Error::new(
+            a.span(),
+            "attribute of vptr must be a trait",
+        ))
+    }
+}
+
+fn parse_trait_attr(a: &syn::NestedMeta) -> Result<TraitAttr, syn::Error> {
+    if let syn::NestedMeta::Meta(syn::Meta::List(l)) = a {
+        if l.path.is_ident("cfg") {
+            if l.nested.len() != 2 {
+                return Err(syn::Error::new(
+                    l.span(),
+                    "expected `cfg(condition, Trait)`",
+                ));
+            }
+            let (path, bounds) = parse_trait(&l.nested[1])?;
+            return Ok(TraitAttr {
+                path,
+                bounds,
+                cfg: Some(l.nested[0].clone()),
+                c_abi: Vec::new(),
+            });
+        }
+        if l.path.is_ident("c_abi") {
+            let mut nested = l.nested.iter();
+            let (path, bounds) = parse_trait(nested.next().ok_or_else(|| {
+                syn::Error::new(
+                    l.span(),
+                    "expected `c_abi(Trait, \"fn method(&self)\", ...)`",
+                )
+            })?)?;
+            let c_abi = parse_signatures(nested)?;
+            return Ok(TraitAttr {
+                path,
+                bounds,
+                cfg: None,
+                c_abi,
+            });
+        }
+    }
+    let (path, bounds) = parse_trait(a)?;
+    Ok(TraitAttr {
+        path,
+        bounds,
+        cfg: None,
+        c_abi: Vec::new(),
+    })
+}
+
+/// Parse the signatures of methods written in quotes, as in `c_abi(Trait, "fn method(&self)")`
+fn parse_signatures<'a>(
+    nested: impl Iterator<Item = &'a syn::NestedMeta>,
+) -> Result<Vec<syn::Signature>, syn::Error> {
+    nested
+        .map(|m| {
+            if let syn::NestedMeta::Lit(syn::Lit::Str(lit_str)) = m {
+                lit_str.parse::<syn::Signature>()
+            } else {
+                Err(syn::Error::new(
+                    m.span(),
+                    "expected the signature of a method in quotes",
+                ))
+            }
+        })
+        .collect()
+}
+
+/// Check that the method can be called from C, and return whether it takes `&mut self`
+fn c_abi_receiver_is_mut(sig: &syn::Signature) -> Result<bool, syn::Error> {
+    if !sig.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            sig.generics.span(),
+            "c_abi does not support generic methods",
+        ));
+    }
+    match sig.inputs.first() {
+        Some(syn::FnArg::Receiver(syn::Receiver {
+            reference: Some(_),
+            mutability,
+            ..
+        })) => Ok(mutability.is_some()),
+        _ => Err(syn::Error::new(
+            sig.span(),
+            "c_abi methods must take `&self` or `&mut self`",
+        )),
+    }
+}
+
+/// The arguments of the method, after the receiver, named `arg1`, `arg2`, ...
+fn c_abi_args(sig: &syn::Signature) -> (Vec<syn::Ident>, Vec<&syn::Type>) {
+    sig.inputs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, arg)| match arg {
+            syn::FnArg::Typed(pat) => Some((quote::format_ident!("arg{}", i), &*pat.ty)),
+            syn::FnArg::Receiver(_) => None,
+        })
+        .unzip()
+}
+
+/// Generate the `extern "C"` functions for the methods listed in `c_abi(...)`
+fn c_abi_trampolines(
+    ident: &syn::Ident,
+    vis: &syn::Visibility,
+    trait_: &syn::Path,
+    bounds: &Bounds,
+    c_abi: &[syn::Signature],
+) -> Result<proc_macro2::TokenStream, syn::Error> {
+    let mut result = quote!();
+    for sig in c_abi {
+        let (this_ty, thin_ty, this_ref, mutability) = if c_abi_receiver_is_mut(sig)? {
+            (
+                quote!(*mut core::ffi::c_void),
+                quote!(vptr::ThinRefMut),
+                quote!(&mut *),
+                quote!(mut),
+            )
+        } else {
+            (
+                quote!(*const core::ffi::c_void),
+                quote!(vptr::ThinRef),
+                quote!(&*),
+                quote!(),
+            )
+        };
+        let (arg_names, arg_types) = c_abi_args(sig);
+        let method = &sig.ident;
+        let output = &sig.output;
+        let name = quote::format_ident!("{}_{}", ident, method);
+        let doc = format!(
+            "C ABI function calling `{}::{}` through a thin pointer to a `{}`",
+            quote!(#trait_),
+            method,
+            ident
+        );
+        result = quote!(#result
+            #[doc = #doc]
+            #[allow(non_snake_case)]
+            #vis unsafe extern "C" fn #name(this: #this_ty, #(#arg_names: #arg_types),*) #output {
+                let #mutability this = #thin_ty::<dyn #bounds>::from_raw(this as _);
+                <dyn #bounds as #trait_>::#method(#this_ref this, #(#arg_names),*)
+            }
+        );
+    }
+    Ok(result)
+}
+
+/// The `stable(Trait, VTable, "fn method(&self)", ...)` option
+struct StableAttr {
+    span: proc_macro2::Span,
+    trait_: syn::Path,
+    vtable: syn::Path,
+    field_name: syn::Ident,
+    methods: Vec<syn::Signature>,
+}
+
+fn parse_stable_attr(l: &syn::MetaList) -> Result<StableAttr, syn::Error> {
+    let mut nested = l.nested.iter();
+    let mut next_path = || match nested.next() {
+        Some(syn::NestedMeta::Meta(syn::Meta::Path(p))) => Ok(p.clone()),
+        _ => Err(syn::Error::new(
+            l.span(),
+            "expected `stable(Trait, VTable, \"fn method(&self)\", ...)`",
+        )),
+    };
+    let trait_ = next_path()?;
+    let vtable = next_path()?;
+    let field_name = quote::format_ident!("vptr_{}", vtable.segments.last().unwrap().ident);
+    Ok(StableAttr {
+        span: l.span(),
+        trait_,
+        vtable,
+        field_name,
+        methods: parse_signatures(nested)?,
+    })
+}
+
+/// Generate the implementation of HasStableVPtr, with the `extern "C"` functions of the vtable
+fn stable_vtable(
+    ident: &syn::Ident,
+    attr: &StableAttr,
+) -> Result<proc_macro2::TokenStream, syn::Error> {
+    let StableAttr {
+        trait_,
+        vtable,
+        field_name,
+        methods,
+        ..
+    } = attr;
+    let entries = methods
+        .iter()
+        .map(|sig| {
+            let (this_ty, this_ref) = if c_abi_receiver_is_mut(sig)? {
+                (quote!(*mut core::ffi::c_void), quote!(&mut *))
+            } else {
+                (quote!(*const core::ffi::c_void), quote!(&*))
+            };
+            let (arg_names, arg_types) = c_abi_args(sig);
+            let method = &sig.ident;
+            let output = &sig.output;
+            Ok(quote!(#method: {
+                unsafe extern "C" fn #method(this: #this_ty, #(#arg_names: #arg_types),*) #output {
+                    let offset = core::mem::offset_of!(#ident, #field_name) as isize;
+                    let this = (this as *mut u8).wrapping_offset(-offset) as *mut #ident;
+                    <#ident as #trait_>::#method(#this_ref this, #(#arg_names),*)
+                }
+                #method
+            }))
+        })
+        .collect::<Result<Vec<_>, syn::Error>>()?;
+    Ok(quote!(
+        unsafe impl vptr::HasStableVPtr<#vtable> for #ident {
+            fn init() -> &'static vptr::stable::StableVTableData<#vtable> {
+                static DATA: vptr::stable::StableVTableData<#vtable> = vptr::stable::StableVTableData {
+                    offset: core::mem::offset_of!(#ident, #field_name) as isize,
+                    vtable: #vtable { #(#entries,)* },
+                };
+                &DATA
+            }
+
+            fn get_stable_vptr(&self) -> &vptr::StableVPtr<Self, #vtable> { &self.#field_name }
+        }
+    ))
+}
+
 fn vptr_impl(attr: AttributeArgs, item: ItemStruct) -> Result<TokenStream, syn::Error> {
     let ItemStruct {
         attrs,
@@ -47,45 +593,160 @@ fn vptr_impl(attr: AttributeArgs, item: ItemStruct) -> Result<TokenStream, syn::
         semi_token,
     } = item;
 
+    let mut eq_by_value = false;
+    let mut value_eq = false;
+    let mut register = false;
+    let mut init_in_place = false;
+    let mut debug_guard = None;
+    let mut stable = Vec::new();
+    let mut deref = None;
+    let mut builder = None;
     let attr = attr
         .iter()
-        .map(|a| {
-            if let syn::NestedMeta::Meta(syn::Meta::Path(i)) = a {
-                Ok(i.clone())
-            } else if let syn::NestedMeta::Lit(syn::Lit::Str(lit_str)) = a {
-                lit_str.parse::<syn::Path>()
-            } else {
-                Err(syn::Error::new(
-                    a.span(),
-                    "attribute of vptr must be a trait",
-                ))
+        .filter(|a| match a {
+            syn::NestedMeta::Meta(syn::Meta::Path(p)) if p.is_ident("eq_by_value") => {
+                eq_by_value = true;
+                false
+            }
+            syn::NestedMeta::Meta(syn::Meta::Path(p)) if p.is_ident("value_eq") => {
+                value_eq = true;
+                false
+            }
+            syn::NestedMeta::Meta(syn::Meta::Path(p)) if p.is_ident("register") => {
+                register = true;
+                false
+            }
+            syn::NestedMeta::Meta(syn::Meta::Path(p)) if p.is_ident("init_in_place") => {
+                init_in_place = true;
+                false
+            }
+            syn::NestedMeta::Meta(syn::Meta::List(l)) if l.path.is_ident("stable") => {
+                stable.push(l.clone());
+                false
+            }
+            syn::NestedMeta::Meta(syn::Meta::Path(p)) if p.is_ident("debug_guard") => {
+                debug_guard = Some(p.span());
+                false
+            }
+            syn::NestedMeta::Meta(syn::Meta::Path(p)) if p.is_ident("deref") => {
+                deref = Some(p.span());
+                false
+            }
+            syn::NestedMeta::Meta(syn::Meta::Path(p)) if p.is_ident("builder") => {
+                builder = Some(p.span());
+                false
             }
+            _ => true,
         })
+        .map(parse_trait_attr)
         .collect::<Result<Vec<_>, _>>()?;
 
     if let Some(tp) = generics.type_params().next() {
         return Err(syn::Error::new(tp.span(), "vptr does not support generics"));
     }
 
+    let stable = stable
+        .iter()
+        .map(parse_stable_attr)
+        .collect::<Result<Vec<_>, _>>()?;
+    if let (Some(s), Some(lt)) = (stable.first(), generics.lifetimes().next()) {
+        return Err(syn::Error::new(
+            s.span,
+            format!(
+                "the stable option does not support structs with lifetimes such as {}",
+                lt.lifetime
+            ),
+        ));
+    }
+
+    if let Some(span) = deref {
+        if attr.len() != 1 || attr[0].cfg.is_some() {
+            return Err(syn::Error::new(
+                span,
+                "the deref option requires exactly one trait, without cfg",
+            ));
+        }
+    }
+
+    for a in attrs.iter().filter(|a| a.path.is_ident("repr")) {
+        if let Ok(syn::Meta::List(l)) = a.parse_meta() {
+            let packed = l.nested.iter().find(|n| match n {
+                syn::NestedMeta::Meta(m) => m.path().is_ident("packed"),
+                _ => false,
+            });
+            if let Some(packed) = packed {
+                return Err(syn::Error::new(
+                    packed.span(),
+                    "vptr does not support packed structs, because the VPtr fields would not be \
+                     properly aligned for the thin references to read them",
+                ));
+            }
+        }
+    }
+
     let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let mut static_generics = generics.clone();
+    static_generics
+        .make_where_clause()
+        .predicates
+        .push(syn::parse_quote!(Self: 'static));
+    let static_where_clause = &static_generics.where_clause;
+
+    let builder_fields: Vec<syn::Field> = match (&fields, builder) {
+        (syn::Fields::Named(n), Some(_)) => n.named.iter().cloned().collect(),
+        (_, Some(span)) => {
+            return Err(syn::Error::new(
+                span,
+                "the builder option is only supported on struct with named fields",
+            ))
+        }
+        (_, None) => Vec::new(),
+    };
 
     let (fields, attr_with_names) = if let syn::Fields::Named(mut n) = fields {
         let attr_with_names: Vec<_> = attr
             .iter()
             .map(|t| {
-                let field_name = quote::format_ident!("vptr_{}", t.segments.last().unwrap().ident);
+                let field_name =
+                    quote::format_ident!("vptr_{}", t.path.segments.last().unwrap().ident);
                 (t, quote! { #field_name })
             })
             .collect();
         let parser = syn::Field::parse_named;
-        for (trait_, field_name) in &attr_with_names {
-            n.named
-                .push(parser.parse(
-                    quote!(#field_name : vptr::VPtr<#ident #ty_generics, dyn #trait_>).into(),
-                )?);
+        for (TraitAttr { bounds, cfg, .. }, field_name) in &attr_with_names {
+            let cfg = cfg.iter();
+            n.named.push(parser.parse(
+                quote!(#(#[cfg(#cfg)])* #field_name : vptr::VPtr<#ident #ty_generics, dyn #bounds>)
+                    .into(),
+            )?);
+        }
+        for StableAttr {
+            vtable, field_name, ..
+        } in &stable
+        {
+            n.named.push(parser.parse(
+                quote!(#field_name : vptr::StableVPtr<#ident #ty_generics, #vtable>).into(),
+            )?);
+        }
+        if debug_guard.is_some() {
+            n.named.push(
+                parser.parse(quote!(vptr_guard : vptr::DebugGuard<#ident #ty_generics>).into())?,
+            );
         }
         (syn::Fields::Named(n), attr_with_names)
     } else {
+        if let Some(span) = debug_guard {
+            return Err(syn::Error::new(
+                span,
+                "the debug_guard option is only supported on struct with named fields",
+            ));
+        }
+        if let Some(s) = stable.first() {
+            return Err(syn::Error::new(
+                s.span,
+                "the stable option is only supported on struct with named fields",
+            ));
+        }
         let mut n = if let syn::Fields::Unnamed(n) = fields {
             n
         } else {
@@ -94,11 +755,30 @@ fn vptr_impl(attr: AttributeArgs, item: ItemStruct) -> Result<TokenStream, syn::
                 unnamed: Default::default(),
             }
         };
+        // The VPtr fields are accessed by index, which would be wrong if a field before
+        // them is removed by cfg
+        if let Some(a) = n
+            .unnamed
+            .iter()
+            .flat_map(|f| f.attrs.iter())
+            .find(|a| a.path.is_ident("cfg"))
+        {
+            return Err(syn::Error::new(
+                a.span(),
+                "cfg on fields is only supported on struct with named fields",
+            ));
+        }
         let count = n.unnamed.len();
         let parser = syn::Field::parse_unnamed;
-        for trait_ in &attr {
+        for TraitAttr { bounds, cfg, .. } in &attr {
+            if let Some(cfg) = cfg {
+                return Err(syn::Error::new(
+                    cfg.span(),
+                    "cfg is only supported on struct with named fields",
+                ));
+            }
             n.unnamed
-                .push(parser.parse(quote!(vptr::VPtr<#ident #ty_generics, dyn #trait_>).into())?);
+                .push(parser.parse(quote!(vptr::VPtr<#ident #ty_generics, dyn #bounds>).into())?);
         }
         let attr_with_names: Vec<_> = attr
             .iter()
@@ -115,26 +795,255 @@ fn vptr_impl(attr: AttributeArgs, item: ItemStruct) -> Result<TokenStream, syn::
         #(#attrs)* #[allow(non_snake_case)] #vis #struct_token #ident #generics  #fields  #semi_token
     );
 
-    for (trait_, field_name) in attr_with_names {
+    let vptr_count = attr_with_names.iter().map(|(t, _)| match &t.cfg {
+        Some(cfg) => quote!((if cfg!(#cfg) { 1 } else { 0 })),
+        None => quote!(1),
+    });
+    result = quote!(#result
+        impl #impl_generics #ident #ty_generics #where_clause {
+            /// The number of bytes added to this struct by the VPtr fields
+            #[doc(hidden)]
+            #[allow(dead_code)]
+            #vis const VPTR_OVERHEAD_BYTES: usize =
+                (0 #(+ #vptr_count)*) * core::mem::size_of::<usize>();
+        }
+    );
+
+    if init_in_place {
+        let init_vptrs = attr_with_names.iter().map(|(t, field_name)| {
+            let cfg = t.cfg.iter();
+            quote!(#(#[cfg(#cfg)])* core::ptr::addr_of_mut!((*this).#field_name).write(vptr::VPtr::new());)
+        });
+        let init_stable = stable.iter().map(|StableAttr { field_name, .. }| {
+            quote!(core::ptr::addr_of_mut!((*this).#field_name).write(vptr::StableVPtr::new());)
+        });
+        let init_guard = debug_guard.map(
+            |_| quote!(core::ptr::addr_of_mut!((*this).vptr_guard).write(vptr::DebugGuard::new());),
+        );
+        result = quote!(#result
+            impl #impl_generics #ident #ty_generics #where_clause {
+                /// Initialize the VPtr fields of a possibly uninitialized instance of this struct.
+                ///
+                /// # Safety
+                ///
+                /// `this` must be valid for writes and properly aligned
+                #[allow(dead_code)]
+                #vis unsafe fn init_vptrs_in_place(this: *mut Self) {
+                    #(#init_vptrs)*
+                    #(#init_stable)*
+                    #init_guard
+                }
+            }
+        );
+    }
+
+    if eq_by_value || value_eq {
+        let thin_eq = if eq_by_value {
+            quote!(
+                /// Returns true if both thin references point to an instance of this struct
+                /// and these instances are equal
+                #[allow(dead_code)]
+                #vis fn thin_eq<Trait: ?Sized>(a: vptr::ThinRef<'_, Trait>, b: vptr::ThinRef<'_, Trait>) -> bool
+                where
+                    Self: vptr::HasVPtr<Trait> + vptr::DowncastTarget + PartialEq,
+                {
+                    Self::thin_refs_equal(a, b) == Some(true)
+                }
+            )
+        } else {
+            quote!()
+        };
+        result = quote!(#result
+            impl #impl_generics #ident #ty_generics #where_clause {
+                /// Compares the objects if both thin references point to an instance of this
+                /// struct, or returns None if one of them does not
+                #[allow(dead_code)]
+                #vis fn thin_refs_equal<Trait: ?Sized>(a: vptr::ThinRef<'_, Trait>, b: vptr::ThinRef<'_, Trait>) -> Option<bool>
+                where
+                    Self: vptr::HasVPtr<Trait> + vptr::DowncastTarget + PartialEq,
+                {
+                    Some(vptr::ThinRef::downcast_ref::<Self>(a)? == vptr::ThinRef::downcast_ref::<Self>(b)?)
+                }
+                #thin_eq
+            }
+        );
+    }
+
+    if builder.is_some() {
+        let builder_ident = quote::format_ident!("{}Builder", ident);
+        let names: Vec<_> = builder_fields.iter().map(|f| &f.ident).collect();
+        let tys: Vec<_> = builder_fields.iter().map(|f| &f.ty).collect();
+        let cfgs: Vec<Vec<_>> = builder_fields
+            .iter()
+            .map(|f| f.attrs.iter().filter(|a| a.path.is_ident("cfg")).collect())
+            .collect();
+        let setter_docs = names.iter().map(|n| {
+            format!(
+                "Set the value of the `{}` field",
+                n.as_ref().map(|n| n.to_string()).unwrap_or_default()
+            )
+        });
+        let init_vptrs = attr_with_names.iter().map(|(t, field_name)| {
+            let cfg = t.cfg.iter();
+            quote!(#(#[cfg(#cfg)])* #field_name: vptr::VPtr::new(),)
+        });
+        let init_stable = stable
+            .iter()
+            .map(|StableAttr { field_name, .. }| quote!(#field_name: vptr::StableVPtr::new(),));
+        let init_guard = debug_guard.map(|_| quote!(vptr_guard: vptr::DebugGuard::new(),));
+        let builder_doc = format!(
+            "Builder for [`{0}`], which initializes its VPtr fields. Created with [`{0}::builder`]",
+            ident
+        );
+        result = quote!(#result
+            #[doc = #builder_doc]
+            #vis struct #builder_ident #generics #where_clause {
+                #( #(#cfgs)* #names: core::option::Option<#tys>, )*
+            }
+
+            impl #impl_generics core::default::Default for #builder_ident #ty_generics #where_clause {
+                fn default() -> Self {
+                    Self { #( #(#cfgs)* #names: core::option::Option::None, )* }
+                }
+            }
+
+            impl #impl_generics #builder_ident #ty_generics #where_clause {
+                #(
+                    #(#cfgs)*
+                    #[doc = #setter_docs]
+                    #vis fn #names(mut self, value: #tys) -> Self {
+                        self.#names = core::option::Option::Some(value);
+                        self
+                    }
+                )*
+
+                /// Build the struct, or return an error if a field was not set
+                #vis fn build(self) -> core::result::Result<#ident #ty_generics, vptr::UninitializedFieldError> {
+                    core::result::Result::Ok(#ident {
+                        #(
+                            #(#cfgs)*
+                            #names: self.#names.ok_or(vptr::UninitializedFieldError::new(stringify!(#names)))?,
+                        )*
+                        #(#init_vptrs)*
+                        #(#init_stable)*
+                        #init_guard
+                    })
+                }
+            }
+
+            impl #impl_generics #ident #ty_generics #where_clause {
+                /// Create a builder for this struct
+                #[allow(dead_code)]
+                #vis fn builder() -> #builder_ident #ty_generics {
+                    core::default::Default::default()
+                }
+            }
+        );
+    }
+
+    for s in &stable {
+        result.extend(stable_vtable(&ident, s)?);
+    }
+
+    if debug_guard.is_some() {
+        let offsets = attr_with_names.iter().map(|(t, field_name)| {
+            let cfg = t.cfg.iter();
+            quote!(#(#[cfg(#cfg)])* f(core::mem::offset_of!(Self, #field_name));)
+        });
         result = quote!(#result
-            unsafe impl #impl_generics vptr::HasVPtr<dyn #trait_> for #ident #ty_generics #where_clause {
+            unsafe impl #impl_generics vptr::internal::DebugGuarded for #ident #ty_generics #where_clause {
+                const GUARD_OFFSET: usize = core::mem::offset_of!(Self, vptr_guard);
+                fn vptr_offsets(f: &mut dyn FnMut(usize)) {
+                    #(#offsets)*
+                }
+            }
+        );
+    }
+
+    if deref.is_some() {
+        let bounds = &attr[0].bounds;
+        result = quote!(#result
+            impl #impl_generics core::ops::Deref for #ident #ty_generics #static_where_clause {
+                type Target = dyn #bounds;
+                fn deref(&self) -> &Self::Target {
+                    vptr::ThinRef::get(vptr::HasVPtr::<dyn #bounds>::as_thin_ref(self))
+                }
+            }
+        );
+    }
+
+    let lifetimes: Vec<_> = generics.lifetimes().map(|l| &l.lifetime.ident).collect();
+    if lifetimes.is_empty() {
+        result = quote!(#result
+            // Safety: without lifetime parameters, the VTableData are only used by this type
+            unsafe impl vptr::DowncastTarget for #ident {}
+        );
+    }
+    let static_ty = make_static(quote!(#ident #ty_generics), &lifetimes);
+    let register = if register {
+        if let Some(l) = generics.lifetimes().next() {
+            // The TypeId would be the one of the 'static version of the struct, which would
+            // allow to extend the lifetime with ThinRef::downcast_ref_any
+            return Err(syn::Error::new(
+                l.span(),
+                "the register option does not support structs with lifetimes",
+            ));
+        }
+        quote!(
+            static REGISTER: vptr::internal::Once = vptr::internal::Once::new();
+            // Safety: VTABLE is the VTableData of this type
+            REGISTER.call_once(|| unsafe {
+                vptr::internal::register_type(&VTABLE, core::any::TypeId::of::<#static_ty>())
+            });
+        )
+    } else {
+        quote!()
+    };
+    for (
+        TraitAttr {
+            path: trait_,
+            bounds,
+            cfg,
+            c_abi,
+        },
+        field_name,
+    ) in attr_with_names
+    {
+        let cfg = cfg.iter().map(|c| quote!(#[cfg(#c)]));
+        let cfg = quote!(#(#cfg)*);
+        result.extend(c_abi_trampolines(&ident, &vis, trait_, bounds, c_abi)?);
+        let static_bounds = make_static(quote!(#bounds), &lifetimes);
+        result = quote!(#result
+            // Cross-check offset_of! with the address computation of field_offset!, so that a
+            // layout computation error would be a build error rather than a bad VTableData
+            #cfg
+            const _: () = assert!(
+                vptr::field_offset!(#static_ty, #field_name) as usize
+                    == core::mem::offset_of!(#static_ty, #field_name),
+                "the offset of the vptr field does not match offset_of!"
+            );
+            #cfg
+            unsafe impl #impl_generics vptr::HasVPtr<dyn #bounds> for #ident #ty_generics #where_clause {
                 fn init() -> &'static vptr::VTableData {
-                    use vptr::internal::{TransmuterTO, TransmuterPtr};
                     static VTABLE : vptr::VTableData = vptr::VTableData{
-                        offset: unsafe {
-                            let x: &'static #ident  = TransmuterPtr { int: 0 }.ptr;
-                            TransmuterPtr { ptr: &x.#field_name }.int
-                        },
+                        offset: core::mem::offset_of!(#static_ty, #field_name) as isize,
                         vtable: unsafe {
-                            let x: &'static #ident  = TransmuterPtr::<#ident> { int: 0 }.ptr;
-                            TransmuterTO::<dyn #trait_>{ ptr: x }.to.vtable
+                            let x = core::mem::MaybeUninit::<#ident>::uninit();
+                            let x: *const (dyn #static_bounds) = x.as_ptr();
+                            vptr::internal::to_trait_object(x).vtable
                         }
                     };
+                    #register
                     &VTABLE
                 }
 
-                fn get_vptr(&self) -> &vptr::VPtr<Self, dyn #trait_> { &self.#field_name }
-                fn get_vptr_mut(&mut self) -> &mut vptr::VPtr<Self, dyn #trait_> { &mut self.#field_name }
+                fn get_vptr(&self) -> &vptr::VPtr<Self, dyn #bounds> { &self.#field_name }
+                fn get_vptr_mut(&mut self) -> &mut vptr::VPtr<Self, dyn #bounds> { &mut self.#field_name }
+            }
+
+            #cfg
+            impl #impl_generics core::convert::AsRef<dyn #bounds> for #ident #ty_generics #static_where_clause {
+                fn as_ref(&self) -> &(dyn #bounds + 'static) { self }
             }
         );
     }
@@ -0,0 +1,194 @@
+/* Copyright (C) 2019 Olivier Goffart <ogoffart@woboq.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+and/or sell copies of the Software, and to permit persons to whom the Software is furnished to do so,
+subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! Fixed-size array of objects accessed through thin references
+
+use crate::{HasVPtr, ThinRef, ThinRefMut};
+use core::marker::PhantomData;
+use core::ops::{Index, IndexMut};
+
+/// A fixed-size array of `N` objects of type `T`, stored inline, and accessed as thin references
+/// to `Trait`
+///
+/// Unlike [`ThinVec`](crate::ThinVec), all elements have the same type, but there is no
+/// allocation, so this type is also available without the `std` feature.
+///
+/// ```rust
+/// # use vptr::*;
+/// trait Shape { fn area(&self) -> f32; }
+/// #[vptr(Shape)]
+/// #[derive(Default)]
+/// struct Rectangle { w: f32, h : f32 }
+/// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+///
+/// let a = ThinArray::<_, dyn Shape, 2>::new([
+///     Rectangle { w: 5., h: 10., ..Default::default() },
+///     Rectangle { w: 1., h: 2., ..Default::default() },
+/// ]);
+/// assert_eq!(a.iter().map(|s| s.area()).sum::<f32>(), 52.);
+/// assert_eq!(a.get(1).map(|s| s.area()), Some(2.));
+/// assert_eq!(a[0].w, 5.);
+/// ```
+pub struct ThinArray<T, Trait: ?Sized, const N: usize>
+where
+    T: HasVPtr<Trait>,
+{
+    items: [T; N],
+    phantom: PhantomData<fn() -> *const Trait>,
+}
+
+impl<T, Trait: ?Sized, const N: usize> ThinArray<T, Trait, N>
+where
+    T: HasVPtr<Trait>,
+{
+    /// Creates an array from its elements
+    pub fn new(items: [T; N]) -> Self {
+        ThinArray {
+            items,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Returns the elements
+    pub fn into_inner(self) -> [T; N] {
+        self.items
+    }
+
+    /// Returns the number of elements, which is always `N`
+    pub fn len(&self) -> usize {
+        N
+    }
+
+    /// Returns true if `N` is 0
+    pub fn is_empty(&self) -> bool {
+        N == 0
+    }
+
+    /// Returns a thin reference to the element at the given index, or None if the index is out
+    /// of bounds
+    pub fn get(&self, index: usize) -> Option<ThinRef<'_, Trait>> {
+        self.items.get(index).map(|x| x.as_thin_ref())
+    }
+
+    /// Returns a mutable thin reference to the element at the given index, or None if the index
+    /// is out of bounds
+    pub fn get_mut(&mut self, index: usize) -> Option<ThinRefMut<'_, Trait>> {
+        self.items.get_mut(index).map(|x| x.as_thin_ref_mut())
+    }
+
+    /// Iterate over thin references to the elements
+    pub fn iter(&self) -> impl Iterator<Item = ThinRef<'_, Trait>> {
+        self.items.iter().map(|x| x.as_thin_ref())
+    }
+
+    /// Iterate over mutable thin references to the elements
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = ThinRefMut<'_, Trait>> {
+        self.items.iter_mut().map(|x| x.as_thin_ref_mut())
+    }
+}
+
+impl<T, Trait: ?Sized, const N: usize> From<[T; N]> for ThinArray<T, Trait, N>
+where
+    T: HasVPtr<Trait>,
+{
+    fn from(items: [T; N]) -> Self {
+        Self::new(items)
+    }
+}
+
+impl<T, Trait: ?Sized, const N: usize> Default for ThinArray<T, Trait, N>
+where
+    T: HasVPtr<Trait> + Default,
+{
+    fn default() -> Self {
+        Self::new([(); N].map(|_| T::default()))
+    }
+}
+
+impl<T, Trait: ?Sized, const N: usize> Index<usize> for ThinArray<T, Trait, N>
+where
+    T: HasVPtr<Trait>,
+{
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        &self.items[index]
+    }
+}
+
+impl<T, Trait: ?Sized, const N: usize> IndexMut<usize> for ThinArray<T, Trait, N>
+where
+    T: HasVPtr<Trait>,
+{
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        &mut self.items[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vptr;
+
+    mod vptr {
+        // Because otherwise, the generated code cannot access the vptr crate.
+        pub use crate::*;
+    }
+
+    trait Counter {
+        fn get(&self) -> u32;
+        fn incr(&mut self);
+    }
+
+    #[vptr(Counter)]
+    #[derive(Default)]
+    struct Count {
+        c: u32,
+    }
+    impl Counter for Count {
+        fn get(&self) -> u32 {
+            self.c
+        }
+        fn incr(&mut self) {
+            self.c += 1
+        }
+    }
+
+    // Only uses core, so that it also works without the std feature
+    #[test]
+    fn thin_array() {
+        let mut a = ThinArray::<Count, dyn Counter, 4>::default();
+        assert_eq!(a.len(), 4);
+        assert!(!a.is_empty());
+        for (i, mut c) in a.iter_mut().enumerate() {
+            for _ in 0..i {
+                c.incr();
+            }
+        }
+        a.get_mut(3).unwrap().incr();
+        assert!(a.get(4).is_none());
+        assert!(a.iter().map(|c| c.get()).eq([0, 1, 2, 4]));
+        a[0].c = 10;
+        assert_eq!(a.get(0).unwrap().get(), 10);
+        assert_eq!(a.into_inner()[3].c, 4);
+
+        let empty = ThinArray::<Count, dyn Counter, 0>::from([]);
+        assert!(empty.is_empty());
+        assert_eq!(empty.iter().count(), 0);
+    }
+}
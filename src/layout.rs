@@ -0,0 +1,98 @@
+/* Copyright (C) 2019 Olivier Goffart <ogoffart@woboq.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+and/or sell copies of the Software, and to permit persons to whom the Software is furnished to do so,
+subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! Computation of the layout of structs
+//!
+//! The `#[vptr]` macro needs the offset of the VPtr fields in the `static` VTableData. It uses
+//! `core::mem::offset_of!`, and checks at compile time that the result is the same as the one of
+//! the [`field_offset!`](crate::field_offset) macro, which computes the offset in a `const`
+//! context by taking the address of the field within an uninitialized value, without ever
+//! creating a reference to it. The same technique can be used for other intrusive structures.
+//!
+//! ```rust
+//! #[repr(C)]
+//! struct Node { value: u32, next: *const Node }
+//! const NEXT_OFFSET: isize = vptr::layout::field_offset!(Node, next);
+//! assert_eq!(NEXT_OFFSET as usize, core::mem::size_of::<*const Node>());
+//! ```
+
+#[doc(inline)]
+pub use crate::field_offset;
+
+/// Return the offset, in bytes, of `field` from `base`
+///
+/// This is used by the [`field_offset!`](crate::field_offset) macro.
+///
+/// # Safety
+///
+/// `field` must be derived from `base` and point within the same object (which does not need
+/// to be initialized)
+pub const unsafe fn offset_from_base<T, F: ?Sized>(base: *const T, field: *const F) -> isize {
+    (field as *const u8).offset_from(base as *const u8)
+}
+
+/// Compute the offset, in bytes, of a field within a struct, in a `const` context
+///
+/// The field can be named, or the index of a field of a tuple struct. The result is an `isize`,
+/// like [`VTableData::offset`](crate::VTableData::offset).
+///
+/// ```rust
+/// struct Tuple(u8, u64);
+/// const OFFSET: isize = vptr::field_offset!(Tuple, 1);
+/// assert_eq!(OFFSET as usize, core::mem::offset_of!(Tuple, 1));
+/// ```
+#[macro_export]
+macro_rules! field_offset {
+    ($ty:ty, $field:tt) => {{
+        let x = core::mem::MaybeUninit::<$ty>::uninit();
+        let base = x.as_ptr();
+        // Safety: the field pointer is derived from base without dereferencing it
+        unsafe { $crate::layout::offset_from_base(base, core::ptr::addr_of!((*base).$field)) }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use core::mem::offset_of;
+
+    #[allow(dead_code)]
+    struct Named {
+        a: u8,
+        b: u64,
+        c: [u16; 3],
+    }
+
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct Tuple(u8, u32, u8);
+
+    #[test]
+    fn field_offset() {
+        const B: isize = field_offset!(Named, b);
+        assert_eq!(B as usize, offset_of!(Named, b));
+        assert_eq!(field_offset!(Named, a) as usize, offset_of!(Named, a));
+        assert_eq!(field_offset!(Named, c) as usize, offset_of!(Named, c));
+        assert_eq!(field_offset!(Tuple, 0), 0);
+        assert_eq!(field_offset!(Tuple, 1), 4);
+        assert_eq!(field_offset!(Tuple, 2), 8);
+        assert_eq!(
+            super::field_offset!(Tuple, 2) as usize,
+            offset_of!(Tuple, 2)
+        );
+    }
+}
@@ -93,7 +93,7 @@ fn total_area(list: &[vptr::ThinRef<dyn Shape>]) -> f32 {
 ```
 
 Same as before, but we added `#[vptr(Shape)]` and are now using `ThinRef<Shape>` instead of
-`&dyn Shame`.  The difference is that the ThinRef has only the size of one pointer
+`&dyn Shape`.  The difference is that the ThinRef has only the size of one pointer
 
 
 ```ascii
@@ -107,12 +107,86 @@ Same as before, but we added `#[vptr(Shape)]` and are now using `ThinRef<Shape>`
                       ┗━━━━━━━━━━━━┛                           ╏         ╏
 ```
 
+Like a reference, a `ThinRef<'a, Trait>` borrows the object for the lifetime `'a`, which follows
+the usual elision rules in function signatures. Omitting it entirely also compiles, but recent
+compilers warn about the hidden lifetime, so write it as `'_`. The trait object type itself defaults to
+`dyn Trait + 'static`, so the lifetime of the borrow is the only one elided.
+
+```rust
+# use vptr::*;
+# trait Shape { fn area(&self) -> f32; }
+# #[vptr(Shape)]
+# struct Rectangle { w: f32, h : f32 }
+# impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+fn first_shape(shapes: &[Rectangle]) -> ThinRef<'_, dyn Shape> {
+    shapes[0].as_thin_ref()
+}
+```
+
+## Pinning
+
+Since a thin reference borrows the object, the object cannot be moved while a thin reference
+to it is alive:
+
+```rust,compile_fail
+# use vptr::*;
+# trait Shape { fn area(&self) -> f32; }
+# #[vptr(Shape)]
+# struct Rectangle { w: f32, h : f32 }
+# impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+let r = Rectangle { w: 5., h: 10., vptr_Shape: VPtr::new() };
+let thin: ThinRef<dyn Shape> = r.as_thin_ref();
+let moved = r;
+thin.area();
+```
+
+Types which must never move, such as self-referential types, can be pinned and used through
+`Pin<ThinRef>` with [`HasVPtr::as_pin_thin_ref`], [`HasVPtr::as_pin_thin_ref_mut`] or
+`ThinBox::pin`. Thin references can always be taken to an object which is not pinned, like
+normal references, so to require pinning, the methods of the trait take `self: Pin<&Self>` or
+`self: Pin<&mut Self>`, and cannot be called through a thin reference which is not pinned:
+
+```rust
+# use vptr::*;
+# use core::pin::Pin;
+# use core::marker::PhantomPinned;
+trait Node { fn value(self: Pin<&Self>) -> u32; }
+#[vptr(Node)]
+struct SelfRef { data: u32, data_ptr: *const u32, _pin: PhantomPinned }
+impl Node for SelfRef {
+    fn value(self: Pin<&Self>) -> u32 { unsafe { *self.data_ptr } }
+}
+
+let mut node = Box::pin(SelfRef {
+    data: 42, data_ptr: core::ptr::null(), _pin: PhantomPinned, vptr_Node: VPtr::new()
+});
+unsafe {
+    let n = node.as_mut().get_unchecked_mut();
+    n.data_ptr = &n.data;
+}
+let thin: Pin<ThinRef<dyn Node>> = node.as_ref().as_pin_thin_ref();
+assert_eq!(ThinRef::get_pinned_ref(thin).value(), 42);
+```
+
+```rust,compile_fail
+# use vptr::*;
+# use core::pin::Pin;
+# trait Node { fn value(self: Pin<&Self>) -> u32; }
+# #[vptr(Node)]
+# struct SelfRef { data: u32 }
+# impl Node for SelfRef { fn value(self: Pin<&Self>) -> u32 { self.data } }
+let node = SelfRef { data: 42, vptr_Node: VPtr::new() };
+let thin: ThinRef<dyn Node> = node.as_thin_ref();
+thin.value(); // error: the object is not pinned
+```
+
 
 # The `#[vptr]` macro
 
 The `#[vptr(Trait)]` macro can be applied to a struct and it adds members to the struct
 with pointer to the vtable, these members are of type VPtr<S, Trait>, where S is the struct.
-The macro also implements the `HasVPtr` trait which allow the creation of `ThinRef` for this
+The macro also implements the `HasVPtr` trait which allow the creation of `ThinRef` for this,
+and `AsRef<dyn Trait>` so the struct can be given to generic code expecting a `AsRef<dyn Trait>`.
 
 You probably want to derive from `Default`, otherwise, the extra fields needs to be initialized
 manually (with `Default::default()` or `VPtr::new()`)
@@ -151,8 +225,24 @@ let p = Point(1, 2, VPtr::new());
 let pointref = ThinRef::from(&p);
 assert_eq!(pointref.area(), 0.);
 
-// The trait can be put in quote if it is too complex for a meta attribute
-#[vptr("PartialEq<str>")]
+// With `#[repr(C)]`, the VPtr fields are appended after the other fields, in the order of the
+// traits, and have the layout of a pointer. Here, this matches `struct { uint32_t; void*; }` in C
+#[repr(C)]
+#[vptr(Shape)] struct Slot(u32);
+impl Shape for Slot { fn area(&self) -> f32 { 0. } }
+assert_eq!(mem::size_of::<Slot>(), 2 * mem::size_of::<usize>());
+assert_eq!(mem::offset_of!(Slot, 1), mem::size_of::<usize>());
+
+// A unit struct becomes a tuple struct that only contains the VPtr
+#[vptr(Shape)] struct Origin;
+impl Shape for Origin { fn area(&self) -> f32 { 0. } }
+let o = Origin(VPtr::new());
+assert_eq!(mem::size_of::<Origin>(), mem::size_of::<usize>());
+assert_eq!(ThinRef::<dyn Shape>::from(&o).area(), 0.);
+
+// Traits with generic arguments can be given directly, or in quotes if they are too
+// complex to be parsed
+#[vptr(PartialEq<str>)]
 #[derive(Default)]
 struct MyString(String);
 impl PartialEq<str> for MyString {
@@ -161,13 +251,479 @@ impl PartialEq<str> for MyString {
 let mystr = MyString("Hi".to_string(), VPtr::new());
 let mystring_ref = ThinRef::from(&mystr);
 assert!(*mystring_ref == *"Hi");
+
+// The trait can also be written as a `dyn` type, with extra bounds
+#[vptr(dyn Shape, dyn ToString + Send)]
+#[derive(Default)]
+struct Square { size: f32 }
+impl Shape for Square { fn area(&self) -> f32 { self.size * self.size } }
+# impl Display for Square {
+#   fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result { write!(fmt, "Square") }
+# }
+let sq = Square { size: 2., ..Default::default() };
+let sqref: ThinRef<dyn ToString + Send> = ThinRef::from(&sq);
+assert_eq!(sqref.to_string(), "Square");
+```
+
+## Initialization in place
+
+With the `init_in_place` option, the macro generates an
+`unsafe fn init_vptrs_in_place(this: *mut Self)` associated function which writes the VPtr
+fields of a possibly uninitialized struct. This is useful when the struct is initialized field
+by field, for example in an arena.
+
+```rust
+# use vptr::*;
+# use std::mem::MaybeUninit;
+trait Shape { fn area(&self) -> f32; }
+#[vptr(Shape, init_in_place)]
+struct Rectangle { w: f32, h : f32 }
+impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+
+let mut r = MaybeUninit::<Rectangle>::uninit();
+let r = unsafe {
+    let p = r.as_mut_ptr();
+    std::ptr::addr_of_mut!((*p).w).write(5.);
+    std::ptr::addr_of_mut!((*p).h).write(10.);
+    Rectangle::init_vptrs_in_place(p);
+    r.assume_init()
+};
+assert_eq!(ThinRef::<dyn Shape>::from(&r).area(), 50.);
+```
+
+An all-zero VPtr is not a valid value (it would be a null reference), so `VPtr` cannot be
+`bytemuck::Zeroable`. But the other fields can still be bulk-zeroed, as long as the VPtr fields
+are fixed up with `init_vptrs_in_place` before the memory is assumed to be initialized:
+
+```rust
+# use vptr::*;
+# use std::mem::MaybeUninit;
+# trait Shape { fn area(&self) -> f32; }
+# #[vptr(Shape, init_in_place)]
+# struct Rectangle { w: f32, h : f32 }
+# impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+let mut buffer = MaybeUninit::<[Rectangle; 16]>::zeroed();
+let buffer = unsafe {
+    let p = buffer.as_mut_ptr() as *mut Rectangle;
+    for i in 0..16 {
+        Rectangle::init_vptrs_in_place(p.add(i));
+    }
+    buffer.assume_init()
+};
+assert!(buffer.iter().all(|r| ThinRef::<dyn Shape>::from(r).area() == 0.));
+```
+
+## Size overhead
+
+The macro also generates a `VPTR_OVERHEAD_BYTES` associated constant with the number of bytes
+added by the VPtr fields, which can be used to check the size budget of a struct at compile time.
+
+```rust
+# use vptr::*;
+trait Shape { fn area(&self) -> f32; }
+#[vptr(Shape, ToString)]
+struct Rectangle { w: f32, h : f32 }
+# impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+# impl std::fmt::Display for Rectangle {
+#   fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { Ok(()) }
+# }
+const _: () = assert!(Rectangle::VPTR_OVERHEAD_BYTES == 2 * std::mem::size_of::<usize>());
+const _: () = assert!(std::mem::size_of::<Rectangle>() <= 32);
+```
+
+## Comparing by value
+
+With the `eq_by_value` option, the macro generates a `thin_eq` associated function which
+compares two thin references using the `PartialEq` implementation of the struct. It returns
+false if one of them is not an instance of this struct.
+
+```rust
+# use vptr::*;
+trait Shape { fn area(&self) -> f32; }
+#[vptr(Shape, eq_by_value)]
+#[derive(Default, PartialEq)]
+struct Rectangle { w: f32, h : f32 }
+impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+#[vptr(Shape)]
+#[derive(Default)]
+struct Circle { r: f32 }
+impl Shape for Circle { fn area(&self) -> f32 { 3.14 * self.r * self.r } }
+
+let r1 = Rectangle { w: 5., h: 10., ..Default::default() };
+let r2 = Rectangle { w: 5., h: 10., ..Default::default() };
+let c = Circle { r: 1., ..Default::default() };
+let thin: ThinRef<dyn Shape> = r1.as_thin_ref();
+assert!(Rectangle::thin_eq(thin, r2.as_thin_ref()));
+assert!(!Rectangle::thin_eq(thin, c.as_thin_ref()));
+assert_eq!(Rectangle::thin_refs_equal(thin, c.as_thin_ref()), None);
+```
+
+`thin_eq` is implemented with the `thin_refs_equal` associated function, which returns
+`Option<bool>` instead, with `None` if one of the objects is not an instance of this struct.
+The `value_eq` option generates only `thin_refs_equal`.
+
+## Deref
+
+When the struct has a single trait, the `deref` option implements `Deref<Target = dyn Trait>`
+for the struct, going through the thin reference. This allows to pass the struct to generic
+code which expects such a `Deref`.
+
+```rust
+# use vptr::*;
+# use core::ops::Deref;
+trait Shape { fn area(&self) -> f32; }
+#[vptr(Shape, deref)]
+#[derive(Default)]
+struct Rectangle { w: f32, h : f32 }
+impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+
+fn area_of(s: &impl Deref<Target = dyn Shape>) -> f32 { s.deref().area() }
+assert_eq!(area_of(&Rectangle { w: 5., h: 10., ..Default::default() }), 50.);
+```
+
+It is an error to use `deref` with several traits, since there could only be one `Target`:
+
+```rust,compile_fail
+# use vptr::*;
+trait Shape { fn area(&self) -> f32; }
+trait Named { fn name(&self) -> &str; }
+#[vptr(Shape, Named, deref)]
+struct Rectangle { w: f32, h : f32 }
+```
+
+## Builder
+
+The VPtr fields added by the macro make it harder to construct the struct, as they need to be
+named, or the struct needs to implement `Default`. The `builder` option generates a builder
+with a setter for each field of the struct, whose `build` function initializes the VPtr fields.
+It is only supported for structs with named fields.
+
+```rust
+# use vptr::*;
+trait Shape { fn area(&self) -> f32; }
+#[vptr(Shape, builder)]
+struct Rectangle { w: f32, h : f32 }
+impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+
+let r: Rectangle = Rectangle::builder().w(5.).h(10.).build().unwrap();
+assert_eq!(ThinRef::<dyn Shape>::from(&r).area(), 50.);
+let err = Rectangle::builder().w(5.).build().err().unwrap();
+assert_eq!(err.field_name(), "h");
+```
+
+## Debug guard
+
+A thin reference created from a raw pointer (see [`ThinRef::from_raw`]) is not checked by the
+borrow checker, so it may be used after the object was dropped. The `debug_guard` option adds
+a `vptr_guard` field of type [`DebugGuard`] to the struct, which has no size. When the
+`debug_guard` feature of this crate is enabled, in debug builds, dropping the guard records the
+addresses of the VPtr fields of the object, and dereferencing a `ThinRef` to one of them panics
+instead of reading the memory of the dropped object. The record is removed when a thin pointer
+to a new object at the same address is created.
+
+The guard cannot detect that an object was moved, since moving does not run any code. It
+implements `Drop`, so the struct cannot be `Copy`, but the struct can implement `Drop` itself.
+When enabled, every creation and dereference of a `ThinRef` locks a global mutex: this is meant
+for debugging only. It is only supported for structs with named fields.
+
+```rust
+# use vptr::*;
+trait Shape { fn area(&self) -> f32; }
+#[vptr(Shape, debug_guard)]
+struct Rectangle { w: f32, h : f32 }
+impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+
+let r = Box::new(Rectangle { w: 5., h: 10., vptr_Shape: VPtr::new(), vptr_guard: DebugGuard::new() });
+let raw = ThinRef::<dyn Shape>::into_raw(ThinRef::from(&*r));
+drop(r);
+// With the `debug_guard` feature, in a debug build, this would panic:
+// unsafe { ThinRef::<dyn Shape>::from_raw(raw) }.area();
+```
+
+## Attributes
+
+The attributes of the struct and of its fields, such as doc comments or `#[non_exhaustive]`,
+are kept. The VPtr fields added by the macro are private and do not need to be documented.
+
+```rust
+#![deny(missing_docs)]
+//! A documented crate
+# use vptr::*;
+/// A shape
+pub trait Shape {
+    /// The area
+    fn area(&self) -> f32;
+}
+/// A rectangle
+#[vptr(Shape)]
+#[non_exhaustive]
+#[derive(Default)]
+pub struct Rectangle {
+    /// The width
+    pub w: f32,
+    /// The height
+    pub h: f32,
+}
+impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+# fn main() {}
+```
+
+The VPtr fields must be properly aligned, so packed structs are rejected:
+
+```rust,compile_fail
+# use vptr::*;
+trait Shape { fn area(&self) -> f32; }
+#[vptr(Shape)]
+#[repr(C, packed)]
+struct Rectangle { w: f32, h : f32 }
+```
+
+## Foreign types
+
+The VPtr fields must be inside the object, so types from other crates cannot get thin
+references directly. The `#[vptr_newtype(Trait)]` macro turns a tuple struct with a single
+field into a wrapper which has the VPtr fields. It generates `new` and `into_inner` functions,
+`From` for the wrapped type, and `Deref` and `DerefMut` to it. The macro does not know the
+methods of the trait, so the trait must be implemented for the wrapper, usually by forwarding
+to the wrapped value.
+
+```rust
+# use vptr::*;
+mod foreign {
+    pub struct Rectangle { pub w: f32, pub h: f32 }
+}
+trait Shape { fn area(&self) -> f32; }
+#[vptr_newtype(Shape)]
+struct ThinRect(foreign::Rectangle);
+impl Shape for ThinRect { fn area(&self) -> f32 { self.w * self.h } }
+
+let r = ThinRect::new(foreign::Rectangle { w: 5., h: 10. });
+assert_eq!(ThinRef::<dyn Shape>::from(&r).area(), 50.);
+assert_eq!(r.into_inner().w, 5.);
+```
+
+Since the wrapper already implements `Deref`, the `deref` option cannot be used with it:
+
+```rust,compile_fail
+# use vptr::*;
+# mod foreign {
+#     pub struct Rectangle { pub w: f32, pub h: f32 }
+# }
+trait Shape { fn area(&self) -> f32; }
+#[vptr_newtype(Shape, deref)]
+struct ThinRect(foreign::Rectangle);
+# impl Shape for ThinRect { fn area(&self) -> f32 { self.w * self.h } }
+```
+
+## Thin reference fields
+
+The `#[thin_fields]` macro is used on a struct which holds thin references, rather than on the
+objects they point to. It replaces the type of each field with the `#[thin]` attribute, which
+must be a trait object such as `dyn Trait`, with `ThinRef<'a, dyn Trait>`. The lifetime is the
+first lifetime parameter of the struct, or a new `'a` parameter if it has none. It should be
+placed before the `#[derive]` attributes, so that they see the new fields.
+
+```rust
+# use vptr::*;
+trait Shape { fn area(&self) -> f32; }
+trait Named { fn name(&self) -> &str; }
+#[vptr(Shape, Named)]
+#[derive(Default)]
+struct Rectangle { w: f32, h : f32 }
+impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+impl Named for Rectangle { fn name(&self) -> &str { "rectangle" } }
+
+#[thin_fields]
+#[derive(Clone, Copy)]
+struct Item {
+    #[thin] shape: dyn Shape,
+    #[thin] name: dyn Named,
+    count: u32,
+}
+
+let r = Rectangle { w: 5., h: 10., ..Default::default() };
+let item: Item<'_> = Item { shape: r.as_thin_ref(), name: r.as_thin_ref(), count: 2 };
+assert_eq!(item.shape.area() * item.count as f32, 100.);
+assert_eq!(item.name.name(), "rectangle");
+```
+
+## Conditional traits
+
+A trait can be wrapped in `cfg(condition, Trait)` so that its VPtr field and the `HasVPtr`
+implementation only exist when the condition is true. This is only supported on struct with
+named fields.
+
+```rust
+# use vptr::*;
+# use std::fmt::{self, Debug};
+trait Shape { fn area(&self) -> f32; }
+#[vptr(Shape, cfg(feature = "debug_shapes", Debug))]
+#[derive(Default, Debug)]
+struct Square { size: f32 }
+impl Shape for Square { fn area(&self) -> f32 { self.size * self.size } }
+```
+
+Fields of the struct itself may also have `cfg` attributes, since the offsets of the VPtr fields
+are computed by the compiler after the configuration is applied. For the same reason as above,
+this is only supported on struct with named fields:
+
+```rust,compile_fail
+# use vptr::*;
+trait Shape { fn area(&self) -> f32; }
+#[vptr(Shape)]
+struct Square(#[cfg(feature = "debug_shapes")] u32, f32);
+```
+
+## C ABI functions
+
+A trait can be wrapped in `c_abi(Trait, "fn method(&self, ...)", ...)` to also generate an
+`extern "C"` function named `<Struct>_<method>` for each of the listed methods. These functions
+take the raw thin pointer (see [`ThinRef::into_raw`]) as a `void*` in first argument, followed by
+the arguments of the method, and dispatch the call through the vtable.
+
+This is limited to methods taking `&self` or `&mut self`, without generic parameters, and whose
+arguments and return type can be passed to C. Since the macro does not see the trait definition,
+the signatures need to be repeated.
+
+```rust
+# use vptr::*;
+trait Callback { fn call(&self, x: i32) -> i32; fn reset(&mut self); }
+#[vptr(c_abi(Callback, "fn call(&self, x: i32) -> i32", "fn reset(&mut self)"))]
+#[derive(Default)]
+struct Adder { sum: i32 }
+impl Callback for Adder {
+    fn call(&self, x: i32) -> i32 { self.sum + x }
+    fn reset(&mut self) { self.sum = 0; }
+}
+
+let mut adder = Adder { sum: 3, ..Default::default() };
+let this = ThinRefMut::<dyn Callback>::into_raw(adder.as_thin_ref_mut()) as *mut _;
+// `this` and the function pointers can be given to C code
+let call: unsafe extern "C" fn(*const std::ffi::c_void, i32) -> i32 = Adder_call;
+unsafe {
+    assert_eq!(call(this, 4), 7);
+    Adder_reset(this);
+    assert_eq!(Adder_call(this, 4), 4);
+}
+```
+
+Note that the layout of the Rust vtables, and thus of the `VTableData`, is not stable across
+compiler versions. A thin reference must not be dereferenced by code compiled separately, such
+as a dynamically loaded plugin built with another compiler. The `extern "C"` functions are the
+way to call into an object across such a boundary. With the `abi_stable` feature, the `stable`
+option also puts a vtable of such functions in the object, see the `stable` module.
+
+## Traits with associated types
+
+A trait object must have all its associated types specified, so the binding has to be written
+in the `#[vptr(...)]` attribute, and the exact same spelling must be used
+for the `ThinRef`. The field is still named after the trait, without the binding.
+
+```rust
+# use vptr::*;
+trait Builder { type Out; fn build(&self) -> Self::Out; }
+#[vptr(Builder<Out = u32>)]
+struct Doubler { value: u32 }
+impl Builder for Doubler {
+    type Out = u32;
+    fn build(&self) -> u32 { self.value * 2 }
+}
+
+let d = Doubler { value: 21, vptr_Builder: VPtr::new() };
+let thin: ThinRef<dyn Builder<Out = u32>> = ThinRef::from(&d);
+assert_eq!(thin.build(), 42);
+```
+
+## Supertraits
+
+The methods of the supertraits are part of the vtable of the trait, so they can be called
+directly on a `ThinRef<dyn Sub>`. The dereferenced `&dyn Sub` can also be upcast to a
+`&dyn Super` with Rust's trait upcasting coercion.
+
+```rust
+# use vptr::*;
+trait Shape { fn area(&self) -> f32; }
+trait Drawable: Shape { fn draw(&self) -> String; }
+#[vptr(Drawable)]
+struct Square { size: f32 }
+impl Shape for Square { fn area(&self) -> f32 { self.size * self.size } }
+impl Drawable for Square { fn draw(&self) -> String { format!("square of {}", self.area()) } }
+
+let sq = Square { size: 2., vptr_Drawable: VPtr::new() };
+let thin: ThinRef<dyn Drawable> = ThinRef::from(&sq);
+assert_eq!(thin.area(), 4.);
+let shape: &dyn Shape = &*thin;
+assert_eq!(shape.area(), 4.);
+```
+
+## Errors
+
+`std::error::Error` can be used as the trait, so errors can be passed around as a thin
+reference. All its methods, including `source()` and the `Display` and `Debug` supertraits,
+are dispatched through the vtable.
+
+```rust
+# use vptr::*;
+# use std::{error::Error, fmt};
+#[vptr(Error)]
+#[derive(Debug, Default)]
+struct NotFound { name: String }
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "{} not found", self.name) }
+}
+impl Error for NotFound {}
+
+let e = NotFound { name: "foo".into(), ..Default::default() };
+let thin: ThinRef<dyn Error> = e.as_thin_ref();
+assert_eq!(thin.to_string(), "foo not found");
+assert!(thin.source().is_none());
+```
+
+## Standard traits
+
+Like `Box`, the thin pointers implement `Display` and `Debug` when the trait does, and
+`ThinRefMut` and `ThinBox` implement `Iterator` and `fmt::Write` when the trait does, so they
+can be given to generic code. `ThinBox` also implements `Hash`. The thin references do not
+implement `PartialEq` nor `Hash`, see [`Identity`] and [`ByValue`].
+
+```rust
+# use vptr::*;
+#[vptr("Iterator<Item = u32>")]
+struct Countdown(u32);
+impl Iterator for Countdown {
+    type Item = u32;
+    fn next(&mut self) -> Option<u32> { self.0 = self.0.checked_sub(1)?; Some(self.0) }
+}
+let mut c = Countdown(3, VPtr::new());
+let thin: ThinRefMut<dyn Iterator<Item = u32>> = c.as_thin_ref_mut();
+assert_eq!(thin.collect::<Vec<_>>(), [2, 1, 0]);
 ```
+
+# Cargo features
+
+ - `std` (enabled by default): enables `ThinBox` and the other types that allocate.
+ - `debug_guard`: in debug builds, check that the objects with the `debug_guard` option are not
+   dereferenced through a `ThinRef` after they were dropped. See [Debug guard](#debug-guard).
+ - `abi_stable`: thin references with a `#[repr(C)]` vtable, which can be used across a plugin
+   boundary, and whose layout can be checked by the `abi_stable` crate. See the `stable` module.
+ - `rayon`: implement the parallel iterators of `rayon` for [`ThinVec`], with a `par_iter` function.
+ - `ptr_metadata`: use the unstable `core::ptr::metadata` API to split and rebuild pointers to
+   trait objects, instead of relying on their layout. This requires a nightly compiler.
+
+# Minimum supported Rust version
+
+The minimum supported Rust version is 1.77, which stabilized `core::mem::offset_of!`. The
+macro uses it to compute the offset of the VPtr fields in constants.
 */
 
 #![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(feature = "ptr_metadata", feature(ptr_metadata))]
 #![warn(missing_docs)]
+pub use ::vptr_macros::thin_fields;
 #[doc(inline)]
 pub use ::vptr_macros::vptr;
+#[doc(inline)]
+pub use ::vptr_macros::vptr_newtype;
 use core::borrow::{Borrow, BorrowMut};
 use core::marker::PhantomData;
 use core::ops::{Deref, DerefMut};
@@ -175,19 +731,63 @@ use core::pin::Pin;
 use core::ptr::NonNull;
 #[cfg(feature = "std")]
 use std::boxed::Box;
+#[cfg(feature = "std")]
+use std::{rc::Rc, sync::Arc};
+
+mod array;
+pub mod layout;
+pub use array::ThinArray;
+#[cfg(feature = "std")]
+pub mod observers;
+#[cfg(feature = "abi_stable")]
+pub mod stable;
+#[cfg(feature = "abi_stable")]
+pub use stable::{HasStableVPtr, StableThinRef, StableVPtr};
+#[cfg(feature = "std")]
+mod vec;
+#[cfg(feature = "std")]
+pub use vec::{ThinSmallVec, ThinVec};
 
 /// Represent a pointer to a virtual table to the trait `Trait` that is to be embedded in
 /// a structure `T`
 ///
 /// One should not need to use this structure directly, it is going to be created by the `vptr`
 /// procedural macro.
-#[derive(Clone, Copy, Eq, Hash, PartialEq, PartialOrd)]
+///
+/// A VPtr is `Copy`, so a struct using `#[vptr]` can still derive `Copy` and `Clone`.
+/// Since the VPtr only contains the offset and vtable that are the same for every instance of
+/// the type, the copied VPtr is valid for the copy. But note that a `ThinRef` always refers to
+/// the object it was taken from: a `ThinRef` to the original does not see the changes made to
+/// the copy.
+///
+/// ```rust
+/// # use vptr::*;
+/// trait Shape { fn area(&self) -> f32; }
+/// #[vptr(Shape)]
+/// #[derive(Default, Clone, Copy)]
+/// struct Square { size: f32 }
+/// impl Shape for Square { fn area(&self) -> f32 { self.size * self.size } }
+///
+/// let original = Square { size: 2., ..Default::default() };
+/// let mut copy = original;
+/// copy.size = 3.;
+/// assert_eq!(ThinRef::<dyn Shape>::from(&original).area(), 4.);
+/// assert_eq!(ThinRef::<dyn Shape>::from(&copy).area(), 9.);
+/// ```
+///
+/// For the same reason, all the VPtr of a type are equal: `PartialEq`, `Ord` and `Hash` are
+/// implemented so that the VPtr fields do not change the result of the traits derived on the
+/// struct.
+///
+/// The VPtr only points to static data, so it is always `Send` and `Sync`: it does not change
+/// whether the struct containing it can be sent to, or shared with, another thread.
+#[repr(transparent)]
 pub struct VPtr<T, Trait: ?Sized>
 where
     T: HasVPtr<Trait>,
 {
     vtable: &'static VTableData,
-    phantom: PhantomData<(*const T, *const Trait)>,
+    phantom: PhantomData<fn() -> (*const T, *const Trait)>,
 }
 
 impl<T, Trait: ?Sized> VPtr<T, Trait>
@@ -202,39 +802,185 @@ where
             phantom: PhantomData,
         }
     }
+
+    /// Convert to a VPtr to another trait object type with the same vtable
+    ///
+    /// This is meant to convert between trait object types which only differ by their auto
+    /// traits, such as `dyn Trait + Send` and `dyn Trait`.
+    ///
+    /// # Safety
+    ///
+    /// The VTableData of this VPtr must be valid for `Other`: it must have the same offset and
+    /// vtable as the one returned by `<T as HasVPtr<Other>>::init()`
+    pub unsafe fn cast_trait<Other: ?Sized>(self) -> VPtr<T, Other>
+    where
+        T: HasVPtr<Other>,
+    {
+        VPtr {
+            vtable: self.vtable,
+            phantom: PhantomData,
+        }
+    }
 }
 
-impl<T, Trait: ?Sized> Default for VPtr<T, Trait>
+// Cannot use #[derive()] because it gets the bounds wrong (See rust RFC #2353)
+impl<T, Trait: ?Sized> Clone for VPtr<T, Trait>
 where
     T: HasVPtr<Trait>,
 {
-    // Creates a new VPtr initialized to a pointer of the vtable of the `Trait` for the type `T`.
-    // Same as VPtr::new()
-    fn default() -> Self {
-        VPtr::new()
+    fn clone(&self) -> Self {
+        *self
     }
 }
+impl<T, Trait: ?Sized> Copy for VPtr<T, Trait> where T: HasVPtr<Trait> {}
 
-#[cfg(feature = "std")]
-impl<T, Trait: ?Sized> std::fmt::Debug for VPtr<T, Trait>
+// All the VPtr<T, Trait> point to the same VTableData, so they are always equal. This way, the
+// VPtr fields do not change the result of the traits derived on the struct, and cost nothing.
+impl<T, Trait: ?Sized> PartialEq for VPtr<T, Trait>
 where
     T: HasVPtr<Trait>,
 {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        f.pad("VPtr")
+    fn eq(&self, _other: &Self) -> bool {
+        true
     }
 }
+impl<T, Trait: ?Sized> Eq for VPtr<T, Trait> where T: HasVPtr<Trait> {}
 
-/// This trait indicate that the type has a VPtr field to the trait `Trait`
-///
-/// You should not implement this trait yourself, it is implemented by the `vptr` macro
-///
-/// Safety: For this to work correctly, the init() function must return a reference to a VTableData
-/// with valid content (the offset and vtable pointer need to be correct for this type) and
-/// get_vptr must return a reference of a field withi &self. The `#[vptr] macro does the right thing
-pub unsafe trait HasVPtr<Trait: ?Sized> {
-    /// Initialize a VTableData suitable to initialize the VPtr within Self
-    fn init() -> &'static VTableData;
+impl<T, Trait: ?Sized> PartialOrd for VPtr<T, Trait>
+where
+    T: HasVPtr<Trait>,
+{
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T, Trait: ?Sized> Ord for VPtr<T, Trait>
+where
+    T: HasVPtr<Trait>,
+{
+    fn cmp(&self, _other: &Self) -> core::cmp::Ordering {
+        core::cmp::Ordering::Equal
+    }
+}
+
+impl<T, Trait: ?Sized> core::hash::Hash for VPtr<T, Trait>
+where
+    T: HasVPtr<Trait>,
+{
+    fn hash<H: core::hash::Hasher>(&self, _state: &mut H) {}
+}
+
+impl<T, Trait: ?Sized> Default for VPtr<T, Trait>
+where
+    T: HasVPtr<Trait>,
+{
+    // Creates a new VPtr initialized to a pointer of the vtable of the `Trait` for the type `T`.
+    // Same as VPtr::new()
+    fn default() -> Self {
+        VPtr::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T, Trait: ?Sized> std::fmt::Debug for VPtr<T, Trait>
+where
+    T: HasVPtr<Trait>,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.pad("VPtr")
+    }
+}
+
+/// Field added by the `debug_guard` option of the `#[vptr]` macro to the struct `T`
+///
+/// When the `debug_guard` feature is enabled, in debug builds, dropping the guard records the
+/// addresses of the VPtr fields of the object, so that dereferencing a [`ThinRef`] to it panics.
+/// Otherwise, it does nothing. See the [crate documentation](index.html#debug-guard).
+///
+/// Like the VPtr, all the guards are equal, so that they do not change the result of the traits
+/// derived on the struct.
+pub struct DebugGuard<T: internal::DebugGuarded> {
+    phantom: PhantomData<fn() -> T>,
+}
+
+impl<T: internal::DebugGuarded> DebugGuard<T> {
+    /// Create a new guard
+    pub const fn new() -> Self {
+        DebugGuard {
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T: internal::DebugGuarded> Drop for DebugGuard<T> {
+    fn drop(&mut self) {
+        #[cfg(all(feature = "debug_guard", debug_assertions))]
+        {
+            let object = (self as *mut Self as usize).wrapping_sub(T::GUARD_OFFSET);
+            let mut dropped = internal::DROPPED.lock().unwrap_or_else(|e| e.into_inner());
+            T::vptr_offsets(&mut |offset| {
+                dropped.insert(object.wrapping_add(offset));
+            });
+        }
+    }
+}
+
+// The guard of the clone is the guard of another object
+impl<T: internal::DebugGuarded> Clone for DebugGuard<T> {
+    fn clone(&self) -> Self {
+        DebugGuard::new()
+    }
+}
+
+impl<T: internal::DebugGuarded> Default for DebugGuard<T> {
+    fn default() -> Self {
+        DebugGuard::new()
+    }
+}
+
+impl<T: internal::DebugGuarded> PartialEq for DebugGuard<T> {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl<T: internal::DebugGuarded> Eq for DebugGuard<T> {}
+
+impl<T: internal::DebugGuarded> PartialOrd for DebugGuard<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: internal::DebugGuarded> Ord for DebugGuard<T> {
+    fn cmp(&self, _: &Self) -> core::cmp::Ordering {
+        core::cmp::Ordering::Equal
+    }
+}
+
+impl<T: internal::DebugGuarded> core::hash::Hash for DebugGuard<T> {
+    fn hash<H: core::hash::Hasher>(&self, _: &mut H) {}
+}
+
+impl<T: internal::DebugGuarded> core::fmt::Debug for DebugGuard<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.pad("DebugGuard")
+    }
+}
+
+/// This trait indicate that the type has a VPtr field to the trait `Trait`
+///
+/// You should not implement this trait yourself, it is implemented by the `vptr` macro
+///
+/// # Safety
+///
+/// For this to work correctly, the init() function must return a reference to a VTableData
+/// with valid content (the offset and vtable pointer need to be correct for this type) and
+/// get_vptr must return a reference of a field withi &self. The `#[vptr] macro does the right thing
+pub unsafe trait HasVPtr<Trait: ?Sized> {
+    /// Initialize a VTableData suitable to initialize the VPtr within Self
+    fn init() -> &'static VTableData;
 
     /// return the a reference of the VPtr within Self
     fn get_vptr(&self) -> &VPtr<Self, Trait>
@@ -247,23 +993,60 @@ pub unsafe trait HasVPtr<Trait: ?Sized> {
         Self: Sized;
 
     /// return a thin reference to self
-    fn as_thin_ref(&self) -> ThinRef<Trait>
+    fn as_thin_ref(&self) -> ThinRef<'_, Trait>
+    where
+        Self: Sized,
+    {
+        unsafe { ThinRef::new(self) }
+    }
+
+    /// return a thin reference to self, from a mutable reference
+    ///
+    /// Same as `as_thin_ref`, but avoids having to reborrow with `&*self` when one only has
+    /// a `&mut Self`.
+    fn as_thin_ref_shared(&mut self) -> ThinRef<'_, Trait>
     where
         Self: Sized,
     {
-        unsafe { ThinRef::new(self.get_vptr()) }
+        unsafe { ThinRef::new(self) }
     }
 
     /// return a thin reference to self
-    fn as_thin_ref_mut(&mut self) -> ThinRefMut<Trait>
+    fn as_thin_ref_mut(&mut self) -> ThinRefMut<'_, Trait>
+    where
+        Self: Sized,
+    {
+        unsafe { ThinRefMut::new(self) }
+    }
+
+    /// Same as [`HasVPtr::as_thin_ref`]
+    fn thin(&self) -> ThinRef<'_, Trait>
+    where
+        Self: Sized,
+    {
+        self.as_thin_ref()
+    }
+
+    /// Same as [`HasVPtr::as_thin_ref_mut`]
+    fn thin_mut(&mut self) -> ThinRefMut<'_, Trait>
     where
         Self: Sized,
     {
-        unsafe { ThinRefMut::new(self.get_vptr_mut()) }
+        self.as_thin_ref_mut()
+    }
+
+    /// Same as [`ThinBox::from_box`]
+    #[cfg(feature = "std")]
+    fn into_thin_box(self: Box<Self>) -> ThinBox<Trait>
+    where
+        Self: Sized + 'static,
+        Trait: 'static,
+    {
+        ThinBox::from_box(self)
     }
 
     /// Map a pinned reference to to a pinned thin reference
-    fn as_pin_thin_ref(self: Pin<&Self>) -> Pin<ThinRef<Trait>>
+    fn as_pin_thin_ref(self: Pin<&Self>) -> Pin<ThinRef<'_, Trait>>
     where
         Self: Sized,
     {
@@ -271,7 +1054,7 @@ pub unsafe trait HasVPtr<Trait: ?Sized> {
     }
 
     /// Map a pinned mutable reference to to a pinned mutable thin reference
-    fn as_pin_thin_ref_mut(self: Pin<&mut Self>) -> Pin<ThinRefMut<Trait>>
+    fn as_pin_thin_ref_mut(self: Pin<&mut Self>) -> Pin<ThinRefMut<'_, Trait>>
     where
         Self: Sized,
     {
@@ -279,6 +1062,68 @@ pub unsafe trait HasVPtr<Trait: ?Sized> {
     }
 }
 
+/// This trait indicates that the VTableData of the VPtr fields of the type identify it, so
+/// that a thin reference can be downcast to it (see [`ThinRef::downcast_ref`])
+///
+/// It is implemented by the `#[vptr]` macro for the structs which do not have lifetime
+/// parameters. All the instantiations of a struct with lifetime parameters share the same
+/// VTableData, so downcasting to it would allow to choose any lifetime, such as `'static`.
+///
+/// # Safety
+///
+/// The VTableData returned by [`HasVPtr::init`] must not be returned by the implementation of
+/// `HasVPtr` for any other type.
+pub unsafe trait DowncastTarget: 'static {}
+
+/// Compute the pointer to the object from the pointer to its VPtr field
+///
+/// The result is derived from `ptr` without going through an integer, so it keeps the
+/// provenance of `ptr`, which covers the whole object. Since `ptr` points inside the object and
+/// `offset` is the offset of the field, the result always stays within the object; the
+/// wrapping arithmetic only avoids relying on that for the pointer computation itself.
+///
+/// Safety: `ptr` must point to a VPtr field for `Trait`
+unsafe fn object_ptr<Trait: ?Sized>(ptr: NonNull<&'static VTableData>) -> *mut Trait {
+    let VTableData { offset, vtable } = **ptr.as_ptr();
+    let data = (ptr.as_ptr() as *mut u8).wrapping_offset(-offset) as *const ();
+    internal::from_trait_object::<Trait>(internal::TraitObject { data, vtable }) as *mut Trait
+}
+
+/// Trait to be used as a supertrait of a trait in order to upcast thin references with
+/// [`ThinRef::upcast`]
+///
+/// A `ThinRef<dyn Sub>` only knows where the VPtr for `Sub` is. The VPtr for the supertrait is
+/// at an offset which depends on the concrete type, so it has to be found through the vtable
+/// of `Sub`: this is what this trait does. It is implemented for every type implementing
+/// `HasVPtr<Super>`, so the struct needs both traits in its `#[vptr(...)]` attribute.
+///
+/// ```rust
+/// # use vptr::*;
+/// trait Shape { fn area(&self) -> f32; }
+/// trait Drawable: Shape + ThinUpcast<dyn Shape> { fn draw(&self) -> String; }
+/// #[vptr(Drawable, Shape)]
+/// #[derive(Default)]
+/// struct Square { size: f32 }
+/// impl Shape for Square { fn area(&self) -> f32 { self.size * self.size } }
+/// impl Drawable for Square { fn draw(&self) -> String { "square".into() } }
+///
+/// let sq = Square { size: 2., ..Default::default() };
+/// let thin: ThinRef<dyn Drawable> = sq.as_thin_ref();
+/// let shape: ThinRef<dyn Shape> = ThinRef::upcast(thin);
+/// assert_eq!(shape.area(), 4.);
+/// ```
+pub trait ThinUpcast<Super: ?Sized> {
+    /// Return a thin reference to the VPtr for `Super`
+    #[doc(hidden)]
+    fn __thin_upcast(&self) -> ThinRef<'_, Super>;
+}
+
+impl<Super: ?Sized, T: HasVPtr<Super>> ThinUpcast<Super> for T {
+    fn __thin_upcast(&self) -> ThinRef<'_, Super> {
+        unsafe { ThinRef::new(self) }
+    }
+}
+
 /// A thin reference (size = `size_of::<usize>()`) to an object implementing the trait `Trait`
 ///
 /// This is like a reference to a trait (`&dyn Trait`) for struct that used
@@ -286,6 +1131,26 @@ pub unsafe trait HasVPtr<Trait: ?Sized> {
 ///
 /// See the crate documentation for example of usage.
 ///
+/// The ThinRef dereferences to `&dyn Trait`, so all the methods of the trait can be called,
+/// including the provided methods with a default implementation, which are dispatched through
+/// the vtable like any other method.
+/// Just like with `&dyn Trait`, the methods with a `where Self: Sized` bound are not part of the
+/// vtable and cannot be called:
+///
+/// ```rust,compile_fail
+/// # use vptr::*;
+/// trait Shape { fn area(&self) -> f32; fn scaled(&self, f: f32) -> Self where Self: Sized; }
+/// fn call(s: ThinRef<dyn Shape>) { s.scaled(2.); }
+/// ```
+///
+/// `ThinRef` does not implement `PartialEq` or `Hash`, wrap it in [`Identity`] to compare the
+/// addresses, in [`ByValue`] to compare the objects, or in [`ByVTable`] to compare the types.
+///
+/// The object is found again by subtracting the offset of the VPtr field from the address of
+/// the field, using wrapping pointer arithmetic. The only assumption is that the address of an
+/// object plus the offset of one of its fields does not change the bits of the address outside
+/// of the object, so it also works with high addresses or tagged pointers.
+///
 /// The size is only the size of a single pointer:
 /// ```rust
 /// # use vptr::*;
@@ -294,133 +1159,605 @@ pub unsafe trait HasVPtr<Trait: ?Sized> {
 /// assert_eq!(mem::size_of::<ThinRef<dyn Trait>>(), mem::size_of::<usize>());
 /// assert_eq!(mem::size_of::<Option<ThinRef<dyn Trait>>>(), mem::size_of::<usize>());
 /// ```
+#[repr(transparent)]
 pub struct ThinRef<'a, Trait: ?Sized> {
-    ptr: &'a &'static VTableData,
+    /// Points to the VPtr field within the object.
+    ///
+    /// This is a raw pointer and not a `&'a &'static VTableData` because a reference to the
+    /// field would only allow to access the field itself, while we need to access the whole
+    /// object. So this pointer is always derived from a pointer to the whole object (and gets
+    /// its provenance), and the object is found again by subtracting the offset.
+    ptr: NonNull<&'static VTableData>,
     phantom: PhantomData<&'a Trait>,
 }
 
+// Same as for &'a Trait
+unsafe impl<'a, Trait: ?Sized + Sync> Send for ThinRef<'a, Trait> {}
+unsafe impl<'a, Trait: ?Sized + Sync> Sync for ThinRef<'a, Trait> {}
+
+// The ThinRef is only a pointer and can be moved freely, even if the object it points to cannot.
+// Pinning applies to the pointee through `Pin<ThinRef>` (see HasVPtr::as_pin_thin_ref)
+impl<'a, Trait: ?Sized> Unpin for ThinRef<'a, Trait> {}
+
 impl<'a, Trait: ?Sized> ThinRef<'a, Trait> {
-    /// Create a new reference from a reference to a VPtr
+    /// Create a new reference to an object
     ///
-    /// Safety: the ptr must be a field of a reference to T
-    unsafe fn new<T: HasVPtr<Trait>>(ptr: &'a VPtr<T, Trait>) -> Self {
+    /// Safety: T::init() must return the VTableData for T (guaranteed by HasVPtr's requirements)
+    unsafe fn new<T: HasVPtr<Trait>>(obj: &'a T) -> Self {
+        #[cfg(all(feature = "debug_guard", debug_assertions))]
+        internal::forget_dropped(obj as *const T as usize, core::mem::size_of::<T>());
+        let p = (obj as *const T as *const u8).wrapping_offset(T::init().offset);
         ThinRef {
-            ptr: &ptr.vtable,
+            ptr: NonNull::new_unchecked(p as *mut &'static VTableData),
             phantom: PhantomData,
         }
     }
-}
-
-impl<'a, Trait: ?Sized + 'a> Deref for ThinRef<'a, Trait> {
-    type Target = Trait;
 
-    fn deref(&self) -> &Self::Target {
-        unsafe {
-            let VTableData { offset, vtable } = **self.ptr;
-            let p = (self.ptr as *const _ as *const u8).offset(-offset) as *const ();
-            internal::TransmuterTO::<Trait> {
-                to: internal::TraitObject { data: p, vtable },
-            }
-            .ptr
+    /// Create a thin reference from a pointer to the VPtr field embedded in an object
+    ///
+    /// This is useful for intrusive data structures which keep a pointer to the VPtr field.
+    /// The thin reference accesses the whole object through this pointer, so it must be derived
+    /// from a pointer to the object, for example with `core::ptr::addr_of!`, and not from a
+    /// reference to the field such as the one returned by [`HasVPtr::get_vptr`].
+    ///
+    /// ```rust
+    /// # use vptr::*;
+    /// # use core::ptr::{addr_of, NonNull};
+    /// trait Shape { fn area(&self) -> f32; }
+    /// #[vptr(Shape)]
+    /// struct Rectangle { w: f32, h : f32 }
+    /// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+    /// let r = Rectangle { w: 5., h: 10., vptr_Shape: VPtr::new() };
+    /// let object: *const Rectangle = &r;
+    /// let vptr: *mut VPtr<Rectangle, dyn Shape> = unsafe { addr_of!((*object).vptr_Shape) as _ };
+    /// let vptr = NonNull::new(vptr).unwrap();
+    /// let thin = unsafe { ThinRef::<dyn Shape>::from_embedded(vptr) };
+    /// assert_eq!(thin.area(), 50.);
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// `vptr` must point to the VPtr field of an object of type `T` which is borrowed for `'a`,
+    /// and it must have been derived from a pointer to the whole object.
+    pub unsafe fn from_embedded<T: HasVPtr<Trait>>(vptr: NonNull<VPtr<T, Trait>>) -> Self {
+        ThinRef {
+            ptr: vptr.cast(),
+            phantom: PhantomData,
         }
     }
-}
 
-impl<'a, Trait: ?Sized + 'a> Borrow<Trait> for ThinRef<'a, Trait> {
-    fn borrow(&self) -> &Trait {
-        &**self
+    /// Return the VTableData stored in the VPtr field of the object
+    ///
+    /// This gives both the offset of the VPtr field and the vtable of the concrete type, which
+    /// can be useful for diagnostics. It is the same reference as the one returned by
+    /// [`HasVPtr::init`] for the concrete type.
+    ///
+    /// ```rust
+    /// # use vptr::*;
+    /// trait Shape { fn area(&self) -> f32; }
+    /// #[vptr(Shape)]
+    /// #[derive(Default)]
+    /// struct Rectangle { w: f32, h : f32 }
+    /// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+    ///
+    /// let r = Rectangle::default();
+    /// let data = ThinRef::vtable_data(ThinRef::<dyn Shape>::from(&r));
+    /// assert!(core::ptr::eq(data, <Rectangle as HasVPtr<dyn Shape>>::init()));
+    /// ```
+    pub fn vtable_data(this: Self) -> &'static VTableData {
+        unsafe { *this.ptr.as_ptr() }
     }
-}
 
-impl<'a, Trait: ?Sized + 'a, T: HasVPtr<Trait>> From<&'a T> for ThinRef<'a, Trait> {
-    fn from(f: &'a T) -> Self {
-        unsafe { ThinRef::new(f.get_vptr()) }
+    /// Return the raw pointer contained in this thin reference.
+    ///
+    /// This is the address of the VPtr field within the object, it can be passed to C code as a
+    /// `void*` and converted back with [`ThinRef::from_raw`]
+    pub fn into_raw(this: Self) -> *const () {
+        this.ptr.as_ptr() as *const ()
     }
-}
 
-// Cannot use #[derive()] because it gets the bounds wrong (See rust RFC #2353)
-impl<'a, Trait: ?Sized> Clone for ThinRef<'a, Trait> {
-    fn clone(&self) -> Self {
-        *self
+    /// Create a thin reference from a raw pointer returned by [`ThinRef::into_raw`]
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to the VPtr field for `Trait` of an object which is borrowed
+    /// for the lifetime `'a`
+    pub unsafe fn from_raw(ptr: *const ()) -> Self {
+        ThinRef {
+            ptr: NonNull::new_unchecked(ptr as *mut &'static VTableData),
+            phantom: PhantomData,
+        }
     }
-}
-impl<'a, Trait: ?Sized> Copy for ThinRef<'a, Trait> {}
 
-/// A thin reference (size = `size_of::<usize>()`) to an object implementing the trait `Trait`
-///
-/// Same as ThinRef but for mutable references
-pub struct ThinRefMut<'a, Trait: ?Sized> {
-    ptr: &'a mut &'static VTableData,
-    phantom: PhantomData<&'a mut Trait>,
-}
+    /// Same as [`ThinRef::into_raw`], but `None` is converted to a null pointer
+    ///
+    /// This is useful for a nullable `void*` in a C struct, and can be converted back with
+    /// [`ThinRef::from_raw_opt`]
+    pub fn into_raw_opt(this: Option<Self>) -> *const () {
+        this.map_or(core::ptr::null(), ThinRef::into_raw)
+    }
 
-impl<'a, Trait: ?Sized> ThinRefMut<'a, Trait> {
-    /// Create a new reference from a reference to a VPtr
+    /// Create an optional thin reference from a pointer returned by [`ThinRef::into_raw_opt`]
     ///
-    /// Safety: the ptr must be a field of a reference to T
-    unsafe fn new<T: HasVPtr<Trait>>(ptr: &'a mut VPtr<T, Trait>) -> Self {
-        ThinRefMut {
-            ptr: &mut ptr.vtable,
+    /// # Safety
+    ///
+    /// The pointer must be null, or satisfy the requirements of [`ThinRef::from_raw`]
+    pub unsafe fn from_raw_opt(ptr: *const ()) -> Option<Self> {
+        NonNull::new(ptr as *mut &'static VTableData).map(|ptr| ThinRef {
+            ptr,
             phantom: PhantomData,
-        }
+        })
+    }
+
+    /// Same as [`ThinRef::into_raw`], but return the address as an integer
+    ///
+    /// This can be used as a key in maps indexed by integers, and converted back with
+    /// [`ThinRef::from_usize`]
+    pub fn as_usize(this: Self) -> usize {
+        this.ptr.as_ptr() as usize
+    }
+
+    /// Create a thin reference from an integer returned by [`ThinRef::as_usize`]
+    ///
+    /// # Safety
+    ///
+    /// Same as [`ThinRef::from_raw`]: the integer must be the address of the VPtr field for
+    /// `Trait` of an object which is borrowed for the lifetime `'a`
+    pub unsafe fn from_usize(v: usize) -> Self {
+        ThinRef::from_raw(v as *const ())
+    }
+
+    /// Return a key which is the same for all the objects of the same type
+    ///
+    /// This is the address of the VTableData, which is unique for every type, and can be used
+    /// to sort thin references so that objects of the same type are next to each other.
+    /// See also [`ByVTable`]
+    pub fn by_vtable_key(this: Self) -> usize {
+        ThinRef::vtable_data(this) as *const VTableData as usize
     }
 }
 
-impl<'a, Trait: ?Sized + 'a> Deref for ThinRefMut<'a, Trait> {
-    type Target = Trait;
+impl<'a, Trait: ?Sized + 'a> ThinRef<'a, Trait> {
+    /// Reconstruct the trait object reference, for the full lifetime `'a`
+    fn reconstruct(self) -> &'a Trait {
+        #[cfg(all(feature = "debug_guard", debug_assertions))]
+        internal::check_not_dropped(self.ptr.as_ptr() as usize);
+        unsafe { &*object_ptr(self.ptr) }
+    }
 
-    fn deref(&self) -> &Self::Target {
-        unsafe {
-            let VTableData { offset, vtable } = **self.ptr;
-            let p = (self.ptr as *const _ as *const u8).offset(-offset) as *const ();
-            internal::TransmuterTO::<Trait> {
-                to: internal::TraitObject { data: p, vtable },
+    /// Return the reference to the trait object, for the full lifetime `'a`
+    ///
+    /// Dereferencing the ThinRef, or using the `Borrow` trait, gives a reference which only
+    /// lives as long as the ThinRef itself. Use this function when the reference must outlive
+    /// the ThinRef. In particular, this is the way to call trait methods returning a reference
+    /// into the object, such as `fn name(&self) -> &str`, and get a result which lives for `'a`.
+    ///
+    /// Like the other functions of ThinRef, this is an associated function and not a method, so
+    /// that it does not hide a method of the same name in the trait.
+    ///
+    /// ```rust
+    /// # use vptr::*;
+    /// trait Shape { fn area(&self) -> f32; }
+    /// fn to_fat<'a>(thin: ThinRef<'a, dyn Shape>) -> &'a dyn Shape {
+    ///     ThinRef::get(thin)
+    /// }
+    /// #[vptr(Shape)]
+    /// #[derive(Default)]
+    /// struct Rectangle { w: f32, h : f32 }
+    /// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+    /// let r = Rectangle { w: 5., h: 10., ..Default::default() };
+    /// assert_eq!(to_fat(r.as_thin_ref()).area(), 50.);
+    /// ```
+    pub fn get(this: Self) -> &'a Trait {
+        this.reconstruct()
+    }
+
+    /// Returns true if the pointer to the VTableData in the VPtr field, or the vtable within it,
+    /// is null
+    ///
+    /// This is a cheap check for thin references created with [`ThinRef::from_raw`] from a
+    /// pointer coming from FFI, which only reads the VPtr field and does not dereference the
+    /// object. See also [`ThinRef::try_deref`].
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to memory which is readable as a pointer. The VPtr field is
+    /// only valid if this returns false.
+    pub unsafe fn vtable_is_null(this: Self) -> bool {
+        let data = *(this.ptr.as_ptr() as *const *const VTableData);
+        data.is_null() || (*data).vtable.is_null()
+    }
+
+    /// Reconstruct the trait object reference, unless the vtable is null
+    ///
+    /// When the thin reference was created with [`ThinRef::from_raw`] from a pointer coming
+    /// from FFI, the VPtr field might not have been initialized. This returns `None` if the
+    /// pointer to the VTableData or the vtable within it is null.
+    ///
+    /// # Safety
+    ///
+    /// This only guards against null pointers. Any other invalid value is undefined behavior.
+    pub unsafe fn try_deref(this: Self) -> Option<&'a Trait> {
+        if ThinRef::vtable_is_null(this) {
+            None
+        } else {
+            Some(this.reconstruct())
+        }
+    }
+
+    /// Returns a reference to the object if it is of type `T`, or `None` if it isn't.
+    ///
+    /// The type is checked by comparing the address of the VTableData, which is unique
+    /// for every type.
+    ///
+    /// ```rust
+    /// # use vptr::*;
+    /// trait Shape { fn area(&self) -> f32; }
+    /// #[vptr(Shape)]
+    /// #[derive(Default)]
+    /// struct Rectangle { w: f32, h : f32 }
+    /// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+    /// #[vptr(Shape)]
+    /// #[derive(Default)]
+    /// struct Circle { r: f32 }
+    /// impl Shape for Circle { fn area(&self) -> f32 { 3.14 * self.r * self.r } }
+    ///
+    /// let r = Rectangle { w: 5., h: 10., ..Default::default() };
+    /// let thin: ThinRef<dyn Shape> = r.as_thin_ref();
+    /// assert_eq!(ThinRef::downcast_ref::<Rectangle>(thin).map(|r| r.w), Some(5.));
+    /// assert!(ThinRef::downcast_ref::<Circle>(thin).is_none());
+    /// ```
+    ///
+    /// The type must implement [`DowncastTarget`], so it cannot be a struct with lifetime
+    /// parameters: all its instantiations share the same VTableData, so the lifetimes could not
+    /// be checked.
+    ///
+    /// ```rust,compile_fail
+    /// # use vptr::*;
+    /// trait Named { fn name(&self) -> &str; }
+    /// #[vptr(Named)]
+    /// struct Person<'a> { name: &'a str }
+    /// impl Named for Person<'_> { fn name(&self) -> &str { self.name } }
+    ///
+    /// fn extend(thin: ThinRef<'_, dyn Named>) -> &'static str {
+    ///     ThinRef::downcast_ref::<Person<'static>>(thin).unwrap().name
+    /// }
+    /// ```
+    pub fn downcast_ref<T: HasVPtr<Trait> + DowncastTarget>(this: Self) -> Option<&'a T> {
+        let data = ThinRef::vtable_data(this);
+        if core::ptr::eq(data, T::init()) {
+            unsafe {
+                let p = (this.ptr.as_ptr() as *const u8).wrapping_offset(-data.offset);
+                Some(&*(p as *const T))
             }
-            .ptr
+        } else {
+            None
         }
     }
-}
 
-impl<'a, Trait: ?Sized + 'a> DerefMut for ThinRefMut<'a, Trait> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
+    /// Returns the `TypeId` of the object, if its type was registered with the `register`
+    /// option of the `#[vptr]` macro
+    ///
+    /// Same as [`type_id_of`]
+    #[cfg(feature = "std")]
+    pub fn concrete_type_id(this: Self) -> Option<core::any::TypeId> {
+        type_id_of(this)
+    }
+
+    /// Returns a reference to the object if it is of type `T`, using the `TypeId` of the
+    /// registered types
+    ///
+    /// Unlike [`ThinRef::downcast_ref`], `T` does not need to implement `HasVPtr<Trait>`, but
+    /// the type of the object needs the `register` option of the `#[vptr]` macro, otherwise
+    /// this returns `None`.
+    ///
+    /// ```rust
+    /// # use vptr::*;
+    /// trait Shape { fn area(&self) -> f32; }
+    /// #[vptr(Shape, register)]
+    /// #[derive(Default)]
+    /// struct Rectangle { w: f32, h : f32 }
+    /// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+    ///
+    /// let r = Rectangle { w: 5., h: 10., ..Default::default() };
+    /// let thin: ThinRef<dyn Shape> = r.as_thin_ref();
+    /// assert_eq!(ThinRef::downcast_ref_any::<Rectangle>(thin).map(|r| r.w), Some(5.));
+    /// assert!(ThinRef::downcast_ref_any::<String>(thin).is_none());
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn downcast_ref_any<T: 'static>(this: Self) -> Option<&'a T> {
+        if ThinRef::concrete_type_id(this)? != core::any::TypeId::of::<T>() {
+            return None;
+        }
+        let offset = ThinRef::vtable_data(this).offset;
+        // Safety: the registered VTableData is the one of T, so the offset is the one of the VPtr
+        // field within T
         unsafe {
-            let VTableData { offset, vtable } = **self.ptr;
-            let p = (self.ptr as *mut _ as *mut u8).offset(-offset) as *mut ();
-            union Transmuter<T: ?Sized> {
-                pub ptr: *mut T,
-                pub to: internal::TraitObject,
-            }
-            let ptr = Transmuter::<Trait> {
-                to: internal::TraitObject { data: p, vtable },
-            }
-            .ptr;
-            &mut *ptr
+            let p = (this.ptr.as_ptr() as *const u8).wrapping_offset(-offset);
+            Some(&*(p as *const T))
         }
     }
-}
 
-impl<'a, Trait: ?Sized + 'a> Borrow<Trait> for ThinRefMut<'a, Trait> {
-    fn borrow(&self) -> &Trait {
-        &**self
+    /// Returns an iterator over the objects of type `T` among the given thin references
+    ///
+    /// See also [`ThinRef::partition_type`]
+    ///
+    /// ```rust
+    /// # use vptr::*;
+    /// trait Shape { fn area(&self) -> f32; }
+    /// #[vptr(Shape)]
+    /// #[derive(Default)]
+    /// struct Rectangle { w: f32, h : f32 }
+    /// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+    /// #[vptr(Shape)]
+    /// #[derive(Default)]
+    /// struct Circle { r: f32 }
+    /// impl Shape for Circle { fn area(&self) -> f32 { 3.14 * self.r * self.r } }
+    ///
+    /// let r = Rectangle { w: 5., h: 10., ..Default::default() };
+    /// let c = Circle { r: 1., ..Default::default() };
+    /// let shapes: [ThinRef<dyn Shape>; 2] = [c.as_thin_ref(), r.as_thin_ref()];
+    /// let widths = ThinRef::filter_type::<Rectangle>(shapes).map(|r| r.w);
+    /// assert!(widths.eq([5.]));
+    /// ```
+    pub fn filter_type<T: HasVPtr<Trait> + DowncastTarget>(
+        iter: impl IntoIterator<Item = Self>,
+    ) -> impl Iterator<Item = &'a T> {
+        iter.into_iter().filter_map(ThinRef::downcast_ref::<T>)
     }
-}
 
-impl<'a, Trait: ?Sized + 'a> BorrowMut<Trait> for ThinRefMut<'a, Trait> {
-    fn borrow_mut(&mut self) -> &mut Trait {
-        &mut **self
+    /// Split the thin references between the objects of type `T` and the other ones
+    ///
+    /// The order of the elements is kept in both vectors.
+    /// See also [`ThinRef::filter_type`] which does not allocate.
+    #[cfg(feature = "std")]
+    pub fn partition_type<T: HasVPtr<Trait> + DowncastTarget>(
+        slice: &[Self],
+    ) -> (std::vec::Vec<&'a T>, std::vec::Vec<Self>) {
+        let mut matching = std::vec::Vec::new();
+        let mut others = std::vec::Vec::new();
+        for &x in slice {
+            match ThinRef::downcast_ref::<T>(x) {
+                Some(t) => matching.push(t),
+                None => others.push(x),
+            }
+        }
+        (matching, others)
     }
-}
 
-impl<'a, Trait: ?Sized + 'a, T: HasVPtr<Trait>> From<&'a mut T> for ThinRefMut<'a, Trait> {
-    fn from(f: &'a mut T) -> Self {
-        unsafe { ThinRefMut::new(f.get_vptr_mut()) }
+    /// Convert to a thin reference to a trait object type with the same vtable
+    ///
+    /// # Safety
+    ///
+    /// The vtable of `Trait` must be usable as the vtable of `Other`. This is the case when
+    /// `Other` is the same trait as `Trait` with fewer auto traits (such as `Send` or `Sync`).
+    /// This is not the case for supertraits, see [`ThinRef::upcast`] instead.
+    pub unsafe fn cast<Other: ?Sized>(this: Self) -> ThinRef<'a, Other> {
+        ThinRef {
+            ptr: this.ptr,
+            phantom: PhantomData,
+        }
     }
-}
 
-/// A Box of a trait with a size of `size_of::<usize>`
+    /// Remove auto traits from the trait object type, such as converting a
+    /// `ThinRef<dyn Trait + Send>` to a `ThinRef<dyn Trait>`
+    ///
+    /// The conversion is done by `f`, which is normally `|x| x`, with the target type given
+    /// explicitly. This returns `None` if the resulting reference does not use the same vtable,
+    /// which is the case if `f` does more than removing auto traits (for example an upcast to a
+    /// supertrait)
+    ///
+    /// ```rust
+    /// # use vptr::*;
+    /// trait Shape { fn area(&self) -> f32; }
+    /// #[vptr("dyn Shape + Send")]
+    /// #[derive(Default)]
+    /// struct Rectangle { w: f32, h : f32 }
+    /// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+    ///
+    /// let r = Rectangle { w: 5., h: 10., ..Default::default() };
+    /// let thin: ThinRef<dyn Shape + Send> = r.as_thin_ref();
+    /// let relaxed = ThinRef::relax_auto_traits::<dyn Shape>(thin, |x| x).unwrap();
+    /// assert_eq!(relaxed.area(), 50.);
+    /// ```
+    pub fn relax_auto_traits<Other: ?Sized + 'a>(
+        this: Self,
+        f: impl FnOnce(&'a Trait) -> &'a Other,
+    ) -> Option<ThinRef<'a, Other>> {
+        let original = this.reconstruct();
+        let relaxed = f(original);
+        let (a, b) = unsafe {
+            (
+                internal::to_trait_object::<Trait>(original),
+                internal::to_trait_object::<Other>(relaxed),
+            )
+        };
+        if a.data == b.data && a.vtable == b.vtable {
+            Some(unsafe { Self::cast(this) })
+        } else {
+            None
+        }
+    }
+
+    /// Convert to a thin reference to a supertrait
+    ///
+    /// See [`ThinUpcast`]
+    pub fn upcast<Super: ?Sized>(this: Self) -> ThinRef<'a, Super>
+    where
+        Trait: ThinUpcast<Super>,
+    {
+        this.reconstruct().__thin_upcast()
+    }
+
+    /// The size of the object, as stored in the vtable
+    ///
+    /// Same as `core::mem::size_of_val(&*this)`
+    pub fn size_of_val(this: Self) -> usize {
+        core::mem::size_of_val(this.reconstruct())
+    }
+
+    /// The alignment of the object, as stored in the vtable
+    ///
+    /// Same as `core::mem::align_of_val(&*this)`
+    pub fn align_of_val(this: Self) -> usize {
+        core::mem::align_of_val(this.reconstruct())
+    }
+
+    /// The bytes of the object, for example to write them with a binary serializer
+    ///
+    /// The slice contains [`ThinRef::size_of_val`] bytes from the start of the object. This
+    /// includes the bytes of the VPtr fields, which are addresses only meaningful within the
+    /// current process, so the serializer should skip them or the deserializer should overwrite
+    /// them with `VPtr::new()`. This is only meaningful for plain data types, with a known layout
+    /// such as `#[repr(C)]`.
+    ///
+    /// ```rust
+    /// # use vptr::*;
+    /// trait Shape { fn area(&self) -> f32; }
+    /// #[vptr(Shape)]
+    /// #[repr(C)]
+    /// #[derive(Default)]
+    /// struct Square { size: usize }
+    /// impl Shape for Square { fn area(&self) -> f32 { (self.size * self.size) as f32 } }
+    ///
+    /// let s = Square { size: 3, ..Default::default() };
+    /// let bytes = unsafe { ThinRef::<dyn Shape>::as_bytes(s.as_thin_ref()) };
+    /// assert_eq!(bytes.len(), 2 * std::mem::size_of::<usize>());
+    /// assert_eq!(bytes[..std::mem::size_of::<usize>()], 3usize.to_ne_bytes());
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// All the bytes of the object must be initialized: it must not have padding bytes, or
+    /// fields which may contain uninitialized bytes such as `MaybeUninit` or unions. The object
+    /// must not have interior mutability, as it would be modified while the slice is borrowed.
+    pub unsafe fn as_bytes(this: Self) -> &'a [u8] {
+        let obj = this.reconstruct();
+        core::slice::from_raw_parts(
+            obj as *const Trait as *const u8,
+            core::mem::size_of_val(obj),
+        )
+    }
+
+    /// Map a pinned thin reference to a pinned reference to the trait object.
+    ///
+    /// This is useful to call the methods of the trait which takes `self: Pin<&Self>`,
+    /// in order to access structurally pinned fields.
+    ///
+    /// ```rust
+    /// # use vptr::*;
+    /// # use core::pin::Pin;
+    /// trait Named { fn name(self: Pin<&Self>) -> &str; }
+    /// #[vptr(Named)]
+    /// #[derive(Default)]
+    /// struct Person { name: String }
+    /// impl Named for Person {
+    ///     fn name(self: Pin<&Self>) -> &str { &self.get_ref().name }
+    /// }
+    ///
+    /// let p = Box::pin(Person { name: "Ferris".into(), ..Default::default() });
+    /// let thin: Pin<ThinRef<dyn Named>> = p.as_ref().as_pin_thin_ref();
+    /// assert_eq!(ThinRef::get_pinned_ref(thin).name(), "Ferris");
+    /// ```
+    pub fn get_pinned_ref(this: Pin<ThinRef<'a, Trait>>) -> Pin<&'a Trait> {
+        // Safety: the pointee was pinned and stays pinned
+        unsafe { Pin::new_unchecked(Pin::into_inner_unchecked(this).reconstruct()) }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, Trait: ?Sized + DynClone + 'a> ThinRef<'a, Trait> {
+    /// Clone the object in a new Box
+    fn clone_box(this: Self) -> Box<Trait> {
+        unsafe {
+            let vtable = ThinRef::vtable_data(this).vtable;
+            let data = this.reconstruct().__clone_raw() as *const ();
+            // The clone has the same type, so it uses the same vtable
+            let ptr = internal::from_trait_object::<Trait>(internal::TraitObject { data, vtable });
+            Box::from_raw(ptr as *mut Trait)
+        }
+    }
+
+    /// Clone the object into a new `Arc`
+    ///
+    /// ```rust
+    /// # use vptr::*;
+    /// # use std::sync::Arc;
+    /// trait Shape: DynClone { fn area(&self) -> f32; }
+    /// #[vptr(Shape)]
+    /// #[derive(Default, Clone)]
+    /// struct Rectangle { w: f32, h : f32 }
+    /// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+    ///
+    /// let r = Rectangle { w: 5., h: 10., ..Default::default() };
+    /// let arc: Arc<dyn Shape> = ThinRef::to_arc(r.as_thin_ref());
+    /// assert_eq!(arc.area(), 50.);
+    /// ```
+    pub fn to_arc(this: Self) -> Arc<Trait> {
+        Arc::from(Self::clone_box(this))
+    }
+
+    /// Clone the object into a new `Rc`
+    pub fn to_rc(this: Self) -> Rc<Trait> {
+        Rc::from(Self::clone_box(this))
+    }
+}
+
+impl<'a, Trait: ?Sized + 'a> Deref for ThinRef<'a, Trait> {
+    type Target = Trait;
+
+    fn deref(&self) -> &Self::Target {
+        self.reconstruct()
+    }
+}
+
+impl<'a, Trait: ?Sized + 'a> Borrow<Trait> for ThinRef<'a, Trait> {
+    fn borrow(&self) -> &Trait {
+        self
+    }
+}
+
+/// This allows functions to take an `impl Into<ThinRef<'a, dyn Trait>>`, so that the callers can
+/// pass either a reference to an object, or a thin reference. The lifetime needs to be named,
+/// since `'_` is not allowed within `impl Trait` in argument position.
 ///
-/// The ThinBox can be created from a Box which implement the HasVPtr<Trait>
+/// ```rust
+/// # use vptr::*;
+/// trait Handler { fn id(&self) -> u32; }
+/// #[vptr(Handler)]
+/// #[derive(Default)]
+/// struct Logger { id: u32 }
+/// impl Handler for Logger { fn id(&self) -> u32 { self.id } }
 ///
+/// struct Registry<'a> { handlers: Vec<ThinRef<'a, dyn Handler>> }
+/// impl<'a> Registry<'a> {
+///     fn register(&mut self, h: impl Into<ThinRef<'a, dyn Handler>>) {
+///         self.handlers.push(h.into());
+///     }
+/// }
+///
+/// let l1 = Logger { id: 1, ..Default::default() };
+/// let l2 = Logger { id: 2, ..Default::default() };
+/// let mut registry = Registry { handlers: Vec::new() };
+/// registry.register(&l1);
+/// registry.register(l2.as_thin_ref());
+/// assert_eq!(registry.handlers.iter().map(|h| h.id()).collect::<Vec<_>>(), [1, 2]);
+/// ```
+impl<'a, Trait: ?Sized + 'a, T: HasVPtr<Trait>> From<&'a T> for ThinRef<'a, Trait> {
+    fn from(f: &'a T) -> Self {
+        unsafe { ThinRef::new(f) }
+    }
+}
+
+// Cannot use #[derive()] because it gets the bounds wrong (See rust RFC #2353)
+impl<'a, Trait: ?Sized> Clone for ThinRef<'a, Trait> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<'a, Trait: ?Sized> Copy for ThinRef<'a, Trait> {}
+
+/// Wrapper around a ThinRef which compares and orders by type, using [`ThinRef::by_vtable_key`]
 ///
 /// ```rust
 /// # use vptr::*;
@@ -429,356 +1766,4201 @@ impl<'a, Trait: ?Sized + 'a, T: HasVPtr<Trait>> From<&'a mut T> for ThinRefMut<'
 /// #[derive(Default)]
 /// struct Rectangle { w: f32, h : f32 }
 /// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+/// #[vptr(Shape)]
+/// #[derive(Default)]
+/// struct Circle { r: f32 }
+/// impl Shape for Circle { fn area(&self) -> f32 { 3.14 * self.r * self.r } }
 ///
-/// let r = Box::new(Rectangle { w: 5., h: 10., ..Default::default() });
-/// let thin = ThinBox::from_box(r);
-/// assert_eq!(thin.area(), 50.);
+/// let (r1, r2, c) = (Rectangle::default(), Rectangle::default(), Circle::default());
+/// let mut v = vec![ByVTable(r1.as_thin_ref()), ByVTable(c.as_thin_ref()), ByVTable(r2.as_thin_ref())];
+/// v.sort();
+/// assert!(v[0] != v[2]);
+/// assert!(v[1] == v[0] || v[1] == v[2]);
 /// ```
+pub struct ByVTable<'a, Trait: ?Sized>(pub ThinRef<'a, Trait>);
+
+// Cannot use #[derive()] because it gets the bounds wrong (See rust RFC #2353)
+impl<'a, Trait: ?Sized> Clone for ByVTable<'a, Trait> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<'a, Trait: ?Sized> Copy for ByVTable<'a, Trait> {}
+
+impl<'a, Trait: ?Sized> PartialEq for ByVTable<'a, Trait> {
+    fn eq(&self, other: &Self) -> bool {
+        ThinRef::by_vtable_key(self.0) == ThinRef::by_vtable_key(other.0)
+    }
+}
+impl<'a, Trait: ?Sized> Eq for ByVTable<'a, Trait> {}
+
+impl<'a, Trait: ?Sized> PartialOrd for ByVTable<'a, Trait> {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl<'a, Trait: ?Sized> Ord for ByVTable<'a, Trait> {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        ThinRef::by_vtable_key(self.0).cmp(&ThinRef::by_vtable_key(other.0))
+    }
+}
+
+impl<'a, Trait: ?Sized> core::hash::Hash for ByVTable<'a, Trait> {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        ThinRef::by_vtable_key(self.0).hash(state)
+    }
+}
+
+/// Wrapper around a ThinRef which compares, orders and hashes by the address of the object
+///
+/// Two `Identity` are equal if they refer to the same object.
 ///
-/// The size is the size of a pointer
 /// ```rust
 /// # use vptr::*;
-/// # use std::mem;
-/// # trait Trait { }
-/// assert_eq!(mem::size_of::<ThinBox<dyn Trait>>(), mem::size_of::<usize>());
-/// assert_eq!(mem::size_of::<Option<ThinBox<dyn Trait>>>(), mem::size_of::<usize>());
+/// trait Shape { fn area(&self) -> f32; }
+/// #[vptr(Shape)]
+/// #[derive(Default)]
+/// struct Rectangle { w: f32, h : f32 }
+/// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+///
+/// let (r1, r2) = (Rectangle::default(), Rectangle::default());
+/// assert!(Identity::<dyn Shape>(r1.as_thin_ref()) == Identity(r1.as_thin_ref()));
+/// assert!(Identity::<dyn Shape>(r1.as_thin_ref()) != Identity(r2.as_thin_ref()));
 /// ```
-#[cfg(feature = "std")]
-#[repr(transparent)]
-pub struct ThinBox<Trait: ?Sized + 'static>(NonNull<&'static VTableData>, PhantomData<*mut Trait>);
+pub struct Identity<'a, Trait: ?Sized>(pub ThinRef<'a, Trait>);
 
-#[cfg(feature = "std")]
-#[allow(clippy::wrong_self_convention)]
-impl<Trait: ?Sized + 'static> ThinBox<Trait> {
-    /// Creates a ThinBox from a Box
-    pub fn from_box<T: HasVPtr<Trait>>(f: Box<T>) -> Self {
-        ThinBox(
-            NonNull::from(&mut Box::leak(f).get_vptr_mut().vtable),
-            PhantomData,
-        )
+// Cannot use #[derive()] because it gets the bounds wrong (See rust RFC #2353)
+impl<'a, Trait: ?Sized> Clone for Identity<'a, Trait> {
+    fn clone(&self) -> Self {
+        *self
     }
-    /// Conver the ThinBox into a Box
-    pub fn into_box(mut b: ThinBox<Trait>) -> Box<Trait> {
-        let ptr = (&mut *ThinBox::as_thin_ref_mut(&mut b)) as *mut Trait;
-        core::mem::forget(b);
-        unsafe { Box::from_raw(ptr) }
+}
+impl<'a, Trait: ?Sized> Copy for Identity<'a, Trait> {}
+
+impl<'a, Trait: ?Sized> Deref for Identity<'a, Trait> {
+    type Target = ThinRef<'a, Trait>;
+    fn deref(&self) -> &Self::Target {
+        &self.0
     }
+}
 
-    /// As a ThinRef
-    pub fn as_thin_ref(b: &ThinBox<Trait>) -> ThinRef<Trait> {
-        ThinRef {
-            ptr: unsafe { b.0.as_ref() },
+impl<'a, Trait: ?Sized> PartialEq for Identity<'a, Trait> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.ptr == other.0.ptr
+    }
+}
+impl<'a, Trait: ?Sized> Eq for Identity<'a, Trait> {}
+
+impl<'a, Trait: ?Sized> PartialOrd for Identity<'a, Trait> {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl<'a, Trait: ?Sized> Ord for Identity<'a, Trait> {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        self.0.ptr.cmp(&other.0.ptr)
+    }
+}
+
+impl<'a, Trait: ?Sized> core::hash::Hash for Identity<'a, Trait> {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        self.0.ptr.hash(state)
+    }
+}
+
+/// Wrapper around a ThinRef which compares, orders and hashes the objects it refers to
+///
+/// This uses the implementations of `PartialEq`, `Ord` and `Hash` for `Trait`, which are usually
+/// implemented for the `dyn Trait` type.
+///
+/// ```rust
+/// # use vptr::*;
+/// trait Named { fn name(&self) -> &str; }
+/// impl PartialEq for dyn Named {
+///     fn eq(&self, other: &Self) -> bool { self.name() == other.name() }
+/// }
+/// #[vptr(Named)]
+/// struct Person { name: String }
+/// impl Named for Person { fn name(&self) -> &str { &self.name } }
+///
+/// let p1 = Person { name: "Alice".into(), vptr_Named: VPtr::new() };
+/// let p2 = Person { name: "Alice".into(), vptr_Named: VPtr::new() };
+/// assert!(ByValue::<dyn Named>(p1.as_thin_ref()) == ByValue(p2.as_thin_ref()));
+/// ```
+pub struct ByValue<'a, Trait: ?Sized>(pub ThinRef<'a, Trait>);
+
+// Cannot use #[derive()] because it gets the bounds wrong (See rust RFC #2353)
+impl<'a, Trait: ?Sized> Clone for ByValue<'a, Trait> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<'a, Trait: ?Sized> Copy for ByValue<'a, Trait> {}
+
+impl<'a, Trait: ?Sized> Deref for ByValue<'a, Trait> {
+    type Target = ThinRef<'a, Trait>;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'a, Trait: ?Sized + PartialEq + 'a> PartialEq for ByValue<'a, Trait> {
+    fn eq(&self, other: &Self) -> bool {
+        *self.0 == *other.0
+    }
+}
+impl<'a, Trait: ?Sized + Eq + 'a> Eq for ByValue<'a, Trait> {}
+
+impl<'a, Trait: ?Sized + PartialOrd + 'a> PartialOrd for ByValue<'a, Trait> {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        (*self.0).partial_cmp(&*other.0)
+    }
+}
+impl<'a, Trait: ?Sized + Ord + 'a> Ord for ByValue<'a, Trait> {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        (*self.0).cmp(&*other.0)
+    }
+}
+
+impl<'a, Trait: ?Sized + core::hash::Hash + 'a> core::hash::Hash for ByValue<'a, Trait> {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        (*self.0).hash(state)
+    }
+}
+
+/// A possibly null raw pointer which was vouched to be a valid [`ThinRef`] if it is not null
+///
+/// Creating it is unsafe, but it can then be converted to a `ThinRef` with `TryFrom`, which
+/// fails if the pointer is null. This is useful to convert pointers coming from C code at one
+/// place, and then only use safe code.
+///
+/// ```rust
+/// # use vptr::*;
+/// # use std::convert::TryFrom;
+/// trait Shape { fn area(&self) -> f32; }
+/// #[vptr(Shape)]
+/// #[derive(Default)]
+/// struct Rectangle { w: f32, h : f32 }
+/// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+///
+/// let r = Rectangle { w: 5., h: 10., ..Default::default() };
+/// let raw = ThinRef::<dyn Shape>::into_raw(r.as_thin_ref());
+/// let vouched = unsafe { RawThinPtr::<dyn Shape>::new(raw) };
+/// assert_eq!(ThinRef::try_from(vouched).unwrap().area(), 50.);
+/// let null = unsafe { RawThinPtr::<dyn Shape>::new(core::ptr::null()) };
+/// assert!(ThinRef::try_from(null).is_err());
+/// ```
+pub struct RawThinPtr<'a, Trait: ?Sized> {
+    ptr: *const (),
+    phantom: PhantomData<ThinRef<'a, Trait>>,
+}
+
+impl<'a, Trait: ?Sized> RawThinPtr<'a, Trait> {
+    /// Create a RawThinPtr from a raw pointer
+    ///
+    /// # Safety
+    ///
+    /// The pointer must be null, or satisfy the requirements of [`ThinRef::from_raw`]
+    pub unsafe fn new(ptr: *const ()) -> Self {
+        RawThinPtr {
+            ptr,
             phantom: PhantomData,
         }
     }
 
-    /// As a ThinRefMut
-    pub fn as_thin_ref_mut(b: &mut ThinBox<Trait>) -> ThinRefMut<Trait> {
-        ThinRefMut {
-            ptr: unsafe { b.0.as_mut() },
+    /// Return the raw pointer
+    pub fn as_ptr(self) -> *const () {
+        self.ptr
+    }
+}
+
+// Cannot use #[derive()] because it gets the bounds wrong (See rust RFC #2353)
+impl<'a, Trait: ?Sized> Clone for RawThinPtr<'a, Trait> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<'a, Trait: ?Sized> Copy for RawThinPtr<'a, Trait> {}
+
+impl<'a, Trait: ?Sized> core::fmt::Debug for RawThinPtr<'a, Trait> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("RawThinPtr").field(&self.ptr).finish()
+    }
+}
+
+impl<'a, Trait: ?Sized> From<ThinRef<'a, Trait>> for RawThinPtr<'a, Trait> {
+    fn from(r: ThinRef<'a, Trait>) -> Self {
+        RawThinPtr {
+            ptr: ThinRef::into_raw(r),
             phantom: PhantomData,
         }
     }
 }
 
-#[cfg(feature = "std")]
-impl<Trait: ?Sized + 'static> Drop for ThinBox<Trait> {
-    fn drop(&mut self) {
-        let ptr = &mut *ThinBox::as_thin_ref_mut(self) as *mut Trait;
-        unsafe { Box::from_raw(ptr) };
+impl<'a, Trait: ?Sized> core::convert::TryFrom<RawThinPtr<'a, Trait>> for ThinRef<'a, Trait> {
+    type Error = NullPointerError;
+    fn try_from(p: RawThinPtr<'a, Trait>) -> Result<Self, NullPointerError> {
+        // Safety: guaranteed by the contract of RawThinPtr::new
+        unsafe { ThinRef::from_raw_opt(p.ptr) }.ok_or(NullPointerError)
     }
 }
 
-#[cfg(feature = "std")]
-impl<Trait: ?Sized + 'static> Deref for ThinBox<Trait> {
-    type Target = Trait;
+/// The error returned when converting a null [`RawThinPtr`] into a [`ThinRef`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NullPointerError;
 
-    fn deref(&self) -> &Self::Target {
-        let ptr = &*ThinBox::as_thin_ref(self) as *const Trait;
-        unsafe { &*ptr }
+impl core::fmt::Display for NullPointerError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("null pointer")
     }
 }
 
 #[cfg(feature = "std")]
-impl<Trait: ?Sized + 'static> DerefMut for ThinBox<Trait> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        let ptr = &mut *ThinBox::as_thin_ref_mut(self) as *mut Trait;
-        unsafe { &mut *ptr }
+impl std::error::Error for NullPointerError {}
+
+/// The error returned by the `build` function of the builders generated with the `builder`
+/// option of the `#[vptr]` macro, when a field was not set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UninitializedFieldError(&'static str);
+
+impl UninitializedFieldError {
+    /// Create the error for the field with the given name
+    pub fn new(field_name: &'static str) -> Self {
+        UninitializedFieldError(field_name)
+    }
+
+    /// The name of the field which was not set
+    pub fn field_name(&self) -> &'static str {
+        self.0
     }
 }
 
-/// The data structure generated by the `#[vptr]` macro
-///
-/// You should normaly not use directly this struct
-#[derive(Eq, Hash, PartialEq, PartialOrd)]
-pub struct VTableData {
-    /// Offset, in byte, of the VPtr field within the struct
-    pub offset: isize,
-    /// Pointer to the actual vtable generated by rust (i.e., the second pointer in a TraitObject,
-    /// or core::raw::TraitObject::vtable)
-    pub vtable: *const (),
+impl core::fmt::Display for UninitializedFieldError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "field `{}` was not set", self.0)
+    }
 }
-unsafe impl core::marker::Sync for VTableData {}
 
-/// A convenience module import the most important items
+#[cfg(feature = "std")]
+impl std::error::Error for UninitializedFieldError {}
+
+/// A thin reference (size = `size_of::<usize>()`) to an object implementing the trait `Trait`
 ///
+/// Same as ThinRef but for mutable references
+///
+/// Unlike `ThinRef`, a `ThinRefMut` is neither `Copy` nor `Clone`, since it is unique like a
+/// `&mut` reference. Use [`ThinRefMut::reborrow`] to use it several times.
+///
+/// ```rust,compile_fail
+/// # use vptr::*;
+/// # trait Trait { }
+/// fn assert_copy<T: Copy>() {}
+/// assert_copy::<ThinRefMut<dyn Trait>>();
 /// ```
-/// use vptr::prelude::*;
-/// ```
-pub mod prelude {
-    #[doc(no_inline)]
-    pub use crate::{vptr, HasVPtr};
+pub struct ThinRefMut<'a, Trait: ?Sized> {
+    /// Points to the VPtr field within the object (see ThinRef::ptr)
+    ptr: NonNull<&'static VTableData>,
+    phantom: PhantomData<&'a mut Trait>,
 }
 
-#[doc(hidden)]
-pub mod internal {
-    /// Internal struct used by the macro generated code
-    /// Copy of core::raw::TraitObject since it is unstable
-    #[doc(hidden)]
-    #[repr(C)]
-    #[derive(Copy, Clone)]
-    pub struct TraitObject {
-        pub data: *const (),
-        pub vtable: *const (),
-    }
+// Same as for &'a mut Trait
+unsafe impl<'a, Trait: ?Sized + Send> Send for ThinRefMut<'a, Trait> {}
+unsafe impl<'a, Trait: ?Sized + Sync> Sync for ThinRefMut<'a, Trait> {}
 
-    /// Internal struct used by the macro generated code
-    #[doc(hidden)]
-    pub union TransmuterPtr<T: 'static> {
-        pub ptr: &'static T,
-        pub int: isize,
+// Same as for ThinRef
+impl<'a, Trait: ?Sized> Unpin for ThinRefMut<'a, Trait> {}
+
+impl<'a, Trait: ?Sized> ThinRefMut<'a, Trait> {
+    /// Create a new reference to an object
+    ///
+    /// Safety: T::init() must return the VTableData for T (guaranteed by HasVPtr's requirements)
+    unsafe fn new<T: HasVPtr<Trait>>(obj: &'a mut T) -> Self {
+        #[cfg(all(feature = "debug_guard", debug_assertions))]
+        internal::forget_dropped(obj as *mut T as usize, core::mem::size_of::<T>());
+        let p = (obj as *mut T as *mut u8).wrapping_offset(T::init().offset);
+        ThinRefMut {
+            ptr: NonNull::new_unchecked(p as *mut &'static VTableData),
+            phantom: PhantomData,
+        }
     }
 
-    /// Internal struct used by the macro generated code
+    /// The VTableData in the VPtr field
+    fn vtable_data(&self) -> &'static VTableData {
+        unsafe { *self.ptr.as_ptr() }
+    }
+
+    /// Return the raw pointer contained in this thin reference.
+    ///
+    /// Same as [`ThinRef::into_raw`], but for mutable references
+    pub fn into_raw(this: Self) -> *mut () {
+        this.ptr.as_ptr() as *mut ()
+    }
+
+    /// Create a thin reference from a raw pointer returned by [`ThinRefMut::into_raw`]
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to the VPtr field for `Trait` of an object which is mutably
+    /// borrowed for the lifetime `'a`
+    pub unsafe fn from_raw(ptr: *mut ()) -> Self {
+        ThinRefMut {
+            ptr: NonNull::new_unchecked(ptr as *mut &'static VTableData),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Reborrow this reference for a shorter lifetime, leaving `self` usable once the
+    /// returned reference is no longer used.
+    ///
+    /// This is the equivalent of `&mut *r` for a `&mut dyn Trait`
+    pub fn reborrow(&mut self) -> ThinRefMut<'_, Trait> {
+        ThinRefMut {
+            ptr: self.ptr,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Return a shared reference to the same object, borrowing this reference.
+    ///
+    /// The returned reference only lives as long as the borrow of `this`, after which this
+    /// reference can be used mutably again. Use [`ThinRefMut::into_thin_ref`] to get a shared
+    /// reference for the full lifetime `'a`.
+    pub fn as_shared(this: &Self) -> ThinRef<'_, Trait> {
+        ThinRef {
+            ptr: this.ptr,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Convert this reference into a shared reference with the same lifetime `'a`.
+    ///
+    /// This is the equivalent of coercing a `&'a mut dyn Trait` into a `&'a dyn Trait`: the
+    /// mutable reference is consumed, but the shared reference keeps the full lifetime.
+    ///
+    /// ```rust
+    /// # use vptr::*;
+    /// trait Shape { fn area(&self) -> f32; }
+    /// #[vptr(Shape)]
+    /// #[derive(Default)]
+    /// struct Rectangle { w: f32, h : f32 }
+    /// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+    ///
+    /// fn biggest<'a>(a: ThinRefMut<'a, dyn Shape>, b: ThinRef<'a, dyn Shape>) -> ThinRef<'a, dyn Shape> {
+    ///     let a = ThinRefMut::into_thin_ref(a);
+    ///     if a.area() > b.area() { a } else { b }
+    /// }
+    /// let mut r1 = Rectangle { w: 5., h: 10., ..Default::default() };
+    /// let r2 = Rectangle { w: 1., h: 2., ..Default::default() };
+    /// assert_eq!(biggest(r1.as_thin_ref_mut(), r2.as_thin_ref()).area(), 50.);
+    /// ```
+    pub fn into_thin_ref(this: Self) -> ThinRef<'a, Trait> {
+        ThinRef {
+            ptr: this.ptr,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Convert this reference into a mutable reference to the trait object, for the full
+    /// lifetime `'a`
+    ///
+    /// Dereferencing the ThinRefMut gives a reference which only lives as long as the borrow of
+    /// the ThinRefMut, after which the ThinRefMut can be used again. This function consumes the
+    /// ThinRefMut instead, since it is not possible to keep both.
+    ///
+    /// ```rust
+    /// # use vptr::*;
+    /// trait Shape { fn scale(&mut self, f: f32); }
+    /// fn to_fat<'a>(thin: ThinRefMut<'a, dyn Shape>) -> &'a mut dyn Shape {
+    ///     ThinRefMut::into_mut(thin)
+    /// }
+    /// ```
+    pub fn into_mut(this: Self) -> &'a mut Trait
+    where
+        Trait: 'a,
+    {
+        unsafe { &mut *object_ptr(this.ptr) }
+    }
+
+    /// Map a pinned mutable thin reference to a pinned mutable reference to the trait object.
+    ///
+    /// This is useful to call the methods of the trait which takes `self: Pin<&mut Self>`,
+    /// such as poll-style methods.
+    ///
+    /// ```rust
+    /// # use vptr::*;
+    /// # use core::pin::Pin;
+    /// trait Counter { fn bump(self: Pin<&mut Self>) -> u32; }
+    /// #[vptr(Counter)]
+    /// #[derive(Default)]
+    /// struct Clicks { count: u32 }
+    /// impl Counter for Clicks {
+    ///     fn bump(mut self: Pin<&mut Self>) -> u32 { self.count += 1; self.count }
+    /// }
+    /// let mut c = Box::pin(Clicks::default());
+    /// let thin: Pin<ThinRefMut<dyn Counter>> = c.as_mut().as_pin_thin_ref_mut();
+    /// assert_eq!(ThinRefMut::get_pinned_mut(thin).bump(), 1);
+    /// ```
+    pub fn get_pinned_mut(this: Pin<ThinRefMut<'a, Trait>>) -> Pin<&'a mut Trait>
+    where
+        Trait: 'a,
+    {
+        // Safety: the pointee was pinned and stays pinned
+        unsafe { Pin::new_unchecked(ThinRefMut::into_mut(Pin::into_inner_unchecked(this))) }
+    }
+}
+
+impl<'a, Trait: ?Sized + 'a> ThinRefMut<'a, Trait> {
+    /// Swap the objects pointed by the two references, if they are of the same type.
+    ///
+    /// This is the equivalent of `core::mem::swap` for two objects of the same type.
+    /// Returns false and does nothing if the types are different.
+    ///
+    /// ```rust
+    /// # use vptr::*;
+    /// trait Shape { fn area(&self) -> f32; }
+    /// #[vptr(Shape)]
+    /// #[derive(Default)]
+    /// struct Rectangle { w: f32, h : f32 }
+    /// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+    ///
+    /// let mut r1 = Rectangle { w: 5., h: 10., ..Default::default() };
+    /// let mut r2 = Rectangle { w: 1., h: 2., ..Default::default() };
+    /// let mut t1: ThinRefMut<dyn Shape> = r1.as_thin_ref_mut();
+    /// // Safety: Rectangle has no lifetime parameters
+    /// assert!(unsafe { ThinRefMut::swap(&mut t1, &mut r2.as_thin_ref_mut()) });
+    /// assert_eq!(t1.area(), 2.);
+    /// assert_eq!(r2.w, 5.);
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// The type is checked by comparing the VTableData, which is the same for all the lifetimes
+    /// of a struct with lifetime parameters. If the objects are of such a type, each of them must
+    /// be valid with the lifetimes of the other: swapping a `S<'long>` with a `S<'short>` would
+    /// leave a dangling reference in the first object.
+    pub unsafe fn swap(this: &mut Self, other: &mut ThinRefMut<'_, Trait>) -> bool {
+        let data = this.vtable_data();
+        if !core::ptr::eq(data, other.vtable_data()) {
+            return false;
+        }
+        let size = core::mem::size_of_val::<Trait>(&**this);
+        // Both objects have the same type, and the VPtr at the same offset
+        let a = (this.ptr.as_ptr() as *mut u8).wrapping_offset(-data.offset);
+        let b = (other.ptr.as_ptr() as *mut u8).wrapping_offset(-data.offset);
+        core::ptr::swap_nonoverlapping(a, b, size);
+        true
+    }
+}
+
+impl<'a, Trait: ?Sized + 'a> Deref for ThinRefMut<'a, Trait> {
+    type Target = Trait;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*object_ptr(self.ptr) }
+    }
+}
+
+impl<'a, Trait: ?Sized + 'a> DerefMut for ThinRefMut<'a, Trait> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *object_ptr(self.ptr) }
+    }
+}
+
+impl<'a, Trait: ?Sized + 'a> Borrow<Trait> for ThinRefMut<'a, Trait> {
+    fn borrow(&self) -> &Trait {
+        self
+    }
+}
+
+impl<'a, Trait: ?Sized + 'a> BorrowMut<Trait> for ThinRefMut<'a, Trait> {
+    fn borrow_mut(&mut self) -> &mut Trait {
+        self
+    }
+}
+
+impl<'a, Trait: ?Sized + 'a, T: HasVPtr<Trait>> From<&'a mut T> for ThinRefMut<'a, Trait> {
+    fn from(f: &'a mut T) -> Self {
+        unsafe { ThinRefMut::new(f) }
+    }
+}
+
+/// A Box of a trait with a size of `size_of::<usize>`
+///
+/// The ThinBox can be created from a Box which implement the HasVPtr<Trait>
+///
+///
+/// ```rust
+/// # use vptr::*;
+/// trait Shape { fn area(&self) -> f32; }
+/// #[vptr(Shape)]
+/// #[derive(Default)]
+/// struct Rectangle { w: f32, h : f32 }
+/// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+///
+/// let r = Box::new(Rectangle { w: 5., h: 10., ..Default::default() });
+/// let thin = ThinBox::from_box(r);
+/// assert_eq!(thin.area(), 50.);
+/// ```
+///
+/// Like `Box`, a `ThinBox` is always `Unpin`, even if the object it contains is not.
+/// Use `Pin<ThinBox<Trait>>` to pin the object.
+///
+/// The size is the size of a pointer
+/// ```rust
+/// # use vptr::*;
+/// # use std::mem;
+/// # trait Trait { }
+/// assert_eq!(mem::size_of::<ThinBox<dyn Trait>>(), mem::size_of::<usize>());
+/// assert_eq!(mem::size_of::<Option<ThinBox<dyn Trait>>>(), mem::size_of::<usize>());
+/// ```
+#[cfg(feature = "std")]
+#[repr(transparent)]
+pub struct ThinBox<Trait: ?Sized + 'static>(NonNull<&'static VTableData>, PhantomData<*mut Trait>);
+
+// Same as for Box<Trait>
+#[cfg(feature = "std")]
+unsafe impl<Trait: ?Sized + Send + 'static> Send for ThinBox<Trait> {}
+#[cfg(feature = "std")]
+unsafe impl<Trait: ?Sized + Sync + 'static> Sync for ThinBox<Trait> {}
+
+// Like Box, moving the ThinBox does not move the object it owns
+#[cfg(feature = "std")]
+impl<Trait: ?Sized + 'static> Unpin for ThinBox<Trait> {}
+
+#[cfg(feature = "std")]
+#[allow(clippy::wrong_self_convention)]
+impl<Trait: ?Sized + 'static> ThinBox<Trait> {
+    /// Creates a ThinBox from a Box
+    ///
+    /// Dropping the result drops the object, so not using it is most likely a mistake:
+    /// ```rust,compile_fail
+    /// #![deny(unused_must_use)]
+    /// # use vptr::*;
+    /// # trait Shape { }
+    /// # #[vptr(Shape)] #[derive(Default)] struct Rectangle { w: f32 }
+    /// # impl Shape for Rectangle {}
+    /// ThinBox::<dyn Shape>::from_box(Box::new(Rectangle::default()));
+    /// ```
+    ///
+    /// Like for `Box<dyn Trait>`, the object must be `'static`, since the ThinBox does not
+    /// have a lifetime:
+    /// ```rust,compile_fail
+    /// # use vptr::*;
+    /// # trait Named { fn name(&self) -> &str; }
+    /// #[vptr(Named)]
+    /// struct Person<'a> { name: &'a str }
+    /// # impl Named for Person<'_> { fn name(&self) -> &str { self.name } }
+    ///
+    /// let name = String::from("Ferris");
+    /// let thin = ThinBox::<dyn Named>::from_box(Box::new(Person { name: &name, vptr_Named: VPtr::new() }));
+    /// drop(name);
+    /// thin.name();
+    /// ```
+    #[must_use = "dropping the ThinBox drops the object"]
+    pub fn from_box<T: HasVPtr<Trait> + 'static>(f: Box<T>) -> Self {
+        // Derive the pointer from the pointer to the whole object (see ThinRef::ptr)
+        let p = Box::into_raw(f) as *mut u8;
+        #[cfg(all(feature = "debug_guard", debug_assertions))]
+        internal::forget_dropped(p as usize, core::mem::size_of::<T>());
+        unsafe {
+            ThinBox(
+                NonNull::new_unchecked(
+                    p.wrapping_offset(T::init().offset) as *mut &'static VTableData
+                ),
+                PhantomData,
+            )
+        }
+    }
+    /// Box the value and pin it, like `Box::pin`
+    ///
+    /// The object cannot be moved out of the box, so it stays at the same address
+    #[must_use = "dropping the ThinBox drops the object"]
+    pub fn pin<T: HasVPtr<Trait> + 'static>(value: T) -> Pin<ThinBox<Trait>> {
+        unsafe { Pin::new_unchecked(ThinBox::from_box(Box::new(value))) }
+    }
+
+    /// Convert the ThinBox into a Box
+    #[must_use = "dropping the Box drops the object, use `drop` to drop it explicitly"]
+    pub fn into_box(mut b: ThinBox<Trait>) -> Box<Trait> {
+        let ptr = (&mut *ThinBox::as_thin_ref_mut(&mut b)) as *mut Trait;
+        core::mem::forget(b);
+        unsafe { Box::from_raw(ptr) }
+    }
+
+    /// Convert the ThinBox into a pinned Box, like `Box::into_pin`
+    #[must_use = "dropping the Box drops the object, use `drop` to drop it explicitly"]
+    pub fn into_pin_box(b: ThinBox<Trait>) -> Pin<Box<Trait>> {
+        Box::into_pin(ThinBox::into_box(b))
+    }
+
+    /// Convert into a ThinBox to another trait of the object, if the object is of type `T`
+    ///
+    /// The object stays in the same allocation. If it is not of type `T`, the ThinBox is
+    /// returned unchanged as the error.
+    ///
+    /// ```rust
+    /// # use vptr::*;
+    /// trait Shape { fn area(&self) -> f32; }
+    /// trait Named { fn name(&self) -> &str; }
+    /// #[vptr(Shape, Named)]
+    /// #[derive(Default)]
+    /// struct Rectangle { w: f32, h : f32 }
+    /// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+    /// impl Named for Rectangle { fn name(&self) -> &str { "rectangle" } }
+    ///
+    /// let shape = ThinBox::<dyn Shape>::from_box(Box::new(Rectangle::default()));
+    /// let named = ThinBox::cross_cast::<Rectangle, dyn Named>(shape).ok().unwrap();
+    /// assert_eq!(named.name(), "rectangle");
+    /// ```
+    pub fn cross_cast<T, Other: ?Sized + 'static>(b: ThinBox<Trait>) -> Result<ThinBox<Other>, Self>
+    where
+        T: HasVPtr<Trait> + HasVPtr<Other> + DowncastTarget,
+    {
+        if !core::ptr::eq(
+            ThinRef::vtable_data(ThinBox::as_thin_ref(&b)),
+            <T as HasVPtr<Trait>>::init(),
+        ) {
+            return Err(b);
+        }
+        let p = (b.0.as_ptr() as *mut u8).wrapping_offset(-<T as HasVPtr<Trait>>::init().offset);
+        core::mem::forget(b);
+        unsafe {
+            Ok(ThinBox(
+                NonNull::new_unchecked(p.wrapping_offset(<T as HasVPtr<Other>>::init().offset)
+                    as *mut &'static VTableData),
+                PhantomData,
+            ))
+        }
+    }
+
+    /// Move the object out of the ThinBox if it is of type `T`, and free the allocation
+    ///
+    /// If the object is not of type `T`, the ThinBox is returned unchanged as the error.
+    ///
+    /// ```rust
+    /// # use vptr::*;
+    /// trait Shape { fn area(&self) -> f32; }
+    /// #[vptr(Shape)]
+    /// #[derive(Default)]
+    /// struct Rectangle { w: f32, h : f32 }
+    /// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+    /// #[vptr(Shape)]
+    /// #[derive(Default)]
+    /// struct Circle { r: f32 }
+    /// impl Shape for Circle { fn area(&self) -> f32 { 3.14 * self.r * self.r } }
+    ///
+    /// let shape = ThinBox::<dyn Shape>::from_box(Box::new(Rectangle { w: 2., ..Default::default() }));
+    /// let shape = ThinBox::into_inner::<Circle>(shape).err().unwrap();
+    /// let r: Rectangle = ThinBox::into_inner(shape).ok().unwrap();
+    /// assert_eq!(r.w, 2.);
+    /// ```
+    pub fn into_inner<T: HasVPtr<Trait> + DowncastTarget>(b: ThinBox<Trait>) -> Result<T, Self> {
+        if !core::ptr::eq(ThinRef::vtable_data(ThinBox::as_thin_ref(&b)), T::init()) {
+            return Err(b);
+        }
+        let p = (b.0.as_ptr() as *mut u8).wrapping_offset(-T::init().offset) as *mut T;
+        core::mem::forget(b);
+        // Safety: the object is a T which was allocated by Box::new in ThinBox::from_box
+        Ok(*unsafe { Box::from_raw(p) })
+    }
+
+    /// As a ThinRef
+    pub fn as_thin_ref(b: &ThinBox<Trait>) -> ThinRef<'_, Trait> {
+        ThinRef {
+            ptr: b.0,
+            phantom: PhantomData,
+        }
+    }
+
+    /// As a ThinRefMut
+    pub fn as_thin_ref_mut(b: &mut ThinBox<Trait>) -> ThinRefMut<'_, Trait> {
+        ThinRefMut {
+            ptr: b.0,
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<Trait: ?Sized + 'static> Drop for ThinBox<Trait> {
+    fn drop(&mut self) {
+        let ptr = &mut *ThinBox::as_thin_ref_mut(self) as *mut Trait;
+        drop(unsafe { Box::from_raw(ptr) });
+    }
+}
+
+#[cfg(feature = "std")]
+impl<Trait: ?Sized + 'static> Deref for ThinBox<Trait> {
+    type Target = Trait;
+
+    fn deref(&self) -> &Self::Target {
+        let ptr = &*ThinBox::as_thin_ref(self) as *const Trait;
+        unsafe { &*ptr }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<Trait: ?Sized + 'static> DerefMut for ThinBox<Trait> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        let ptr = &mut *ThinBox::as_thin_ref_mut(self) as *mut Trait;
+        unsafe { &mut *ptr }
+    }
+}
+
+/// A ThinBox can be cloned if `DynClone` is a supertrait of the trait
+///
+/// ```rust
+/// # use vptr::*;
+/// trait Shape: DynClone { fn area(&self) -> f32; }
+/// #[vptr(Shape)]
+/// #[derive(Default, Clone)]
+/// struct Rectangle { w: f32, h : f32 }
+/// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+///
+/// let thin = ThinBox::<dyn Shape>::from_box(Box::new(Rectangle { w: 5., h: 10., ..Default::default() }));
+/// let thin2 = thin.clone();
+/// assert_eq!(thin2.area(), 50.);
+/// ```
+#[cfg(feature = "std")]
+impl<Trait: ?Sized + DynClone + 'static> Clone for ThinBox<Trait> {
+    fn clone(&self) -> Self {
+        unsafe {
+            let offset = self.0.as_ref().offset;
+            let p = (**self).__clone_raw() as *mut u8;
+            // The clone has the same type, so the same offset for its VPtr
+            ThinBox(
+                NonNull::new_unchecked(p.wrapping_offset(offset) as *mut &'static VTableData),
+                PhantomData,
+            )
+        }
+    }
+}
+
+/// Trait to be used as a supertrait of the trait of a `ThinBox` in order to make it `Clone`
+///
+/// It is implemented for every type implementing `Clone`
+#[cfg(feature = "std")]
+pub trait DynClone {
+    /// Clone self in a new Box and return the pointer of that Box
     #[doc(hidden)]
-    pub union TransmuterTO<'a, T: ?Sized + 'a> {
-        pub ptr: &'a T,
-        pub to: TraitObject,
+    fn __clone_raw(&self) -> *mut ();
+}
+
+#[cfg(feature = "std")]
+impl<T: Clone> DynClone for T {
+    fn __clone_raw(&self) -> *mut () {
+        Box::into_raw(Box::new(self.clone())) as *mut ()
+    }
+}
+
+// Forward some traits from the standard library, so the thin pointers can be used where these
+// traits are required, like Box does. Traits with methods taking self by value cannot be forwarded.
+
+macro_rules! forward_fmt {
+    ($($fmt:ident),*) => {$(
+        impl<'a, Trait: ?Sized + core::fmt::$fmt + 'a> core::fmt::$fmt for ThinRef<'a, Trait> {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                (**self).fmt(f)
+            }
+        }
+        impl<'a, Trait: ?Sized + core::fmt::$fmt + 'a> core::fmt::$fmt for ThinRefMut<'a, Trait> {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                (**self).fmt(f)
+            }
+        }
+        #[cfg(feature = "std")]
+        impl<Trait: ?Sized + core::fmt::$fmt + 'static> core::fmt::$fmt for ThinBox<Trait> {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                (**self).fmt(f)
+            }
+        }
+    )*};
+}
+forward_fmt!(Display, Debug);
+
+impl<'a, Trait: ?Sized + Iterator + 'a> Iterator for ThinRefMut<'a, Trait> {
+    type Item = Trait::Item;
+    fn next(&mut self) -> Option<Self::Item> {
+        (**self).next()
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (**self).size_hint()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<Trait: ?Sized + Iterator + 'static> Iterator for ThinBox<Trait> {
+    type Item = Trait::Item;
+    fn next(&mut self) -> Option<Self::Item> {
+        (**self).next()
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (**self).size_hint()
+    }
+}
+
+impl<'a, Trait: ?Sized + core::fmt::Write + 'a> core::fmt::Write for ThinRefMut<'a, Trait> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        (**self).write_str(s)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<Trait: ?Sized + core::fmt::Write + 'static> core::fmt::Write for ThinBox<Trait> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        (**self).write_str(s)
+    }
+}
+
+// Only for ThinBox: like Box, it owns the object. (Use ByValue for the thin references)
+#[cfg(feature = "std")]
+impl<Trait: ?Sized + core::hash::Hash + 'static> core::hash::Hash for ThinBox<Trait> {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+/// The data structure generated by the `#[vptr]` macro
+///
+/// You should normaly not use directly this struct
+#[derive(Eq, Hash, PartialEq, PartialOrd)]
+pub struct VTableData {
+    /// Offset, in byte, of the VPtr field within the struct
+    pub offset: isize,
+    /// Pointer to the actual vtable generated by rust (i.e., the second pointer in a TraitObject,
+    /// or core::raw::TraitObject::vtable)
+    pub vtable: *const (),
+}
+unsafe impl core::marker::Sync for VTableData {}
+
+/// Returns the `TypeId` of the object, if its type was registered
+///
+/// Types are registered with the `register` option of the `#[vptr]` macro, when a `VPtr`
+/// field of the type is initialized for the first time. This allows to identify the type of
+/// objects which come from any crate, but it costs a synchronization in `VPtr::new()`.
+///
+/// ```rust
+/// # use vptr::*;
+/// # use std::any::TypeId;
+/// trait Shape { fn area(&self) -> f32; }
+/// #[vptr(Shape, register)]
+/// #[derive(Default)]
+/// struct Rectangle { w: f32, h : f32 }
+/// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+/// #[vptr(Shape)]
+/// #[derive(Default)]
+/// struct Circle { r: f32 }
+/// impl Shape for Circle { fn area(&self) -> f32 { 3.14 * self.r * self.r } }
+///
+/// let r = Rectangle::default();
+/// assert_eq!(type_id_of::<dyn Shape>(r.as_thin_ref()), Some(TypeId::of::<Rectangle>()));
+/// let c = Circle::default();
+/// assert_eq!(type_id_of::<dyn Shape>(c.as_thin_ref()), None);
+/// ```
+#[cfg(feature = "std")]
+pub fn type_id_of<Trait: ?Sized>(thin: ThinRef<'_, Trait>) -> Option<core::any::TypeId> {
+    let registry = internal::REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    registry
+        .as_ref()?
+        .get(&ThinRef::by_vtable_key(thin))
+        .copied()
+}
+
+/// Iterate over thin references to each element of the slice
+///
+/// Each ThinRef points to the element in place, so nothing is allocated.
+/// See [`thin_refs_of_slice`] to collect them in a `Vec`.
+pub fn thin_refs_iter<'a, T, Trait>(
+    s: &'a [T],
+) -> impl ExactSizeIterator<Item = ThinRef<'a, Trait>> + DoubleEndedIterator + Clone
+where
+    T: HasVPtr<Trait>,
+    Trait: ?Sized + 'a,
+{
+    s.iter().map(HasVPtr::as_thin_ref)
+}
+
+/// Collect thin references to each element of the slice
+///
+/// This is cheaper than a [`ThinVec`] when all the objects are of the same type and already
+/// stored in a slice or a `Vec`: only the references are allocated, the objects stay where
+/// they are.
+///
+/// ```rust
+/// # use vptr::*;
+/// trait Shape { fn area(&self) -> f32; }
+/// #[vptr(Shape)]
+/// #[derive(Default)]
+/// struct Rectangle { w: f32, h : f32 }
+/// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+///
+/// let rects = vec![
+///     Rectangle { w: 1., h: 2., ..Default::default() },
+///     Rectangle { w: 3., h: 4., ..Default::default() },
+/// ];
+/// let shapes: Vec<ThinRef<dyn Shape>> = thin_refs_of_slice(&rects);
+/// assert_eq!(shapes.iter().map(|s| s.area()).sum::<f32>(), 14.);
+/// ```
+#[cfg(feature = "std")]
+pub fn thin_refs_of_slice<'a, T, Trait>(s: &'a [T]) -> Vec<ThinRef<'a, Trait>>
+where
+    T: HasVPtr<Trait>,
+    Trait: ?Sized + 'a,
+{
+    thin_refs_iter(s).collect()
+}
+
+/// Build the VTableData for a VPtr field at the given offset, with the vtable of `object`
+///
+/// This is what the `#[vptr]` macro does, and it can be used to implement [`HasVPtr`] by hand,
+/// for example for a generic struct which the macro does not support. Since a `static` cannot
+/// depend on generic parameters, the VTableData can be put in an associated constant, and
+/// `init()` can return a reference to it.
+///
+/// ```rust
+/// # use vptr::*;
+/// # use std::mem::MaybeUninit;
+/// trait Shape { fn area(&self) -> f32; }
+/// struct Scaled<T: Shape + 'static> { inner: T, factor: f32, vptr: VPtr<Self, dyn Shape> }
+/// impl<T: Shape> Shape for Scaled<T> {
+///     fn area(&self) -> f32 { self.inner.area() * self.factor }
+/// }
+/// impl<T: Shape> Scaled<T> {
+///     const VTABLE: VTableData = unsafe {
+///         make_vtable_data(
+///             field_offset!(Self, vptr),
+///             MaybeUninit::<Self>::uninit().as_ptr() as *const dyn Shape,
+///         )
+///     };
+/// }
+/// unsafe impl<T: Shape> HasVPtr<dyn Shape> for Scaled<T> {
+///     fn init() -> &'static VTableData { &Self::VTABLE }
+///     fn get_vptr(&self) -> &VPtr<Self, dyn Shape> { &self.vptr }
+///     fn get_vptr_mut(&mut self) -> &mut VPtr<Self, dyn Shape> { &mut self.vptr }
+/// }
+/// # struct Square(f32);
+/// # impl Shape for Square { fn area(&self) -> f32 { self.0 * self.0 } }
+///
+/// let s = Scaled { inner: Square(2.), factor: 3., vptr: VPtr::new() };
+/// assert_eq!(ThinRef::<dyn Shape>::from(&s).area(), 12.);
+/// ```
+///
+/// Note that the compiler does not guarantee that a reference to a constant is always the same
+/// address, so [`ThinRef::downcast_ref`] may fail to recognize the type.
+///
+/// # Safety
+///
+/// `offset` must be the offset of a `VPtr<T, Trait>` field within a type `T`, and `object` must
+/// be a pointer to a `T` (which does not need to be valid or initialized) converted to a pointer
+/// to the trait object.
+pub const unsafe fn make_vtable_data<Trait: ?Sized>(
+    offset: isize,
+    object: *const Trait,
+) -> VTableData {
+    VTableData {
+        offset,
+        vtable: internal::to_trait_object(object).vtable,
+    }
+}
+
+/// Returns true if the thin references to `Trait` have the size of a single pointer
+///
+/// This is always the case, but it can be used to check that guarantee in a `const` context.
+/// See also the [`assert_thin!`] macro.
+pub const fn is_thin<Trait: ?Sized>() -> bool {
+    core::mem::size_of::<ThinRef<Trait>>() == core::mem::size_of::<usize>()
+        && core::mem::size_of::<ThinRefMut<Trait>>() == core::mem::size_of::<usize>()
+}
+
+/// Assert at compile time that thin references to the given trait object have the size of a
+/// single pointer
+///
+/// ```rust
+/// # use vptr::*;
+/// trait Shape { fn area(&self) -> f32; }
+/// assert_thin!(dyn Shape);
+/// ```
+#[macro_export]
+macro_rules! assert_thin {
+    ($trait_:ty) => {
+        const _: () = assert!($crate::is_thin::<$trait_>());
+    };
+}
+
+/// Match a `ThinRef` against a list of concrete types
+///
+/// Each arm is tried in turn with [`ThinRef::downcast_ref`] and the first matching arm is
+/// evaluated with a reference to the concrete type. The last arm `_ => ...` is used when
+/// none of the types matches.
+///
+/// ```rust
+/// # use vptr::*;
+/// trait Shape { fn area(&self) -> f32; }
+/// #[vptr(Shape)]
+/// #[derive(Default)]
+/// struct Rectangle { w: f32, h : f32 }
+/// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+/// #[vptr(Shape)]
+/// #[derive(Default)]
+/// struct Circle { r: f32 }
+/// impl Shape for Circle { fn area(&self) -> f32 { 3.14 * self.r * self.r } }
+///
+/// fn describe(shape: ThinRef<dyn Shape>) -> String {
+///     thin_match!(shape, {
+///         Rectangle(r) => format!("rectangle {}x{}", r.w, r.h),
+///         Circle(c) => format!("circle of radius {}", c.r),
+///         _ => "unknown".to_string(),
+///     })
+/// }
+/// let r = Rectangle { w: 5., h: 10., ..Default::default() };
+/// assert_eq!(describe(r.as_thin_ref()), "rectangle 5x10");
+/// ```
+#[macro_export]
+macro_rules! thin_match {
+    ($thin:expr, { $($($ty:ident)::+ ($var:pat) => $body:expr,)* _ => $fallback:expr $(,)? }) => {{
+        let thin = $thin;
+        $(
+            if let Some($var) = $crate::ThinRef::downcast_ref::<$($ty)::+>(thin) {
+                $body
+            } else
+        )* {
+            $fallback
+        }
+    }};
+}
+
+/// Build an array of `ThinRef` indexed by the keys, which are usually the variants of a
+/// `#[repr(usize)]` enum
+///
+/// Every index from 0 to the number of entries must be given a value exactly once,
+/// otherwise the macro panics.
+///
+/// ```rust
+/// # use vptr::*;
+/// #[derive(Clone, Copy, PartialEq, Debug)]
+/// #[repr(usize)]
+/// enum StateId { Idle, Running, Done }
+///
+/// trait State { fn next(&self) -> StateId; }
+/// #[vptr(State)]
+/// #[derive(Default)]
+/// struct Idle;
+/// impl State for Idle { fn next(&self) -> StateId { StateId::Running } }
+/// #[vptr(State)]
+/// #[derive(Default)]
+/// struct Running { steps: u32 }
+/// impl State for Running { fn next(&self) -> StateId { StateId::Done } }
+/// #[vptr(State)]
+/// #[derive(Default)]
+/// struct Done;
+/// impl State for Done { fn next(&self) -> StateId { StateId::Done } }
+///
+/// let (idle, running, done) = (Idle::default(), Running::default(), Done::default());
+/// let table: [ThinRef<dyn State>; 3] = thin_table! {
+///     StateId::Idle => &idle,
+///     StateId::Running => &running,
+///     StateId::Done => &done,
+/// };
+/// let mut state = StateId::Idle;
+/// state = table[state as usize].next();
+/// assert_eq!(state, StateId::Running);
+/// state = table[state as usize].next();
+/// assert_eq!(state, StateId::Done);
+/// ```
+#[macro_export]
+macro_rules! thin_table {
+    (@unit $key:expr) => { () };
+    ($($key:expr => $value:expr),* $(,)?) => {{
+        const N: usize = <[()]>::len(&[$($crate::thin_table!(@unit $key)),*]);
+        let mut table = [None; N];
+        $(
+            let slot = &mut table[$key as usize];
+            assert!(slot.is_none(), "duplicated key in thin_table!");
+            *slot = Some($crate::ThinRef::from($value));
+        )*
+        table.map(|x| x.unwrap())
+    }};
+}
+
+/// Create a [`ThinVec`] containing the given values
+///
+/// The `dyn Trait` type can be given before a `;`, otherwise it is inferred from the usage.
+///
+/// ```rust
+/// # use vptr::*;
+/// trait Shape { fn area(&self) -> f32; }
+/// #[vptr(Shape)]
+/// #[derive(Default)]
+/// struct Rectangle { w: f32, h : f32 }
+/// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+/// #[vptr(Shape)]
+/// #[derive(Default)]
+/// struct Circle { r: f32 }
+/// impl Shape for Circle { fn area(&self) -> f32 { 3. * self.r * self.r } }
+///
+/// let v = thin_vec![dyn Shape; Rectangle { w: 5., h: 10., ..Default::default() },
+///                              Circle { r: 1., ..Default::default() }];
+/// assert_eq!(v.iter().map(|s| s.area()).sum::<f32>(), 53.);
+/// let empty: ThinVec<dyn Shape> = thin_vec![];
+/// assert!(empty.is_empty());
+/// ```
+#[cfg(feature = "std")]
+#[macro_export]
+macro_rules! thin_vec {
+    (@ty [$($ty:tt)*] ; $($value:expr),* $(,)?) => {{
+        let mut v = $crate::ThinVec::<$($ty)*>::new();
+        $(v.push($value);)*
+        v
+    }};
+    (@ty [$($ty:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::thin_vec!(@ty [$($ty)* $next] $($rest)*)
+    };
+    (dyn $($rest:tt)*) => {
+        $crate::thin_vec!(@ty [dyn] $($rest)*)
+    };
+    ($($value:expr),* $(,)?) => {{
+        let mut v = $crate::ThinVec::new();
+        $(v.push($value);)*
+        v
+    }};
+}
+
+/// A convenience module import the most important items
+///
+/// ```
+/// use vptr::prelude::*;
+/// ```
+pub mod prelude {
+    #[cfg(feature = "std")]
+    #[doc(no_inline)]
+    pub use crate::thin_vec;
+    #[doc(no_inline)]
+    pub use crate::{vptr, HasVPtr};
+}
+
+#[doc(hidden)]
+pub mod internal {
+    /// Internal struct used by the macro generated code
+    /// Copy of core::raw::TraitObject since it is unstable
+    #[doc(hidden)]
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct TraitObject {
+        pub data: *const (),
+        pub vtable: *const (),
+    }
+
+    #[cfg(feature = "std")]
+    pub use std::sync::Once;
+
+    #[cfg(feature = "std")]
+    pub(crate) static REGISTRY: std::sync::Mutex<
+        Option<std::collections::HashMap<usize, core::any::TypeId>>,
+    > = std::sync::Mutex::new(None);
+
+    /// Implemented by the code generated with the `debug_guard` option
+    ///
+    /// # Safety
+    ///
+    /// `GUARD_OFFSET` must be the offset of the DebugGuard field, and `vptr_offsets` must call
+    /// `f` with the offset of each VPtr field
+    pub unsafe trait DebugGuarded {
+        const GUARD_OFFSET: usize;
+        fn vptr_offsets(f: &mut dyn FnMut(usize));
+    }
+
+    /// The addresses of the VPtr fields of the objects dropped with a DebugGuard
+    #[cfg(all(feature = "debug_guard", debug_assertions))]
+    pub(crate) static DROPPED: std::sync::Mutex<std::collections::BTreeSet<usize>> =
+        std::sync::Mutex::new(std::collections::BTreeSet::new());
+
+    /// Remove the records of the dropped objects within the memory of a new object
+    #[cfg(all(feature = "debug_guard", debug_assertions))]
+    pub(crate) fn forget_dropped(object: usize, size: usize) {
+        let mut dropped = DROPPED.lock().unwrap_or_else(|e| e.into_inner());
+        let reused: std::vec::Vec<usize> = dropped
+            .range(object..object.saturating_add(size))
+            .copied()
+            .collect();
+        for address in reused {
+            dropped.remove(&address);
+        }
+    }
+
+    /// Panics if `vptr` is the address of a VPtr field of an object dropped with a DebugGuard
+    #[cfg(all(feature = "debug_guard", debug_assertions))]
+    pub(crate) fn check_not_dropped(vptr: usize) {
+        let dropped = DROPPED
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(&vptr);
+        if dropped {
+            panic!("thin reference to an object which was dropped");
+        }
+    }
+
+    /// Called by the code generated with the `register` option
+    ///
+    /// # Safety
+    ///
+    /// `data` must be the VTableData of the type with the TypeId `id`
+    #[cfg(feature = "std")]
+    pub unsafe fn register_type(data: &'static crate::VTableData, id: core::any::TypeId) {
+        let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+        registry
+            .get_or_insert_with(Default::default)
+            .insert(data as *const crate::VTableData as usize, id);
+    }
+
+    /// Internal struct used by the macro generated code
+    #[doc(hidden)]
+    #[cfg(not(feature = "ptr_metadata"))]
+    pub union TransmuterTO<T: ?Sized> {
+        pub ptr: *const T,
+        pub to: TraitObject,
+    }
+
+    /// Split a pointer to a trait object into its data pointer and its vtable
+    #[cfg(not(feature = "ptr_metadata"))]
+    pub const unsafe fn to_trait_object<T: ?Sized>(ptr: *const T) -> TraitObject {
+        TransmuterTO { ptr }.to
+    }
+
+    /// Build a pointer to a trait object from its data pointer and its vtable
+    #[cfg(not(feature = "ptr_metadata"))]
+    pub unsafe fn from_trait_object<T: ?Sized>(to: TraitObject) -> *const T {
+        TransmuterTO { to }.ptr
+    }
+
+    /// Split a pointer to a trait object into its data pointer and its vtable
+    #[cfg(feature = "ptr_metadata")]
+    pub const unsafe fn to_trait_object<T: ?Sized>(ptr: *const T) -> TraitObject {
+        TraitObject {
+            data: ptr as *const (),
+            vtable: core::mem::transmute_copy(&core::ptr::metadata(ptr)),
+        }
+    }
+
+    /// Build a pointer to a trait object from its data pointer and its vtable
+    #[cfg(feature = "ptr_metadata")]
+    pub unsafe fn from_trait_object<T: ?Sized>(to: TraitObject) -> *const T {
+        debug_assert_eq!(
+            core::mem::size_of::<<T as core::ptr::Pointee>::Metadata>(),
+            core::mem::size_of::<*const ()>()
+        );
+        core::ptr::from_raw_parts(to.data, core::mem::transmute_copy(&to.vtable))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    pub use crate::{vptr, HasVPtr, ThinRef, ThinRefMut, VPtr};
+
+    mod vptr {
+        // Because otherwise, the generated code cannot access the vptr crate.
+        pub use crate::*;
+    }
+
+    trait MyTrait {
+        fn myfn(&self) -> u32;
+    }
+
+    #[vptr(MyTrait)]
+    #[derive(Default)]
+    struct Foobar2 {
+        q: u32,
+    }
+
+    impl MyTrait for Foobar2 {
+        fn myfn(&self) -> u32 {
+            self.q + 4
+        }
+    }
+
+    #[test]
+    fn it_works2() {
+        let f = Foobar2 {
+            q: 5,
+            ..Default::default()
+        };
+        assert_eq!(f.myfn(), 9);
+
+        let xx = f.as_thin_ref();
+        assert_eq!(xx.myfn(), 9);
+    }
+
+    #[vptr(MyTrait, SomeOtherTrait, init_in_place)]
+    #[derive(Default, Debug)]
+    struct Foobar3 {
+        q: u32,
+    }
+
+    impl MyTrait for Foobar3 {
+        fn myfn(&self) -> u32 {
+            self.q + 4
+        }
+    }
+
+    trait SomeOtherTrait {}
+    impl SomeOtherTrait for Foobar3 {}
+
+    #[test]
+    fn it_works3() {
+        let mut f = Foobar3 {
+            q: 5,
+            ..Default::default()
+        };
+        println!("{:?}", f);
+        assert_eq!(f.myfn(), 9);
+
+        {
+            let xx: ThinRef<dyn MyTrait> = f.as_thin_ref();
+            assert_eq!(xx.myfn(), 9);
+        }
+
+        {
+            let xx: ThinRefMut<dyn MyTrait> = f.as_thin_ref_mut();
+            assert_eq!(xx.myfn(), 9);
+        }
+    }
+
+    /*
+    #[vptr(MyTrait)]
+    #[derive(Default)]
+    struct WithGenerics<T> {
+        pub foo: Vec<T>
+    }
+
+    impl<T> MyTrait for WithGenerics<T> {
+        fn myfn(&self)  -> u32 {
+            self.foo.len() as u32
+        }
+    }
+
+    #[test]
+    fn with_generics() {
+        let mut f = WithGenerics::<u32>::default();
+        f.foo.push(3);
+        assert_eq!(f.myfn(), 1);
+
+        let xx : ThinRef<dyn MyTrait> = f.as_thin_ref();
+        assert_eq!(xx.myfn(), 9);
+
+    }
+    */
+
+    #[vptr(MyTrait)]
+    #[derive(Default)]
+    struct WithLifeTime<'a> {
+        pub foo: Option<&'a u32>,
+    }
+
+    impl<'a> MyTrait for WithLifeTime<'a> {
+        fn myfn(&self) -> u32 {
+            *self.foo.unwrap_or(&0)
+        }
+    }
+
+    #[test]
+    fn with_lifetime() {
+        let mut f = WithLifeTime::default();
+        let x = 43;
+        f.foo = Some(&x);
+        assert_eq!(f.myfn(), 43);
+
+        let xx: ThinRef<dyn MyTrait> = f.as_thin_ref();
+        assert_eq!(xx.myfn(), 43);
+    }
+
+    #[vptr(MyTrait, init_in_place)]
+    struct Tuple(u32, u32);
+
+    impl MyTrait for Tuple {
+        fn myfn(&self) -> u32 {
+            self.1
+        }
+    }
+    #[test]
+    fn tuple() {
+        let f = Tuple(42, 43, Default::default());
+        assert_eq!(f.0, 42);
+        assert_eq!(f.myfn(), 43);
+
+        let xx: ThinRef<_> = f.as_thin_ref();
+        assert_eq!(xx.myfn(), 43);
+    }
+
+    #[vptr(MyTrait)]
+    struct Empty1;
+
+    impl MyTrait for Empty1 {
+        fn myfn(&self) -> u32 {
+            88
+        }
+    }
+
+    #[test]
+    fn empty_struct() {
+        let f = Empty1(VPtr::new());
+        assert_eq!(f.myfn(), 88);
+
+        let xx: ThinRef<dyn MyTrait> = f.as_thin_ref();
+        assert_eq!(xx.myfn(), 88);
+    }
+
+    #[test]
+    fn empty_struct_offset() {
+        assert_eq!(<Empty1 as HasVPtr<dyn MyTrait>>::init().offset, 0);
+        assert_eq!(
+            core::mem::size_of::<Empty1>(),
+            core::mem::size_of::<usize>()
+        );
+
+        let f = Empty1(VPtr::new());
+        let xx: ThinRef<dyn MyTrait> = f.as_thin_ref();
+        assert_eq!(
+            &*xx as *const dyn MyTrait as *const u8,
+            &f as *const Empty1 as *const u8
+        );
+        assert_eq!(xx.myfn(), 88);
+    }
+
+    #[vptr(std::fmt::Display)]
+    struct TestDisplay {
+        str: String,
+    }
+    impl std::fmt::Display for TestDisplay {
+        fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(fmt, "Test {}", self.str)
+        }
+    }
+
+    #[test]
+    fn with_path() {
+        let x = TestDisplay {
+            str: "Hello".to_string(),
+            vptr_Display: Default::default(),
+        };
+        let xx: ThinRef<dyn std::fmt::Display> = x.as_thin_ref();
+        assert_eq!(xx.to_string(), "Test Hello");
+    }
+
+    #[test]
+    fn test_trait_with_gen() {
+        trait TraitWithGen<T> {
+            fn compute(&self, x: T) -> u32;
+        }
+        #[vptr("TraitWithGen<u64>")]
+        struct TestTraitWithGen {
+            value: u32,
+        }
+        impl TraitWithGen<u64> for TestTraitWithGen {
+            fn compute(&self, x: u64) -> u32 {
+                self.value + (x as u32)
+            }
+        }
+
+        let x = TestTraitWithGen {
+            value: 44,
+            vptr_TraitWithGen: Default::default(),
+        };
+        let xx: ThinRef<dyn TraitWithGen<u64>> = x.as_thin_ref();
+        assert_eq!(core::mem::size_of_val(&xx), core::mem::size_of::<usize>());
+        assert_eq!(xx.compute(66u64), 44 + 66);
+    }
+
+    #[test]
+    fn test_trait_with_gen_unquoted() {
+        trait TraitWithGen<T> {
+            fn compute(&self, x: T) -> u32;
+        }
+        #[vptr(TraitWithGen<u64>, MyTrait)]
+        struct TestTraitWithGen {
+            value: u32,
+        }
+        impl TraitWithGen<u64> for TestTraitWithGen {
+            fn compute(&self, x: u64) -> u32 {
+                self.value + (x as u32)
+            }
+        }
+        impl MyTrait for TestTraitWithGen {
+            fn myfn(&self) -> u32 {
+                self.value
+            }
+        }
+
+        let x = TestTraitWithGen {
+            value: 44,
+            vptr_TraitWithGen: Default::default(),
+            vptr_MyTrait: Default::default(),
+        };
+        let xx: ThinRef<dyn TraitWithGen<u64>> = x.as_thin_ref();
+        assert_eq!(core::mem::size_of_val(&xx), core::mem::size_of::<usize>());
+        assert_eq!(xx.compute(66u64), 44 + 66);
+        assert_eq!(ThinRef::<dyn MyTrait>::from(&x).myfn(), 44);
+    }
+
+    #[test]
+    fn test_trait_with_assoc_type() {
+        trait Builder {
+            type Out;
+            fn build(&self) -> Self::Out;
+        }
+        #[vptr("Builder<Out = u32>")]
+        struct TestBuilder {
+            value: u32,
+        }
+        impl Builder for TestBuilder {
+            type Out = u32;
+            fn build(&self) -> u32 {
+                self.value * 2
+            }
+        }
+
+        let x = TestBuilder {
+            value: 21,
+            vptr_Builder: Default::default(),
+        };
+        let xx: ThinRef<dyn Builder<Out = u32>> = x.as_thin_ref();
+        assert_eq!(core::mem::size_of_val(&xx), core::mem::size_of::<usize>());
+        assert_eq!(xx.build(), 42);
+    }
+
+    #[vptr(MyTrait, cfg(test, SomeOtherTrait), cfg(not(test), std::fmt::Debug))]
+    #[derive(Default)]
+    struct WithCfg {
+        q: u32,
+    }
+
+    impl MyTrait for WithCfg {
+        fn myfn(&self) -> u32 {
+            self.q
+        }
+    }
+    impl SomeOtherTrait for WithCfg {}
+
+    #[test]
+    fn with_cfg() {
+        let f = WithCfg {
+            q: 3,
+            vptr_MyTrait: VPtr::new(),
+            vptr_SomeOtherTrait: VPtr::new(),
+        };
+        assert_eq!(
+            core::mem::size_of::<WithCfg>(),
+            core::mem::size_of::<usize>() * 3
+        );
+        // Only the fields whose condition is true are counted
+        const _: () = assert!(WithCfg::VPTR_OVERHEAD_BYTES == 2 * core::mem::size_of::<usize>());
+        assert_eq!(Foobar2::VPTR_OVERHEAD_BYTES, core::mem::size_of::<usize>());
+        assert_eq!(Tuple::VPTR_OVERHEAD_BYTES, core::mem::size_of::<usize>());
+        let xx: ThinRef<dyn MyTrait> = f.as_thin_ref();
+        assert_eq!(xx.myfn(), 3);
+        let _yy: ThinRef<dyn SomeOtherTrait> = f.as_thin_ref();
+    }
+
+    #[vptr(MyTrait, SomeOtherTrait)]
+    struct Large {
+        data: [u8; 65536],
+        last: u32,
+    }
+
+    impl MyTrait for Large {
+        fn myfn(&self) -> u32 {
+            self.data[65535] as u32 + self.last
+        }
+    }
+    impl SomeOtherTrait for Large {}
+
+    #[test]
+    fn large_struct() {
+        let mut f = Box::new(Large {
+            data: [0; 65536],
+            last: 4,
+            vptr_MyTrait: VPtr::new(),
+            vptr_SomeOtherTrait: VPtr::new(),
+        });
+        f.data[65535] = 3;
+        let offset = <Large as HasVPtr<dyn MyTrait>>::init().offset;
+        assert!(offset >= 0 && (offset as usize) < core::mem::size_of::<Large>());
+        let xx: ThinRef<dyn MyTrait> = f.as_thin_ref();
+        assert_eq!(xx.myfn(), 7);
+        let _yy: ThinRef<dyn SomeOtherTrait> = f.as_thin_ref();
+    }
+
+    trait Callback {
+        fn call(&self, x: i32, y: u8) -> i32;
+        fn set(&mut self, x: i32);
+    }
+
+    #[vptr(
+        MyTrait,
+        c_abi(
+            Callback,
+            "fn call(&self, x: i32, y: u8) -> i32",
+            "fn set(&mut self, x: i32)"
+        )
+    )]
+    #[derive(Default)]
+    struct WithCAbi {
+        value: i32,
+    }
+
+    impl MyTrait for WithCAbi {
+        fn myfn(&self) -> u32 {
+            self.value as u32
+        }
+    }
+    impl Callback for WithCAbi {
+        fn call(&self, x: i32, y: u8) -> i32 {
+            self.value * x + y as i32
+        }
+        fn set(&mut self, x: i32) {
+            self.value = x
+        }
+    }
+
+    #[test]
+    fn c_abi() {
+        let mut f = WithCAbi {
+            value: 2,
+            ..Default::default()
+        };
+        let this = ThinRefMut::<dyn Callback>::into_raw(f.as_thin_ref_mut());
+        let call: unsafe extern "C" fn(*const core::ffi::c_void, i32, u8) -> i32 = WithCAbi_call;
+        let set: unsafe extern "C" fn(*mut core::ffi::c_void, i32) = WithCAbi_set;
+        unsafe {
+            assert_eq!(call(this as *const _, 10, 1), 21);
+            set(this as *mut _, 5);
+            assert_eq!(call(this as *const _, 10, 1), 51);
+        }
+        assert_eq!(f.myfn(), 5);
+    }
+
+    #[test]
+    fn elided_lifetimes() {
+        fn first(list: &[Foobar2]) -> ThinRef<'_, dyn MyTrait> {
+            list[0].as_thin_ref()
+        }
+        fn first_mut(list: &mut [Foobar2]) -> ThinRefMut<'_, dyn MyTrait> {
+            ThinRefMut::from(&mut list[0])
+        }
+        fn bigger<'a>(a: &'a Foobar2, b: &'a Foobar2) -> ThinRef<'a, dyn MyTrait> {
+            if a.q > b.q {
+                a.as_thin_ref()
+            } else {
+                ThinRef::from(b)
+            }
+        }
+        struct Holder(Foobar2);
+        impl Holder {
+            fn get(&self) -> ThinRef<'_, dyn MyTrait> {
+                self.0.as_thin_ref()
+            }
+        }
+
+        let mut list = [
+            Foobar2 {
+                q: 1,
+                ..Default::default()
+            },
+            Foobar2 {
+                q: 2,
+                ..Default::default()
+            },
+        ];
+        assert_eq!(first(&list).myfn(), 5);
+        assert_eq!(first_mut(&mut list).myfn(), 5);
+        assert_eq!(bigger(&list[0], &list[1]).myfn(), 6);
+        let holder = Holder(Foobar2 {
+            q: 3,
+            ..Default::default()
+        });
+        assert_eq!(holder.get().myfn(), 7);
+    }
+
+    #[test]
+    fn raw() {
+        let f = Tuple(1, 2, VPtr::new());
+        let raw = ThinRef::<dyn MyTrait>::into_raw(f.as_thin_ref());
+        assert_eq!(raw, f.get_vptr() as *const _ as *const ());
+        let xx = unsafe { ThinRef::<dyn MyTrait>::from_raw(raw) };
+        assert_eq!(xx.myfn(), 2);
+
+        let key = ThinRef::as_usize(xx);
+        assert_eq!(key, raw as usize);
+        let yy = unsafe { ThinRef::<dyn MyTrait>::from_usize(key) };
+        assert_eq!(yy.myfn(), 2);
+
+        assert_eq!(ThinRef::into_raw_opt(Some(yy)), raw);
+        let zz = unsafe { ThinRef::<dyn MyTrait>::from_raw_opt(raw) };
+        assert_eq!(zz.map(|z| z.myfn()), Some(2));
+        let null = ThinRef::<dyn MyTrait>::into_raw_opt(None);
+        assert!(null.is_null());
+        assert!(unsafe { ThinRef::<dyn MyTrait>::from_raw_opt(null) }.is_none());
+    }
+
+    #[test]
+    fn partition_type() {
+        let f2 = Foobar2 {
+            q: 1,
+            ..Default::default()
+        };
+        let f3 = Foobar3 {
+            q: 2,
+            ..Default::default()
+        };
+        let t = Tuple(3, 4, VPtr::new());
+        let list: [ThinRef<dyn MyTrait>; 4] = [
+            f2.as_thin_ref(),
+            f3.as_thin_ref(),
+            t.as_thin_ref(),
+            f2.as_thin_ref(),
+        ];
+        let (foobar2, others) = ThinRef::partition_type::<Foobar2>(&list);
+        assert_eq!(foobar2.len(), 2);
+        assert!(foobar2.iter().all(|f| core::ptr::eq(*f, &f2)));
+        assert_eq!(others.iter().map(|x| x.myfn()).collect::<Vec<_>>(), [6, 4]);
+
+        assert_eq!(
+            ThinRef::filter_type::<Tuple>(list)
+                .map(|t| t.0)
+                .sum::<u32>(),
+            3
+        );
+        assert_eq!(
+            ThinRef::filter_type::<Foobar3>(list.iter().copied()).count(),
+            1
+        );
+        let (none, all) = ThinRef::partition_type::<WithCfgTrait>(&list);
+        assert!(none.is_empty());
+        assert_eq!(all.len(), 4);
+    }
+
+    #[test]
+    fn raw_thin_ptr() {
+        use crate::{NullPointerError, RawThinPtr};
+        use core::convert::TryFrom;
+        let f = Tuple(1, 2, VPtr::new());
+        let raw = RawThinPtr::from(ThinRef::<dyn MyTrait>::from(&f));
+        assert_eq!(raw.as_ptr(), f.get_vptr() as *const _ as *const ());
+        let xx = ThinRef::try_from(raw).unwrap();
+        assert_eq!(xx.myfn(), 2);
+        let yy = ThinRef::try_from(unsafe { RawThinPtr::<dyn MyTrait>::new(raw.as_ptr()) });
+        assert_eq!(yy.map(|y| y.myfn()), Ok(2));
+        let null = unsafe { RawThinPtr::<dyn MyTrait>::new(core::ptr::null()) };
+        assert_eq!(ThinRef::try_from(null).err(), Some(NullPointerError));
+        assert_eq!(NullPointerError.to_string(), "null pointer");
+    }
+
+    #[test]
+    fn register() {
+        use core::any::TypeId;
+        #[vptr(MyTrait, SomeOtherTrait, register)]
+        #[derive(Default)]
+        struct Registered1 {
+            q: u32,
+        }
+        impl MyTrait for Registered1 {
+            fn myfn(&self) -> u32 {
+                self.q
+            }
+        }
+        impl SomeOtherTrait for Registered1 {}
+        #[vptr(MyTrait, register)]
+        struct Registered2(u32);
+        impl MyTrait for Registered2 {
+            fn myfn(&self) -> u32 {
+                self.0
+            }
+        }
+
+        let r1 = Registered1::default();
+        let r2 = Registered2(42, VPtr::new());
+        let f2 = Foobar2::default();
+        let list: [ThinRef<dyn MyTrait>; 3] =
+            [r1.as_thin_ref(), r2.as_thin_ref(), f2.as_thin_ref()];
+        let ids: Vec<_> = list.iter().map(|x| crate::type_id_of(*x)).collect();
+        assert_eq!(
+            ids,
+            [
+                Some(TypeId::of::<Registered1>()),
+                Some(TypeId::of::<Registered2>()),
+                None
+            ]
+        );
+        assert_eq!(
+            crate::type_id_of::<dyn SomeOtherTrait>(r1.as_thin_ref()),
+            Some(TypeId::of::<Registered1>())
+        );
+    }
+
+    #[test]
+    fn vptr_newtype() {
+        mod foreign {
+            #[derive(Debug, PartialEq)]
+            pub struct Value(pub u32);
+        }
+        #[crate::vptr_newtype(MyTrait, SomeOtherTrait)]
+        struct Wrapper(foreign::Value);
+        impl MyTrait for Wrapper {
+            fn myfn(&self) -> u32 {
+                self.0 .0
+            }
+        }
+        impl SomeOtherTrait for Wrapper {}
+
+        let mut w = Wrapper::from(foreign::Value(4));
+        assert_eq!(ThinRef::<dyn MyTrait>::from(&w).myfn(), 4);
+        (*w).0 = 5;
+        assert_eq!(*w, foreign::Value(5));
+        let thin: ThinRef<dyn SomeOtherTrait> = w.as_thin_ref();
+        assert!(ThinRef::downcast_ref::<Wrapper>(thin).is_some());
+        assert_eq!(w.into_inner(), foreign::Value(5));
+    }
+
+    #[test]
+    fn large_vtable() {
+        macro_rules! many_methods {
+            ($($m:ident = $v:expr,)*) => {
+                trait Large {
+                    $(fn $m(&self) -> u32;)*
+                    fn last(&self) -> u32;
+                }
+                #[vptr(Large)]
+                #[derive(Default)]
+                struct Impl {
+                    base: u32,
+                }
+                impl Large for Impl {
+                    $(fn $m(&self) -> u32 { self.base + $v })*
+                    fn last(&self) -> u32 {
+                        self.base + 0xdead
+                    }
+                }
+                fn all(x: &dyn Large) -> [u32; 24] {
+                    [$(x.$m(),)* x.last()]
+                }
+            };
+        }
+        many_methods!(
+            m0 = 0,
+            m1 = 1,
+            m2 = 2,
+            m3 = 3,
+            m4 = 4,
+            m5 = 5,
+            m6 = 6,
+            m7 = 7,
+            m8 = 8,
+            m9 = 9,
+            m10 = 10,
+            m11 = 11,
+            m12 = 12,
+            m13 = 13,
+            m14 = 14,
+            m15 = 15,
+            m16 = 16,
+            m17 = 17,
+            m18 = 18,
+            m19 = 19,
+            m20 = 20,
+            m21 = 21,
+            m22 = 22,
+        );
+
+        let x = Impl {
+            base: 1000,
+            ..Default::default()
+        };
+        let thin: ThinRef<dyn Large> = x.as_thin_ref();
+        assert_eq!(thin.last(), 1000 + 0xdead);
+        assert_eq!(thin.m22(), 1022);
+        assert_eq!(thin.m0(), 1000);
+        let fat: &dyn Large = &x;
+        assert_eq!(all(&*thin), all(fat));
+        assert_eq!(all(fat)[23], 1000 + 0xdead);
+    }
+
+    #[test]
+    fn event_dispatch() {
+        use crate::ThinBox;
+        enum Event<'a> {
+            Click(i32, i32),
+            Key(&'a str),
+        }
+        trait Handler {
+            fn handle(&mut self, event: &Event) -> Option<String>;
+        }
+        #[vptr(Handler)]
+        #[derive(Default)]
+        struct ClickCounter {
+            clicks: u32,
+        }
+        impl Handler for ClickCounter {
+            fn handle(&mut self, event: &Event) -> Option<String> {
+                match event {
+                    Event::Click(..) => {
+                        self.clicks += 1;
+                        Some(format!("click #{}", self.clicks))
+                    }
+                    _ => None,
+                }
+            }
+        }
+        #[vptr(Handler)]
+        #[derive(Default)]
+        struct Logger {
+            log: Vec<String>,
+        }
+        impl Handler for Logger {
+            fn handle(&mut self, event: &Event) -> Option<String> {
+                self.log.push(match event {
+                    Event::Click(x, y) => format!("{},{}", x, y),
+                    Event::Key(k) => k.to_string(),
+                });
+                None
+            }
+        }
+
+        let mut handlers: Vec<ThinBox<dyn Handler>> = vec![
+            ThinBox::from_box(Box::new(ClickCounter::default())),
+            ThinBox::from_box(Box::new(Logger::default())),
+            ThinBox::from_box(Box::new(ClickCounter::default())),
+        ];
+        let mut dispatch = |event: &Event| -> Vec<String> {
+            handlers
+                .iter_mut()
+                .filter_map(|h| h.handle(event))
+                .collect()
+        };
+        assert_eq!(dispatch(&Event::Click(1, 2)), ["click #1", "click #1"]);
+        let key = String::from("a");
+        assert!(dispatch(&Event::Key(&key)).is_empty());
+        assert_eq!(dispatch(&Event::Click(3, 4)), ["click #2", "click #2"]);
+
+        // The handlers can also be called through thin references
+        let mut thin: ThinRefMut<dyn Handler> = ThinBox::as_thin_ref_mut(&mut handlers[0]);
+        assert_eq!(
+            thin.handle(&Event::Click(0, 0)).as_deref(),
+            Some("click #3")
+        );
+        let logger = ThinRef::downcast_ref::<Logger>(ThinBox::as_thin_ref(&handlers[1]));
+        assert_eq!(logger.unwrap().log, ["1,2", "a", "3,4"]);
+    }
+
+    #[test]
+    fn vptr_cast_trait() {
+        #[vptr(MyTrait)]
+        #[derive(Default)]
+        struct Relaxed {
+            q: u32,
+        }
+        impl MyTrait for Relaxed {
+            fn myfn(&self) -> u32 {
+                self.q
+            }
+        }
+        // The vtable of `dyn MyTrait + Send` is the same as the one of `dyn MyTrait`
+        unsafe impl HasVPtr<dyn MyTrait + Send> for Relaxed {
+            fn init() -> &'static crate::VTableData {
+                <Self as HasVPtr<dyn MyTrait>>::init()
+            }
+            fn get_vptr(&self) -> &VPtr<Self, dyn MyTrait + Send> {
+                unsafe { &*(&self.vptr_MyTrait as *const VPtr<Self, dyn MyTrait> as *const _) }
+            }
+            fn get_vptr_mut(&mut self) -> &mut VPtr<Self, dyn MyTrait + Send> {
+                unsafe { &mut *(&mut self.vptr_MyTrait as *mut VPtr<Self, dyn MyTrait> as *mut _) }
+            }
+        }
+
+        let send = VPtr::<Relaxed, dyn MyTrait + Send>::new();
+        let relaxed: VPtr<Relaxed, dyn MyTrait> = unsafe { send.cast_trait() };
+        assert!(relaxed == VPtr::new());
+        let r = Relaxed {
+            q: 12,
+            vptr_MyTrait: relaxed,
+        };
+        assert_eq!(ThinRef::<dyn MyTrait>::from(&r).myfn(), 12);
+        assert_eq!(ThinRef::<dyn MyTrait + Send>::from(&r).myfn(), 12);
+    }
+
+    #[test]
+    fn downcast_ref_any() {
+        #[vptr(MyTrait, register)]
+        #[derive(Default)]
+        struct Any1 {
+            q: u32,
+        }
+        impl MyTrait for Any1 {
+            fn myfn(&self) -> u32 {
+                self.q
+            }
+        }
+        #[vptr(SomeOtherTrait, MyTrait, register)]
+        #[derive(Default)]
+        struct Any2 {
+            s: &'static str,
+        }
+        impl MyTrait for Any2 {
+            fn myfn(&self) -> u32 {
+                self.s.len() as u32
+            }
+        }
+        impl SomeOtherTrait for Any2 {}
+
+        let a1 = Any1 {
+            q: 7,
+            ..Default::default()
+        };
+        let a2 = Any2 {
+            s: "hello",
+            ..Default::default()
+        };
+        let f2 = Foobar2::default();
+        let t1: ThinRef<dyn MyTrait> = a1.as_thin_ref();
+        let t2: ThinRef<dyn MyTrait> = a2.as_thin_ref();
+        assert_eq!(
+            ThinRef::concrete_type_id(t1),
+            Some(core::any::TypeId::of::<Any1>())
+        );
+        assert_eq!(ThinRef::downcast_ref_any::<Any1>(t1).map(|a| a.q), Some(7));
+        assert!(ThinRef::downcast_ref_any::<Any2>(t1).is_none());
+        assert_eq!(
+            ThinRef::downcast_ref_any::<Any2>(t2).map(|a| a.s),
+            Some("hello")
+        );
+        assert!(ThinRef::downcast_ref_any::<Any1>(t2).is_none());
+        assert!(ThinRef::downcast_ref_any::<Foobar2>(f2.as_thin_ref()).is_none());
+    }
+
+    #[test]
+    fn derives_ignore_vptr() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+        #[vptr(MyTrait, SomeOtherTrait)]
+        #[derive(Default, Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+        struct Derived {
+            q: u32,
+        }
+        impl MyTrait for Derived {
+            fn myfn(&self) -> u32 {
+                self.q
+            }
+        }
+        impl SomeOtherTrait for Derived {}
+        #[derive(Hash)]
+        struct Plain {
+            q: u32,
+        }
+        fn hash(x: &impl Hash) -> u64 {
+            let mut h = DefaultHasher::new();
+            x.hash(&mut h);
+            h.finish()
+        }
+
+        let a = Derived {
+            q: 1,
+            ..Default::default()
+        };
+        let b = Derived {
+            q: 2,
+            ..Default::default()
+        };
+        assert_eq!(a, a.clone());
+        assert_ne!(a, b);
+        assert!(a < b);
+        assert_eq!(a.cmp(&b), core::cmp::Ordering::Less);
+        assert_eq!(hash(&a), hash(&Plain { q: 1 }));
+    }
+
+    #[test]
+    fn returned_references_live_for_a() {
+        trait Named {
+            fn name(&self) -> &str;
+        }
+        #[vptr(Named)]
+        struct Person {
+            name: String,
+        }
+        impl Named for Person {
+            fn name(&self) -> &str {
+                &self.name
+            }
+        }
+        // Through Deref, the result would only live as long as the local `thin`
+        fn name_of<'a>(thin: ThinRef<'a, dyn Named>) -> &'a str {
+            ThinRef::get(thin).name()
+        }
+
+        let p = Person {
+            name: "Alice".into(),
+            vptr_Named: VPtr::new(),
+        };
+        let names: Vec<&str> = (0..2).map(|_| name_of(p.as_thin_ref())).collect();
+        assert_eq!(names, ["Alice", "Alice"]);
+    }
+
+    #[test]
+    fn builder() {
+        #[vptr(MyTrait, SomeOtherTrait, builder)]
+        #[derive(Debug)]
+        struct Built<'a> {
+            q: u32,
+            name: &'a str,
+            #[cfg(test)]
+            extra: u32,
+            #[cfg(not(test))]
+            missing: u32,
+        }
+        impl MyTrait for Built<'_> {
+            fn myfn(&self) -> u32 {
+                self.q + self.extra + self.name.len() as u32
+            }
+        }
+        impl SomeOtherTrait for Built<'_> {}
+
+        let name = String::from("abc");
+        let b = Built::builder().q(1).name(&name).extra(10).build().unwrap();
+        let thin: ThinRef<dyn MyTrait> = b.as_thin_ref();
+        assert_eq!(thin.myfn(), 14);
+        assert!(core::ptr::eq(
+            ThinRef::vtable_data(thin),
+            <Built as HasVPtr<dyn MyTrait>>::init()
+        ));
+
+        let err = Built::builder().q(1).extra(2).build().unwrap_err();
+        assert_eq!(err, crate::UninitializedFieldError::new("name"));
+        assert_eq!(err.to_string(), "field `name` was not set");
+        let err = BuiltBuilder::default().build().unwrap_err();
+        assert_eq!(err.field_name(), "q");
+    }
+
+    #[test]
+    fn impl_into_argument() {
+        fn call<'a>(h: impl Into<ThinRef<'a, dyn MyTrait>>) -> u32 {
+            h.into().myfn()
+        }
+        fn keep<'a>(v: &mut Vec<ThinRef<'a, dyn MyTrait>>, h: impl Into<ThinRef<'a, dyn MyTrait>>) {
+            v.push(h.into())
+        }
+        fn call_mut<'a>(h: impl Into<ThinRefMut<'a, dyn MyTrait>>) -> u32 {
+            h.into().myfn()
+        }
+
+        let mut f = Foobar2 {
+            q: 1,
+            vptr_MyTrait: VPtr::new(),
+        };
+        let t = Tuple(1, 2, VPtr::new());
+        assert_eq!(call(&f), 5);
+        assert_eq!(call(&t), 2);
+        assert_eq!(call(t.as_thin_ref()), 2);
+        assert_eq!(call_mut(&mut f), 5);
+
+        let mut v = Vec::new();
+        keep(&mut v, &f);
+        keep(&mut v, &t);
+        assert_eq!(v.iter().map(|x| x.myfn()).sum::<u32>(), 7);
+    }
+
+    #[test]
+    fn cfg_fields() {
+        // Depending on the configuration, the fields before the VPtr fields are different
+        #[vptr(MyTrait, SomeOtherTrait)]
+        #[derive(Default)]
+        struct Gated {
+            #[cfg(test)]
+            before: u64,
+            #[cfg(not(test))]
+            before: u8,
+            a: u8,
+            #[cfg(test)]
+            extra: [u32; 3],
+            #[cfg(not(test))]
+            missing: u16,
+            // Never present
+            #[cfg(any())]
+            removed: [u64; 4],
+            b: u16,
+        }
+        impl MyTrait for Gated {
+            #[cfg(test)]
+            fn myfn(&self) -> u32 {
+                self.before as u32 + self.a as u32 + self.extra.iter().sum::<u32>() + self.b as u32
+            }
+            #[cfg(not(test))]
+            fn myfn(&self) -> u32 {
+                self.a as u32 + self.b as u32
+            }
+        }
+        impl SomeOtherTrait for Gated {}
+
+        let g = Gated {
+            before: 1,
+            a: 2,
+            extra: [3, 4, 5],
+            b: 6,
+            ..Default::default()
+        };
+        let thin: ThinRef<dyn MyTrait> = g.as_thin_ref();
+        assert_eq!(thin.myfn(), 21);
+        assert!(core::ptr::eq(
+            ThinRef::downcast_ref::<Gated>(thin).unwrap(),
+            &g
+        ));
+        let offset = <Gated as HasVPtr<dyn MyTrait>>::init().offset;
+        assert_eq!(offset, crate::field_offset!(Gated, vptr_MyTrait));
+        assert_eq!(
+            ThinRef::as_usize(thin),
+            &g as *const Gated as usize + offset as usize
+        );
+        let other: ThinRef<dyn SomeOtherTrait> = g.as_thin_ref();
+        assert!(ThinRef::downcast_ref::<Gated>(other).is_some());
+    }
+
+    #[test]
+    fn thin_fields() {
+        #[crate::thin_fields]
+        #[derive(Clone, Copy)]
+        struct Pair<'x, T: Copy> {
+            #[thin]
+            first: dyn MyTrait,
+            #[thin]
+            second: dyn SomeOtherTrait,
+            extra: &'x T,
+        }
+        #[crate::thin_fields]
+        struct Tuple2(#[thin] dyn MyTrait, u32);
+
+        let f = Foobar3 {
+            q: 1,
+            ..Default::default()
+        };
+        let g = Foobar2 {
+            q: 3,
+            vptr_MyTrait: VPtr::new(),
+        };
+        let p = Pair {
+            first: g.as_thin_ref(),
+            second: f.as_thin_ref(),
+            extra: &4u8,
+        };
+        let p2 = p;
+        let first: ThinRef<'_, dyn MyTrait> = p2.first;
+        assert_eq!(first.myfn() + *p.extra as u32, 11);
+        let t = Tuple2(f.as_thin_ref(), 2);
+        assert_eq!(t.0.myfn() * t.1, 10);
+    }
+
+    #[test]
+    fn deref_option() {
+        use core::ops::Deref;
+        #[vptr(MyTrait, deref)]
+        struct Derefed<'a> {
+            q: &'a u32,
+        }
+        impl MyTrait for Derefed<'_> {
+            fn myfn(&self) -> u32 {
+                *self.q
+            }
+        }
+        fn generic<D: Deref<Target = dyn MyTrait>>(d: &D) -> u32 {
+            d.myfn()
+        }
+        static Q: u32 = 12;
+        let d = Derefed {
+            q: &Q,
+            vptr_MyTrait: VPtr::new(),
+        };
+        assert_eq!(generic(&d), 12);
+        let target: &dyn MyTrait = d.deref();
+        assert_eq!(
+            target as *const dyn MyTrait as *const u8,
+            &d as *const Derefed as *const u8
+        );
+        assert_eq!(d.myfn(), 12);
+    }
+
+    #[test]
+    fn value_eq() {
+        #[vptr(MyTrait, value_eq)]
+        #[derive(Default, PartialEq)]
+        struct Compared {
+            q: u32,
+        }
+        impl MyTrait for Compared {
+            fn myfn(&self) -> u32 {
+                self.q
+            }
+        }
+        let c1 = Compared {
+            q: 1,
+            ..Default::default()
+        };
+        let c2 = Compared {
+            q: 2,
+            ..Default::default()
+        };
+        let c3 = Compared {
+            q: 1,
+            ..Default::default()
+        };
+        let f2 = Foobar2 {
+            q: 1,
+            ..Default::default()
+        };
+        fn thin(c: &Compared) -> ThinRef<'_, dyn MyTrait> {
+            c.as_thin_ref()
+        }
+        assert_eq!(Compared::thin_refs_equal(thin(&c1), thin(&c3)), Some(true));
+        assert_eq!(Compared::thin_refs_equal(thin(&c1), thin(&c2)), Some(false));
+        assert_eq!(Compared::thin_refs_equal(thin(&c1), f2.as_thin_ref()), None);
+        assert_eq!(Compared::thin_refs_equal(f2.as_thin_ref(), thin(&c1)), None);
+    }
+
+    #[test]
+    fn cross_cast() {
+        use crate::ThinBox;
+        let f = Box::new(Foobar3 {
+            q: 5,
+            ..Default::default()
+        });
+        let b = ThinBox::<dyn SomeOtherTrait>::from_box(f);
+        let b = ThinBox::cross_cast::<WithCfgTrait, dyn MyTrait>(b)
+            .err()
+            .unwrap();
+        let b = ThinBox::cross_cast::<Foobar3, dyn MyTrait>(b).ok().unwrap();
+        assert_eq!(b.myfn(), 9);
+        let b = ThinBox::cross_cast::<Foobar3, dyn SomeOtherTrait>(b)
+            .ok()
+            .unwrap();
+        let b = ThinBox::cross_cast::<Foobar3, dyn MyTrait>(b).ok().unwrap();
+        assert_eq!(b.myfn(), 9);
+    }
+
+    #[test]
+    fn clone_thin_box() {
+        trait Shape: crate::DynClone {
+            fn area(&self) -> f32;
+            fn scale(&mut self, f: f32);
+        }
+        #[vptr(MyTrait, Shape)]
+        #[derive(Default, Clone)]
+        struct Rectangle {
+            w: f32,
+            h: f32,
+            name: String,
+        }
+        impl MyTrait for Rectangle {
+            fn myfn(&self) -> u32 {
+                self.name.len() as u32
+            }
+        }
+        impl Shape for Rectangle {
+            fn area(&self) -> f32 {
+                self.w * self.h
+            }
+            fn scale(&mut self, f: f32) {
+                self.w *= f;
+                self.h *= f;
+            }
+        }
+
+        let thin = crate::ThinBox::<dyn Shape>::from_box(Box::new(Rectangle {
+            w: 2.,
+            h: 3.,
+            name: "Rectangle".into(),
+            ..Default::default()
+        }));
+        let mut thin2 = thin.clone();
+        assert_eq!(thin2.area(), 6.);
+        thin2.scale(2.);
+        assert_eq!(thin.area(), 6.);
+        assert_eq!(thin2.area(), 24.);
+        drop(thin);
+        assert_eq!(thin2.area(), 24.);
+
+        let mut r = Rectangle {
+            w: 1.,
+            h: 5.,
+            ..Default::default()
+        };
+        let arc: std::sync::Arc<dyn Shape> = ThinRef::to_arc(r.as_thin_ref());
+        let rc: std::rc::Rc<dyn Shape> = ThinRef::to_rc(r.as_thin_ref());
+        r.scale(3.);
+        assert_eq!(arc.area(), 5.);
+        assert_eq!(rc.area(), 5.);
+        assert_eq!(r.area(), 45.);
+    }
+
+    /// Documented struct
+    #[vptr(MyTrait)]
+    #[non_exhaustive]
+    #[derive(Default)]
+    pub struct NonExhaustive {
+        /// Documented field
+        pub q: u32,
+    }
+
+    impl MyTrait for NonExhaustive {
+        fn myfn(&self) -> u32 {
+            self.q
+        }
+    }
+
+    #[test]
+    fn non_exhaustive() {
+        let f = NonExhaustive {
+            q: 12,
+            vptr_MyTrait: VPtr::new(),
+        };
+        let xx: ThinRef<dyn MyTrait> = f.as_thin_ref();
+        assert_eq!(xx.myfn(), 12);
+    }
+
+    #[vptr(MyTrait)]
+    struct FieldAttributes {
+        #[cfg(test)]
+        a: u32,
+        #[cfg(not(test))]
+        b: [u8; 1000],
+        #[allow(dead_code)]
+        #[rustfmt::skip]
+        c: u32,
+    }
+
+    impl MyTrait for FieldAttributes {
+        fn myfn(&self) -> u32 {
+            self.a
+        }
+    }
+
+    #[test]
+    fn field_attributes() {
+        let f = FieldAttributes {
+            a: 5,
+            c: 6,
+            vptr_MyTrait: VPtr::new(),
+        };
+        assert_eq!(
+            core::mem::size_of::<FieldAttributes>(),
+            core::mem::size_of::<VPtr<FieldAttributes, dyn MyTrait>>() + 8
+        );
+        let xx: ThinRef<dyn MyTrait> = f.as_thin_ref();
+        assert_eq!(xx.myfn(), 5);
+    }
+
+    #[test]
+    fn try_deref() {
+        let f = Tuple(1, 2, VPtr::new());
+        let xx = f.as_thin_ref();
+        assert_eq!(unsafe { ThinRef::try_deref(xx) }.map(|x| x.myfn()), Some(2));
+        assert!(!unsafe { ThinRef::vtable_is_null(xx) });
+
+        let null_slot: *const crate::VTableData = core::ptr::null();
+        let xx = unsafe { ThinRef::<dyn MyTrait>::from_raw(&null_slot as *const _ as *const ()) };
+        assert!(unsafe { ThinRef::vtable_is_null(xx) });
+        assert!(unsafe { ThinRef::try_deref(xx) }.is_none());
+
+        static NULL_VTABLE: crate::VTableData = crate::VTableData {
+            offset: 0,
+            vtable: core::ptr::null(),
+        };
+        let slot: *const crate::VTableData = &NULL_VTABLE;
+        let xx = unsafe { ThinRef::<dyn MyTrait>::from_raw(&slot as *const _ as *const ()) };
+        assert!(unsafe { ThinRef::vtable_is_null(xx) });
+        assert!(unsafe { ThinRef::try_deref(xx) }.is_none());
+    }
+
+    #[test]
+    fn as_ref() {
+        fn call_myfn<T: AsRef<dyn MyTrait>>(t: &T) -> u32 {
+            t.as_ref().myfn()
+        }
+        let f = Foobar3 {
+            q: 1,
+            ..Default::default()
+        };
+        assert_eq!(call_myfn(&f), 5);
+        let _: &dyn SomeOtherTrait = f.as_ref();
+
+        static X: u32 = 88;
+        let f = WithLifeTime {
+            foo: Some(&X),
+            vptr_MyTrait: VPtr::new(),
+        };
+        assert_eq!(call_myfn(&f), 88);
+    }
+
+    #[test]
+    fn nested_dyn() {
+        use core::any::Any;
+        trait Handler<T> {
+            fn handle(&self, t: T) -> u32;
+        }
+        // The `dyn` within the generic arguments must be kept
+        trait Sink<T> {
+            fn sink(&self, t: T) -> u32;
+        }
+        #[vptr(dyn Handler<Box<dyn Any>>, cfg(test, Sink<&'static dyn Any>))]
+        struct AnyHandler {
+            value: u32,
+        }
+        impl Handler<Box<dyn Any>> for AnyHandler {
+            fn handle(&self, t: Box<dyn Any>) -> u32 {
+                self.value + *t.downcast::<u32>().unwrap()
+            }
+        }
+        impl Sink<&'static dyn Any> for AnyHandler {
+            fn sink(&self, t: &'static dyn Any) -> u32 {
+                self.value * t.downcast_ref::<u32>().unwrap()
+            }
+        }
+        let h = AnyHandler {
+            value: 2,
+            vptr_Handler: VPtr::new(),
+            vptr_Sink: VPtr::new(),
+        };
+        let thin: ThinRef<dyn Handler<Box<dyn Any>>> = h.as_thin_ref();
+        assert_eq!(thin.handle(Box::new(3u32)), 5);
+        let thin: ThinRef<dyn Sink<&'static dyn Any>> = h.as_thin_ref();
+        assert_eq!(thin.sink(&4u32), 8);
+    }
+
+    #[vptr(dyn MyTrait, "dyn SomeOtherTrait + Send")]
+    #[derive(Default)]
+    struct WithDyn {
+        value: u32,
+    }
+    impl MyTrait for WithDyn {
+        fn myfn(&self) -> u32 {
+            self.value
+        }
+    }
+    impl SomeOtherTrait for WithDyn {}
+
+    #[test]
+    fn with_dyn() {
+        let f = WithDyn {
+            value: 12,
+            ..Default::default()
+        };
+        let xx: ThinRef<dyn MyTrait> = f.as_thin_ref();
+        assert_eq!(xx.myfn(), 12);
+        let yy: ThinRef<dyn SomeOtherTrait + Send> = f.as_thin_ref();
+        let _: &(dyn SomeOtherTrait + Send) = &*yy;
+        fn assert_send<T: Send>(_: &T) {}
+        assert_send(&f);
+    }
+
+    #[test]
+    fn offset_matches_offset_of() {
+        use core::mem::offset_of;
+        // The macro also checks this at compile time
+        let offset = |data: &crate::VTableData| data.offset as usize;
+        assert_eq!(
+            offset(<Foobar3 as HasVPtr<dyn MyTrait>>::init()),
+            offset_of!(Foobar3, vptr_MyTrait)
+        );
+        assert_eq!(
+            offset(<Foobar3 as HasVPtr<dyn SomeOtherTrait>>::init()),
+            offset_of!(Foobar3, vptr_SomeOtherTrait)
+        );
+        assert_eq!(
+            offset(<Tuple as HasVPtr<dyn MyTrait>>::init()),
+            offset_of!(Tuple, 2)
+        );
+        assert_eq!(
+            crate::field_offset!(Foobar3, vptr_SomeOtherTrait) as usize,
+            offset(<Foobar3 as HasVPtr<dyn SomeOtherTrait>>::init())
+        );
+    }
+
+    #[test]
+    fn vtable_data() {
+        let f = Foobar3::default();
+        let a = ThinRef::vtable_data(ThinRef::<dyn MyTrait>::from(&f));
+        let b = ThinRef::vtable_data(ThinRef::<dyn SomeOtherTrait>::from(&f));
+        assert!(core::ptr::eq(a, <Foobar3 as HasVPtr<dyn MyTrait>>::init()));
+        assert!(core::ptr::eq(
+            b,
+            <Foobar3 as HasVPtr<dyn SomeOtherTrait>>::init()
+        ));
+        assert_ne!(a.offset, b.offset);
+        assert!(a.offset >= 0 && (a.offset as usize) < core::mem::size_of::<Foobar3>());
+    }
+
+    #[test]
+    fn by_vtable() {
+        let f2: Vec<_> = (0..3)
+            .map(|q| Foobar2 {
+                q,
+                ..Default::default()
+            })
+            .collect();
+        let f3: Vec<_> = (0..3)
+            .map(|q| Foobar3 {
+                q,
+                ..Default::default()
+            })
+            .collect();
+        let mut v: Vec<ThinRef<dyn MyTrait>> = f2
+            .iter()
+            .zip(f3.iter())
+            .flat_map(|(a, b)| [a.as_thin_ref(), b.as_thin_ref()])
+            .collect();
+        v.sort_by_key(|x| ThinRef::by_vtable_key(*x));
+        let runs = v
+            .windows(2)
+            .filter(|w| ThinRef::by_vtable_key(w[0]) != ThinRef::by_vtable_key(w[1]))
+            .count();
+        assert_eq!(runs, 1);
+        let foobar2_first = ThinRef::downcast_ref::<Foobar2>(v[0]).is_some();
+        for x in &v[..3] {
+            assert_eq!(
+                ThinRef::downcast_ref::<Foobar2>(*x).is_some(),
+                foobar2_first
+            );
+        }
+        for x in &v[3..] {
+            assert_eq!(
+                ThinRef::downcast_ref::<Foobar2>(*x).is_some(),
+                !foobar2_first
+            );
+        }
+
+        let mut w: Vec<_> = v.iter().rev().map(|x| crate::ByVTable(*x)).collect();
+        w.sort();
+        assert!(w[0] == w[2] && w[2] != w[3] && w[3] == w[5]);
+    }
+
+    #[test]
+    fn swap() {
+        let mut a = Foobar3 {
+            q: 1,
+            ..Default::default()
+        };
+        let mut b = Foobar3 {
+            q: 2,
+            ..Default::default()
+        };
+        let mut c = Foobar2 {
+            q: 3,
+            ..Default::default()
+        };
+        let mut ta: ThinRefMut<dyn MyTrait> = a.as_thin_ref_mut();
+        let mut tb: ThinRefMut<dyn MyTrait> = b.as_thin_ref_mut();
+        assert!(unsafe { ThinRefMut::swap(&mut ta, &mut tb) });
+        assert_eq!(ta.myfn(), 6);
+        assert_eq!(tb.myfn(), 5);
+        assert!(!unsafe { ThinRefMut::swap(&mut ta, &mut c.as_thin_ref_mut()) });
+        assert_eq!(ta.myfn(), 6);
+        assert_eq!((a.q, b.q, c.q), (2, 1, 3));
+    }
+
+    #[test]
+    fn size_of_val() {
+        use core::mem::{align_of, size_of};
+        let f = Foobar3::default();
+        let xx: ThinRef<dyn MyTrait> = f.as_thin_ref();
+        assert_eq!(ThinRef::size_of_val(xx), size_of::<Foobar3>());
+        assert_eq!(ThinRef::align_of_val(xx), align_of::<Foobar3>());
+        let t = Tuple(1, 2, VPtr::new());
+        let xx: ThinRef<dyn MyTrait> = t.as_thin_ref();
+        assert_eq!(ThinRef::size_of_val(xx), size_of::<Tuple>());
+        assert_eq!(ThinRef::align_of_val(xx), align_of::<Tuple>());
+    }
+
+    #[test]
+    fn as_bytes() {
+        use core::mem::size_of;
+        #[vptr(MyTrait)]
+        #[repr(C)]
+        struct Plain {
+            a: usize,
+            b: [u8; size_of::<usize>()],
+        }
+        impl MyTrait for Plain {
+            fn myfn(&self) -> u32 {
+                self.a as u32
+            }
+        }
+        let p = Plain {
+            a: 0x0102,
+            b: [7; size_of::<usize>()],
+            vptr_MyTrait: VPtr::new(),
+        };
+        let bytes = unsafe { ThinRef::<dyn MyTrait>::as_bytes(p.as_thin_ref()) };
+        assert_eq!(bytes.len(), size_of::<Plain>());
+        assert_eq!(bytes.as_ptr(), &p as *const Plain as *const u8);
+        let (a, rest) = bytes.split_at(size_of::<usize>());
+        let (b, vptr) = rest.split_at(size_of::<usize>());
+        assert_eq!(a, 0x0102usize.to_ne_bytes());
+        assert_eq!(b, p.b);
+        assert_eq!(vptr.len(), size_of::<VPtr<Plain, dyn MyTrait>>());
+    }
+
+    #[test]
+    fn thin_table() {
+        #[derive(Clone, Copy, PartialEq, Debug)]
+        #[repr(usize)]
+        enum Light {
+            Red,
+            Green,
+            Orange,
+        }
+        trait State {
+            fn next(&self) -> Light;
+            fn duration(&self) -> u32;
+        }
+        #[vptr(State)]
+        struct Timed(Light, u32);
+        impl State for Timed {
+            fn next(&self) -> Light {
+                self.0
+            }
+            fn duration(&self) -> u32 {
+                self.1
+            }
+        }
+
+        let red = Timed(Light::Green, 30, VPtr::new());
+        let green = Timed(Light::Orange, 25, VPtr::new());
+        let orange = Timed(Light::Red, 5, VPtr::new());
+        // The order of the entries does not matter
+        let table: [ThinRef<dyn State>; 3] = thin_table! {
+            Light::Orange => &orange,
+            Light::Red => &red,
+            Light::Green => &green,
+        };
+        let mut state = Light::Red;
+        let mut total = 0;
+        for _ in 0..4 {
+            total += table[state as usize].duration();
+            state = table[state as usize].next();
+        }
+        assert_eq!(state, Light::Green);
+        assert_eq!(total, 90);
+    }
+
+    #[test]
+    fn thin_box_send() {
+        use crate::ThinBox;
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::sync::Arc;
+
+        #[vptr("dyn MyTrait + Send")]
+        struct Counted(Arc<AtomicU32>);
+        impl MyTrait for Counted {
+            fn myfn(&self) -> u32 {
+                self.0.load(Ordering::SeqCst)
+            }
+        }
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Counted>();
+
+        let drops = Arc::new(AtomicU32::new(40));
+        let b =
+            ThinBox::<dyn MyTrait + Send>::from_box(Box::new(Counted(drops.clone(), VPtr::new())));
+        let r = std::thread::spawn(move || b.myfn()).join().unwrap();
+        assert_eq!(r, 40);
+        assert_eq!(drops.load(Ordering::SeqCst), 41);
+    }
+
+    trait Ref<'a> {
+        fn get(&self) -> &'a str;
+    }
+    #[vptr("Ref<'a>")]
+    struct WithLifetimeTrait<'a> {
+        s: &'a str,
+    }
+    impl<'a> Ref<'a> for WithLifetimeTrait<'a> {
+        fn get(&self) -> &'a str {
+            self.s
+        }
+    }
+
+    #[test]
+    fn lifetime_trait() {
+        fn get<'a>(x: ThinRef<'_, dyn Ref<'a> + 'a>) -> &'a str {
+            x.get()
+        }
+        let string = String::from("hello");
+        let result;
+        {
+            let f = WithLifetimeTrait {
+                s: &string,
+                vptr_Ref: VPtr::new(),
+            };
+            result = get(f.as_thin_ref());
+        }
+        assert_eq!(result, "hello");
+    }
+
+    #[test]
+    fn default_methods() {
+        trait WithDefault {
+            fn value(&self) -> u32;
+            fn double(&self) -> u32 {
+                self.value() * 2
+            }
+            fn name(&self) -> &'static str {
+                "default"
+            }
+        }
+        #[vptr(WithDefault)]
+        struct UsesDefault(u32);
+        impl WithDefault for UsesDefault {
+            fn value(&self) -> u32 {
+                self.0
+            }
+        }
+        #[vptr(WithDefault)]
+        struct Overrides(u32);
+        impl WithDefault for Overrides {
+            fn value(&self) -> u32 {
+                self.0
+            }
+            fn double(&self) -> u32 {
+                self.0 + 1000
+            }
+            fn name(&self) -> &'static str {
+                "overrides"
+            }
+        }
+
+        let a = UsesDefault(4, VPtr::new());
+        let b = Overrides(5, VPtr::new());
+        let refs: [ThinRef<dyn WithDefault>; 2] = [a.as_thin_ref(), b.as_thin_ref()];
+        assert_eq!(refs.map(|x| x.double()), [8, 1005]);
+        assert_eq!(refs.map(|x| x.name()), ["default", "overrides"]);
+        let mut a = a;
+        let xx: ThinRefMut<dyn WithDefault> = a.as_thin_ref_mut();
+        assert_eq!(xx.double(), 8);
+    }
+
+    #[test]
+    fn supertrait() {
+        trait Sub: MyTrait {
+            fn sub(&self) -> u32 {
+                self.myfn() + 100
+            }
+        }
+        #[vptr(Sub)]
+        struct S(u32);
+        impl MyTrait for S {
+            fn myfn(&self) -> u32 {
+                self.0
+            }
+        }
+        impl Sub for S {}
+
+        fn call_super(x: &dyn MyTrait) -> u32 {
+            x.myfn()
+        }
+
+        let s = S(3, VPtr::new());
+        let xx: ThinRef<dyn Sub> = s.as_thin_ref();
+        assert_eq!(xx.myfn(), 3);
+        assert_eq!(xx.sub(), 103);
+        assert_eq!(call_super(&*xx), 3);
+        let upcast: &dyn MyTrait = &*xx;
+        assert_eq!(upcast.myfn(), 3);
+
+        let mut s = s;
+        let mut yy: ThinRefMut<dyn Sub> = s.as_thin_ref_mut();
+        let upcast: &mut dyn MyTrait = &mut *yy;
+        assert_eq!(upcast.myfn(), 3);
+    }
+
+    #[test]
+    fn upcast() {
+        trait Sub: MyTrait + SomeOtherTrait + crate::ThinUpcast<dyn MyTrait> {}
+        #[vptr(SomeOtherTrait, Sub, MyTrait)]
+        #[derive(Default)]
+        struct S {
+            q: u32,
+        }
+        impl MyTrait for S {
+            fn myfn(&self) -> u32 {
+                self.q
+            }
+        }
+        impl SomeOtherTrait for S {}
+        impl Sub for S {}
+
+        let s = S {
+            q: 8,
+            ..Default::default()
+        };
+        let xx: ThinRef<dyn Sub> = s.as_thin_ref();
+        let yy: ThinRef<dyn MyTrait> = ThinRef::upcast(xx);
+        assert_eq!(yy.myfn(), 8);
+        assert_eq!(
+            ThinRef::into_raw(yy),
+            s.get_vptr() as *const VPtr<S, dyn MyTrait> as *const ()
+        );
+        assert_eq!(ThinRef::downcast_ref::<S>(yy).map(|s| s.q), Some(8));
+    }
+
+    #[test]
+    fn interleaved_calls() {
+        let mut f = WithCAbi {
+            value: 2,
+            ..Default::default()
+        };
+        let mut xx: ThinRefMut<dyn Callback> = f.as_thin_ref_mut();
+        assert_eq!(xx.call(10, 1), 21);
+        xx.set(3);
+        assert_eq!(xx.call(10, 1), 31);
+        let before = xx.call(1, 0);
+        xx.set(before + 1);
+        assert_eq!(xx.call(1, 0), 4);
+        fn through_ref(x: &dyn Callback) -> i32 {
+            x.call(2, 0)
+        }
+        fn through_mut(x: &mut dyn Callback) {
+            x.set(7)
+        }
+        assert_eq!(through_ref(&*xx), 8);
+        through_mut(&mut *xx);
+        assert_eq!(through_ref(&*xx), 14);
+        assert_eq!(f.value, 7);
+    }
+
+    #[test]
+    fn short_names() {
+        let mut f = Foobar2 {
+            q: 1,
+            ..Default::default()
+        };
+        assert_eq!(f.thin().myfn(), 5);
+        f.thin_mut().myfn();
+        let b: crate::ThinBox<dyn MyTrait> = Box::new(f).into_thin_box();
+        assert_eq!(b.myfn(), 5);
+    }
+
+    #[test]
+    fn identity_and_by_value() {
+        use crate::{ByValue, Identity};
+        use std::collections::{BTreeMap, HashMap};
+
+        trait Named {
+            fn name(&self) -> &str;
+        }
+        impl PartialEq for dyn Named + '_ {
+            fn eq(&self, other: &Self) -> bool {
+                self.name() == other.name()
+            }
+        }
+        impl Eq for dyn Named + '_ {}
+        impl PartialOrd for dyn Named + '_ {
+            fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+        impl Ord for dyn Named + '_ {
+            fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+                self.name().cmp(other.name())
+            }
+        }
+        impl core::hash::Hash for dyn Named + '_ {
+            fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+                self.name().hash(state)
+            }
+        }
+        #[vptr(Named)]
+        struct Person(&'static str);
+        impl Named for Person {
+            fn name(&self) -> &str {
+                self.0
+            }
+        }
+
+        let people = [
+            Person("Alice", VPtr::new()),
+            Person("Bob", VPtr::new()),
+            Person("Alice", VPtr::new()),
+        ];
+        let refs = people
+            .each_ref()
+            .map(|p| -> ThinRef<dyn Named> { p.as_thin_ref() });
+
+        let mut by_identity = HashMap::new();
+        let mut by_value = HashMap::new();
+        let mut identity_tree = BTreeMap::new();
+        let mut value_tree = BTreeMap::new();
+        for (i, r) in refs.iter().enumerate() {
+            by_identity.insert(Identity(*r), i);
+            by_value.insert(ByValue(*r), i);
+            identity_tree.insert(Identity(*r), i);
+            value_tree.insert(ByValue(*r), i);
+        }
+        assert_eq!(by_identity.len(), 3);
+        assert_eq!(identity_tree.len(), 3);
+        assert_eq!(by_value.len(), 2);
+        assert_eq!(value_tree.len(), 2);
+        assert_eq!(by_identity[&Identity(refs[0])], 0);
+        assert_eq!(by_value[&ByValue(refs[0])], 2);
+        assert_eq!(
+            value_tree.keys().map(|k| k.name()).collect::<Vec<_>>(),
+            ["Alice", "Bob"]
+        );
+    }
+
+    #[test]
+    fn forward_std_traits() {
+        use crate::ThinBox;
+        use core::fmt::Write;
+
+        #[vptr("Iterator<Item = u32>")]
+        struct Counter(u32);
+        impl Iterator for Counter {
+            type Item = u32;
+            fn next(&mut self) -> Option<u32> {
+                self.0 += 1;
+                Some(self.0)
+            }
+        }
+        fn sum_first<I: Iterator<Item = u32>>(it: I, n: usize) -> u32 {
+            it.take(n).sum()
+        }
+        let mut c = Counter(0, VPtr::new());
+        let mut thin: ThinRefMut<dyn Iterator<Item = u32>> = c.as_thin_ref_mut();
+        assert_eq!(sum_first(thin.reborrow(), 3), 6);
+        assert_eq!(thin.next(), Some(4));
+        assert_eq!(c.0, 4);
+        let b = ThinBox::<dyn Iterator<Item = u32>>::from_box(Box::new(Counter(10, VPtr::new())));
+        assert_eq!(b.take(2).collect::<Vec<_>>(), [11, 12]);
+
+        #[vptr(Write)]
+        #[derive(Default)]
+        struct Buffer(String);
+        impl Write for Buffer {
+            fn write_str(&mut self, s: &str) -> core::fmt::Result {
+                self.0.write_str(s)
+            }
+        }
+        let mut buf = Buffer::default();
+        let mut thin: ThinRefMut<dyn Write> = buf.as_thin_ref_mut();
+        write!(thin, "{}-{}", 1, 2).unwrap();
+        assert_eq!(buf.0, "1-2");
+
+        let t = TestDisplay {
+            str: "thin".to_string(),
+            vptr_Display: VPtr::new(),
+        };
+        let thin: ThinRef<dyn std::fmt::Display> = t.as_thin_ref();
+        assert_eq!(format!("{}", thin), t.to_string());
+    }
+
+    #[test]
+    fn sized_methods() {
+        trait WithSized {
+            fn value(&self) -> u32;
+            fn create(value: u32) -> Self
+            where
+                Self: Sized;
+            fn with<T: Into<u32>>(&self, t: T) -> u32
+            where
+                Self: Sized,
+            {
+                self.value() + t.into()
+            }
+        }
+        #[vptr(WithSized)]
+        struct S(u32);
+        impl WithSized for S {
+            fn value(&self) -> u32 {
+                self.0
+            }
+            fn create(value: u32) -> Self {
+                S(value, VPtr::new())
+            }
+        }
+
+        let s = S::create(5);
+        assert_eq!(s.with(1u8), 6);
+        let xx: ThinRef<dyn WithSized> = s.as_thin_ref();
+        assert_eq!(xx.value(), 5);
+        let fat: &dyn WithSized = &s;
+        assert_eq!(fat.value(), xx.value());
+        assert_eq!(ThinRef::downcast_ref::<S>(xx).unwrap().with(2u8), 7);
+    }
+
+    #[test]
+    fn get_lifetime() {
+        fn longest<'a>(
+            a: ThinRef<'a, dyn MyTrait>,
+            b: ThinRef<'a, dyn MyTrait>,
+        ) -> &'a dyn MyTrait {
+            let (a, b) = (ThinRef::get(a), ThinRef::get(b));
+            if a.myfn() > b.myfn() {
+                a
+            } else {
+                b
+            }
+        }
+        let f1 = Foobar2 {
+            q: 1,
+            ..Default::default()
+        };
+        let f2 = Foobar3 {
+            q: 2,
+            ..Default::default()
+        };
+        let r = longest(f1.as_thin_ref(), f2.as_thin_ref());
+        assert_eq!(r.myfn(), 6);
+        // The Borrow trait ties the lifetime to the ThinRef
+        let thin = f1.as_thin_ref();
+        let b: &dyn MyTrait = core::borrow::Borrow::borrow(&thin);
+        assert_eq!(b.myfn(), 5);
+    }
+
+    #[test]
+    fn repr_c_tuple() {
+        use core::mem::{align_of, offset_of, size_of};
+        #[repr(C)]
+        #[vptr(MyTrait, SomeOtherTrait)]
+        struct Slot(u32, u8);
+        impl MyTrait for Slot {
+            fn myfn(&self) -> u32 {
+                self.0 + self.1 as u32
+            }
+        }
+        impl SomeOtherTrait for Slot {}
+
+        // Same as `struct { uint32_t; uint8_t; void*; void*; }` in C
+        assert_eq!(offset_of!(Slot, 0), 0);
+        assert_eq!(offset_of!(Slot, 1), 4);
+        assert_eq!(offset_of!(Slot, 2), size_of::<usize>().max(8));
+        assert_eq!(
+            offset_of!(Slot, 3),
+            offset_of!(Slot, 2) + size_of::<usize>()
+        );
+        assert_eq!(size_of::<Slot>(), offset_of!(Slot, 3) + size_of::<usize>());
+        assert_eq!(align_of::<Slot>(), align_of::<usize>().max(4));
+
+        let slots = [
+            Slot(1, 2, VPtr::new(), VPtr::new()),
+            Slot(3, 4, VPtr::new(), VPtr::new()),
+        ];
+        for (i, s) in slots.iter().enumerate() {
+            let raw = ThinRef::<dyn MyTrait>::into_raw(s.as_thin_ref());
+            let expected = slots.as_ptr() as usize + i * size_of::<Slot>() + offset_of!(Slot, 2);
+            assert_eq!(raw as usize, expected);
+            assert_eq!(
+                <Slot as HasVPtr<dyn MyTrait>>::init().offset,
+                offset_of!(Slot, 2) as isize
+            );
+        }
+    }
+
+    #[test]
+    fn relax_auto_traits() {
+        #[vptr("dyn MyTrait + Send + Sync")]
+        struct S(u32);
+        impl MyTrait for S {
+            fn myfn(&self) -> u32 {
+                self.0
+            }
+        }
+        let s = S(3, VPtr::new());
+        let xx: ThinRef<dyn MyTrait + Send + Sync> = s.as_thin_ref();
+        let yy = ThinRef::relax_auto_traits::<dyn MyTrait + Send>(xx, |x| x).unwrap();
+        let zz = ThinRef::relax_auto_traits::<dyn MyTrait>(yy, |x| x).unwrap();
+        assert_eq!(zz.myfn(), 3);
+        assert_eq!(ThinRef::into_raw(zz), ThinRef::into_raw(xx));
+
+        // Not the same object
+        let other = S(4, VPtr::new());
+        let none = ThinRef::relax_auto_traits::<dyn MyTrait>(xx, |_| &other);
+        assert!(none.is_none());
+    }
+
+    #[test]
+    fn error_chain() {
+        use std::error::Error;
+        use std::fmt;
+
+        #[vptr(Error)]
+        #[derive(Debug)]
+        struct Inner(u32);
+        impl fmt::Display for Inner {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "inner {}", self.0)
+            }
+        }
+        impl Error for Inner {}
+
+        #[vptr(Error)]
+        #[derive(Debug)]
+        struct Outer(&'static str, Inner);
+        impl fmt::Display for Outer {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "outer {}", self.0)
+            }
+        }
+        impl Error for Outer {
+            fn source(&self) -> Option<&(dyn Error + 'static)> {
+                Some(&self.1)
+            }
+        }
+
+        fn to_thin<'a>(e: &'a (dyn Error + 'static)) -> Option<ThinRef<'a, dyn Error>> {
+            if let Some(e) = e.downcast_ref::<Outer>() {
+                Some(e.as_thin_ref())
+            } else {
+                e.downcast_ref::<Inner>().map(|e| e.as_thin_ref())
+            }
+        }
+
+        let e = Outer("ctx", Inner(42, VPtr::new()), VPtr::new());
+        let mut messages = Vec::new();
+        let mut current: Option<ThinRef<dyn Error>> = Some(e.as_thin_ref());
+        while let Some(thin) = current {
+            messages.push(thin.to_string());
+            current = ThinRef::get(thin).source().and_then(to_thin);
+        }
+        assert_eq!(messages, ["outer ctx", "inner 42"]);
+        let thin: ThinRef<dyn Error> = e.as_thin_ref();
+        assert!(format!("{:?}", thin).starts_with("Outer(\"ctx\""));
+    }
+
+    #[vptr(MyTrait, SomeOtherTrait, eq_by_value)]
+    #[derive(Default, PartialEq)]
+    struct EqByValue {
+        q: u32,
+    }
+    impl MyTrait for EqByValue {
+        fn myfn(&self) -> u32 {
+            self.q
+        }
+    }
+    impl SomeOtherTrait for EqByValue {}
+
+    #[test]
+    fn eq_by_value() {
+        let a = EqByValue {
+            q: 1,
+            ..Default::default()
+        };
+        let b = EqByValue {
+            q: 1,
+            ..Default::default()
+        };
+        let c = EqByValue {
+            q: 2,
+            ..Default::default()
+        };
+        let other = Foobar2 {
+            q: 1,
+            ..Default::default()
+        };
+        let (ta, tb, tc): (
+            ThinRef<dyn MyTrait>,
+            ThinRef<dyn MyTrait>,
+            ThinRef<dyn MyTrait>,
+        ) = (a.as_thin_ref(), b.as_thin_ref(), c.as_thin_ref());
+        assert!(EqByValue::thin_eq(ta, ta));
+        assert!(EqByValue::thin_eq(ta, tb));
+        assert!(!EqByValue::thin_eq(ta, tc));
+        assert!(!EqByValue::thin_eq(ta, other.as_thin_ref()));
+        assert!(!EqByValue::thin_eq(other.as_thin_ref(), ta));
+        let (sa, sb): (ThinRef<dyn SomeOtherTrait>, ThinRef<dyn SomeOtherTrait>) =
+            (a.as_thin_ref(), b.as_thin_ref());
+        assert!(EqByValue::thin_eq(sa, sb));
+    }
+
+    #[test]
+    fn thin_match() {
+        fn which(x: ThinRef<dyn MyTrait>) -> (&'static str, u32) {
+            thin_match!(x, {
+                Foobar2(f) => ("Foobar2", f.q),
+                Foobar3(f) => ("Foobar3", f.q),
+                self::Tuple(t) => ("Tuple", t.0),
+                _ => ("other", x.myfn()),
+            })
+        }
+        let f2 = Foobar2 {
+            q: 2,
+            ..Default::default()
+        };
+        let f3 = Foobar3 {
+            q: 3,
+            ..Default::default()
+        };
+        let t = Tuple(4, 5, VPtr::new());
+        let e = Empty1(VPtr::new());
+        assert_eq!(which(f2.as_thin_ref()), ("Foobar2", 2));
+        assert_eq!(which(f3.as_thin_ref()), ("Foobar3", 3));
+        assert_eq!(which(t.as_thin_ref()), ("Tuple", 4));
+        assert_eq!(which(e.as_thin_ref()), ("other", 88));
+    }
+
+    #[test]
+    fn init_vptrs_in_place() {
+        let mut f = core::mem::MaybeUninit::<Foobar3>::uninit();
+        let f = unsafe {
+            let p = f.as_mut_ptr();
+            core::ptr::addr_of_mut!((*p).q).write(7);
+            Foobar3::init_vptrs_in_place(p);
+            f.assume_init()
+        };
+        let xx: ThinRef<dyn MyTrait> = f.as_thin_ref();
+        assert_eq!(xx.myfn(), 11);
+
+        let mut t = core::mem::MaybeUninit::<Tuple>::uninit();
+        let t = unsafe {
+            let p = t.as_mut_ptr();
+            core::ptr::addr_of_mut!((*p).0).write(1);
+            core::ptr::addr_of_mut!((*p).1).write(2);
+            Tuple::init_vptrs_in_place(p);
+            t.assume_init()
+        };
+        assert_eq!(t.as_thin_ref().myfn(), 2);
+    }
+
+    #[vptr(MyTrait, cfg(test, SomeOtherTrait))]
+    #[derive(Default)]
+    struct WithCfgTrait {
+        q: u32,
+    }
+    impl MyTrait for WithCfgTrait {
+        fn myfn(&self) -> u32 {
+            self.q
+        }
+    }
+    impl SomeOtherTrait for WithCfgTrait {}
+
+    #[test]
+    fn own_init_vptrs_in_place() {
+        // Without the init_in_place option, the name is free for the struct
+        #[vptr(MyTrait)]
+        struct Own {
+            q: u32,
+        }
+        impl MyTrait for Own {
+            fn myfn(&self) -> u32 {
+                self.q
+            }
+        }
+        impl Own {
+            fn init_vptrs_in_place(q: u32) -> Self {
+                Own {
+                    q,
+                    vptr_MyTrait: VPtr::new(),
+                }
+            }
+        }
+        assert_eq!(Own::init_vptrs_in_place(4).as_thin_ref().myfn(), 4);
+    }
+
+    #[vptr(MyTrait, SomeOtherTrait, debug_guard, init_in_place)]
+    #[derive(Default, Clone, Debug, PartialEq)]
+    struct Guarded {
+        q: u32,
+    }
+    impl MyTrait for Guarded {
+        fn myfn(&self) -> u32 {
+            self.q
+        }
+    }
+    impl SomeOtherTrait for Guarded {}
+
+    #[test]
+    fn debug_guard() {
+        assert_eq!(core::mem::size_of::<crate::DebugGuard<Guarded>>(), 0);
+        let g = Guarded {
+            q: 3,
+            ..Default::default()
+        };
+        let c = g.clone();
+        assert_eq!(g, c);
+        drop(g);
+        assert_eq!(ThinRef::<dyn MyTrait>::from(&c).myfn(), 3);
+        let mut u = core::mem::MaybeUninit::<Guarded>::uninit();
+        let u = unsafe {
+            let p = u.as_mut_ptr();
+            core::ptr::addr_of_mut!((*p).q).write(4);
+            Guarded::init_vptrs_in_place(p);
+            u.assume_init()
+        };
+        assert_eq!(ThinRef::<dyn MyTrait>::from(&u).myfn(), 4);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    pub use crate::{vptr, HasVPtr, ThinBox, ThinRef, ThinRefMut, VPtr};
+    #[cfg(all(feature = "debug_guard", debug_assertions))]
+    #[test]
+    fn debug_guard_dropped() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        let b = Box::new(Guarded {
+            q: 3,
+            ..Default::default()
+        });
+        let raw = ThinRef::<dyn MyTrait>::into_raw(b.as_thin_ref());
+        let raw_other = ThinRef::<dyn SomeOtherTrait>::into_raw(b.as_thin_ref());
+        assert_eq!(unsafe { ThinRef::<dyn MyTrait>::from_raw(raw) }.myfn(), 3);
+        drop(b);
+        let dangling = unsafe { ThinRef::<dyn MyTrait>::from_raw(raw) };
+        assert!(catch_unwind(AssertUnwindSafe(|| dangling.myfn())).is_err());
+        let dangling = unsafe { ThinRef::<dyn SomeOtherTrait>::from_raw(raw_other) };
+        assert!(catch_unwind(AssertUnwindSafe(|| ThinRef::get(dangling))).is_err());
 
-    mod vptr {
-        // Because otherwise, the generated code cannot access the vptr crate.
-        pub use crate::*;
+        // A new object at the same address can be used
+        let mut slot = core::mem::ManuallyDrop::new(Guarded {
+            q: 5,
+            ..Default::default()
+        });
+        let raw = ThinRef::<dyn MyTrait>::into_raw(slot.as_thin_ref());
+        unsafe { core::mem::ManuallyDrop::drop(&mut slot) };
+        let dangling = unsafe { ThinRef::<dyn MyTrait>::from_raw(raw) };
+        assert!(catch_unwind(AssertUnwindSafe(|| dangling.myfn())).is_err());
+        unsafe {
+            core::ptr::write(
+                &mut *slot,
+                Guarded {
+                    q: 6,
+                    ..Default::default()
+                },
+            )
+        };
+        assert_eq!(ThinRef::<dyn MyTrait>::from(&*slot).myfn(), 6);
     }
 
-    trait MyTrait {
-        fn myfn(&self) -> u32;
+    #[test]
+    fn init_vptrs_in_zeroed_array() {
+        let mut arr = core::mem::MaybeUninit::<[Foobar3; 8]>::zeroed();
+        let mut arr = unsafe {
+            let p = arr.as_mut_ptr() as *mut Foobar3;
+            for i in 0..8 {
+                Foobar3::init_vptrs_in_place(p.add(i));
+            }
+            arr.assume_init()
+        };
+        for (i, f) in arr.iter_mut().enumerate() {
+            f.q = i as u32;
+        }
+        for (i, f) in arr.iter().enumerate() {
+            let xx: ThinRef<dyn MyTrait> = f.as_thin_ref();
+            assert_eq!(xx.myfn(), i as u32 + 4);
+        }
     }
 
+    #[repr(align(128))]
+    #[derive(Default)]
+    struct Align128(u32);
+
     #[vptr(MyTrait)]
+    #[repr(align(256))]
     #[derive(Default)]
-    struct Foobar2 {
-        q: u32,
+    struct OverAligned {
+        a: u8,
+        b: Align128,
     }
 
-    impl MyTrait for Foobar2 {
+    impl MyTrait for OverAligned {
         fn myfn(&self) -> u32 {
-            self.q + 4
+            assert_eq!(self as *const Self as usize % 256, 0);
+            assert_eq!(&self.b as *const Align128 as usize % 128, 0);
+            self.a as u32 + self.b.0
         }
     }
 
     #[test]
-    fn it_works2() {
-        let mut f = Foobar2::default();
-        f.q = 5;
-        assert_eq!(f.myfn(), 9);
-
-        let xx = f.as_thin_ref();
-        assert_eq!(xx.myfn(), 9);
+    fn over_aligned() {
+        let mut f = Box::new(OverAligned {
+            a: 1,
+            b: Align128(2),
+            ..Default::default()
+        });
+        {
+            let xx: ThinRef<dyn MyTrait> = f.as_thin_ref();
+            assert_eq!(&*xx as *const dyn MyTrait as *const u8 as usize % 256, 0);
+            assert_eq!(xx.myfn(), 3);
+        }
+        {
+            let mut xx: ThinRefMut<dyn MyTrait> = f.as_thin_ref_mut();
+            let r: &mut dyn MyTrait = &mut *xx;
+            assert_eq!(r as *mut dyn MyTrait as *mut u8 as usize % 256, 0);
+            assert_eq!(r.myfn(), 3);
+        }
+        let thin = crate::ThinBox::<dyn MyTrait>::from_box(f);
+        assert_eq!(thin.myfn(), 3);
     }
 
-    #[vptr(MyTrait, SomeOtherTrait)]
-    #[derive(Default, Debug)]
-    struct Foobar3 {
-        q: u32,
-    }
+    /// Tests exercising the unsafe code, to be run with `cargo +nightly miri test`
+    /// (also with `MIRIFLAGS=-Zmiri-strict-provenance`)
+    mod miri {
+        use super::*;
+        use crate::ThinBox;
 
-    impl MyTrait for Foobar3 {
-        fn myfn(&self) -> u32 {
-            self.q + 4
+        trait Accumulate {
+            fn get(&self) -> u64;
+            fn add(&mut self, x: u64);
         }
-    }
 
-    trait SomeOtherTrait {}
-    impl SomeOtherTrait for Foobar3 {}
+        #[vptr(MyTrait, Accumulate)]
+        #[derive(Default)]
+        struct Acc {
+            before: u8,
+            values: [u64; 4],
+            after: u16,
+        }
 
-    #[test]
-    fn it_works3() {
-        let mut f = Foobar3::default();
-        f.q = 5;
-        println!("{:?}", f);
-        assert_eq!(f.myfn(), 9);
+        impl Accumulate for Acc {
+            fn get(&self) -> u64 {
+                self.before as u64 + self.values.iter().sum::<u64>() + self.after as u64
+            }
+            fn add(&mut self, x: u64) {
+                self.before += 1;
+                self.values[3] += x;
+                self.after += 1;
+            }
+        }
+        impl MyTrait for Acc {
+            fn myfn(&self) -> u32 {
+                self.get() as u32
+            }
+        }
 
-        {
-            let xx: ThinRef<dyn MyTrait> = f.as_thin_ref();
-            assert_eq!(xx.myfn(), 9);
+        #[test]
+        fn thin_ref() {
+            let f = Acc {
+                values: [1, 2, 3, 4],
+                ..Default::default()
+            };
+            let xx: ThinRef<dyn Accumulate> = f.as_thin_ref();
+            let yy: ThinRef<dyn MyTrait> = ThinRef::from(&f);
+            assert_eq!(xx.get(), 10);
+            assert_eq!(yy.myfn(), 10);
+            assert_eq!(f.get(), 10);
         }
 
-        {
-            let xx: ThinRefMut<dyn MyTrait> = f.as_thin_ref_mut();
-            assert_eq!(xx.myfn(), 9);
+        #[test]
+        fn thin_ref_mut() {
+            let mut f = Acc::default();
+            {
+                let mut xx: ThinRefMut<dyn Accumulate> = f.as_thin_ref_mut();
+                xx.add(5);
+                assert_eq!(xx.get(), 7);
+                xx.reborrow().add(1);
+            }
+            assert_eq!(f.get(), 10);
+            f.add(1);
+            let xx: ThinRef<dyn Accumulate> = f.as_thin_ref();
+            assert_eq!(xx.get(), 13);
         }
-    }
 
-    /*
-    #[vptr(MyTrait)]
-    #[derive(Default)]
-    struct WithGenerics<T> {
-        pub foo: Vec<T>
-    }
+        #[test]
+        fn thin_box() {
+            let mut b = ThinBox::<dyn Accumulate>::from_box(Box::new(Acc::default()));
+            b.add(3);
+            assert_eq!(b.get(), 5);
+            ThinBox::as_thin_ref_mut(&mut b).add(1);
+            assert_eq!(ThinBox::as_thin_ref(&b).get(), 8);
+            let mut b = ThinBox::into_box(b);
+            b.add(1);
+            assert_eq!(b.get(), 11);
+            let b = ThinBox::<dyn MyTrait>::from_box(Box::new(Acc::default()));
+            assert_eq!(b.myfn(), 0);
+        }
 
-    impl<T> MyTrait for WithGenerics<T> {
-        fn myfn(&self)  -> u32 {
-            self.foo.len() as u32
+        #[test]
+        fn aliasing() {
+            let mut f = Acc::default();
+            {
+                let mut xx: ThinRefMut<dyn Accumulate> = f.as_thin_ref_mut();
+                xx.add(1);
+                // Shared reborrows of the ThinRefMut while it is alive
+                let shared: &dyn Accumulate = &*xx;
+                assert_eq!(shared.get(), 3);
+                assert_eq!(xx.get(), 3);
+                xx.add(1);
+            }
+            // The mutable borrow has ended
+            let yy: ThinRef<dyn Accumulate> = f.as_thin_ref();
+            let zz: ThinRef<dyn MyTrait> = f.as_thin_ref();
+            assert_eq!(yy.get(), 6);
+            assert_eq!(zz.myfn(), 6);
+            assert_eq!(f.values[3], 2);
+            // Direct access to the object, then a new mutable thin reference
+            f.values[0] = 10;
+            let mut xx: ThinRefMut<dyn Accumulate> = f.as_thin_ref_mut();
+            let mut r = xx.reborrow();
+            r.add(1);
+            xx.add(1);
+            assert_eq!(f.get(), 22);
+            let mut b = ThinBox::<dyn Accumulate>::from_box(Box::new(f));
+            {
+                let r: &mut dyn Accumulate = &mut *b;
+                r.add(1);
+            }
+            let yy = ThinBox::as_thin_ref(&b);
+            let yy2 = ThinBox::as_thin_ref(&b);
+            assert_eq!(yy.get() + yy2.get(), 50);
+        }
+
+        #[test]
+        fn provenance() {
+            // Write through the reconstructed reference to the fields on both sides of the
+            // VPtr fields, which are only reachable with the provenance of the whole object
+            let mut f = Acc::default();
+            let mut xx: ThinRefMut<dyn Accumulate> = f.as_thin_ref_mut();
+            let acc = &mut *xx as *mut dyn Accumulate as *mut Acc;
+            assert_eq!(acc, &mut f as *mut Acc);
+            let mut xx: ThinRefMut<dyn Accumulate> = f.as_thin_ref_mut();
+            xx.add(4);
+            let yy: ThinRef<dyn MyTrait> = f.as_thin_ref();
+            let zz: ThinRef<dyn Accumulate> = f.as_thin_ref();
+            assert_eq!(yy.myfn(), 6);
+            assert_eq!(zz.get(), 6);
+            assert_eq!((f.before, f.values[3], f.after), (1, 4, 1));
+        }
+
+        #[test]
+        fn addresses() {
+            let v: Vec<Acc> = (0..16)
+                .map(|i| Acc {
+                    values: [i, 0, 0, 0],
+                    ..Default::default()
+                })
+                .collect();
+            for (i, f) in v.iter().enumerate() {
+                let xx: ThinRef<dyn Accumulate> = f.as_thin_ref();
+                let key = ThinRef::as_usize(xx);
+                let begin = f as *const Acc as usize;
+                assert!(key >= begin && key < begin + core::mem::size_of::<Acc>());
+                let raw = ThinRef::into_raw(xx);
+                let yy = unsafe { ThinRef::<dyn Accumulate>::from_raw(raw) };
+                assert_eq!(&*yy as *const dyn Accumulate as *const Acc, f as *const Acc);
+                assert_eq!(yy.get(), i as u64);
+            }
+        }
+
+        #[test]
+        fn large_offset() {
+            // With repr(C), the VPtr field comes after all the other fields, so going back to
+            // the object goes back by nearly the whole size of the object. The object is also
+            // between other objects in the same allocation, which must not be touched.
+            #[vptr(Accumulate)]
+            #[repr(C)]
+            struct Wide {
+                head: [u64; 16],
+                tail: u8,
+            }
+            impl Accumulate for Wide {
+                fn get(&self) -> u64 {
+                    self.head.iter().sum::<u64>() + self.tail as u64
+                }
+                fn add(&mut self, x: u64) {
+                    self.head[0] += x;
+                    self.tail += 1;
+                }
+            }
+            struct Outer {
+                before: u32,
+                wide: Wide,
+                after: u32,
+            }
+            let offset = <Wide as HasVPtr<dyn Accumulate>>::init().offset;
+            assert!(offset as usize > core::mem::size_of::<[u64; 16]>());
+
+            let mut v: Vec<Outer> = (0..3)
+                .map(|i| Outer {
+                    before: i,
+                    wide: Wide {
+                        head: [i as u64; 16],
+                        tail: 0,
+                        vptr_Accumulate: VPtr::new(),
+                    },
+                    after: i,
+                })
+                .collect();
+            for o in v.iter_mut() {
+                let mut xx: ThinRefMut<dyn Accumulate> = o.wide.as_thin_ref_mut();
+                xx.add(1);
+            }
+            for (i, o) in v.iter().enumerate() {
+                let xx: ThinRef<dyn Accumulate> = o.wide.as_thin_ref();
+                assert_eq!(xx.get(), i as u64 * 16 + 2);
+                assert_eq!((o.before, o.after), (i as u32, i as u32));
+            }
+            let b = ThinBox::<dyn Accumulate>::from_box(Box::new(v.pop().unwrap().wide));
+            assert_eq!(b.get(), 2 * 16 + 2);
         }
     }
 
     #[test]
-    fn with_generics() {
-        let mut f = WithGenerics::<u32>::default();
-        f.foo.push(3);
-        assert_eq!(f.myfn(), 1);
+    fn trait_object_parts() {
+        use crate::internal::{from_trait_object, to_trait_object};
+        let f = Tuple(1, 2, VPtr::new());
+        let to = unsafe { to_trait_object::<dyn MyTrait>(&f) };
+        assert_eq!(to.data, &f as *const Tuple as *const ());
+        assert_eq!(to.vtable, <Tuple as HasVPtr<dyn MyTrait>>::init().vtable);
+        let p = unsafe { from_trait_object::<dyn MyTrait>(to) };
+        assert_eq!(unsafe { &*p }.myfn(), 2);
+    }
 
-        let xx : ThinRef<dyn MyTrait> = f.as_thin_ref();
-        assert_eq!(xx.myfn(), 9);
+    #[test]
+    fn from_embedded() {
+        let f = Foobar3 {
+            q: 4,
+            ..Default::default()
+        };
+        let object: *const Foobar3 = &f;
+        let vptr: *mut VPtr<Foobar3, dyn MyTrait> =
+            unsafe { core::ptr::addr_of!((*object).vptr_MyTrait) as *mut _ };
+        let xx = unsafe { ThinRef::from_embedded(core::ptr::NonNull::new(vptr).unwrap()) };
+        assert_eq!(xx.myfn(), 8);
+        assert_eq!(
+            ThinRef::into_raw(xx),
+            ThinRef::into_raw(f.as_thin_ref() as ThinRef<dyn MyTrait>)
+        );
+    }
 
+    #[test]
+    fn copy() {
+        let f = Tuple(2, 3, Default::default());
+        let xx: ThinRef<_> = f.as_thin_ref();
+        let xx2 = xx;
+        assert_eq!(xx.myfn(), 3);
+        assert_eq!(xx2.myfn(), 3);
     }
-    */
 
-    #[vptr(MyTrait)]
-    #[derive(Default)]
-    struct WithLifeTime<'a> {
-        pub foo: Option<&'a u32>,
+    mod downstream {
+        trait Shape {}
+        assert_thin!(dyn Shape);
+        assert_thin!(dyn Shape + Send);
+        assert_thin!(dyn std::fmt::Display);
     }
 
-    impl<'a> MyTrait for WithLifeTime<'a> {
-        fn myfn(&self) -> u32 {
-            *self.foo.unwrap_or(&0)
+    #[test]
+    fn is_thin() {
+        assert!(crate::is_thin::<dyn MyTrait>());
+        assert!(crate::is_thin::<dyn SomeOtherTrait + Sync>());
+    }
+
+    #[test]
+    fn pin_projection() {
+        use crate::ThinBox;
+        use core::marker::PhantomPinned;
+        use core::pin::Pin;
+
+        trait SelfRef {
+            fn value(self: Pin<&Self>) -> u32;
+        }
+        #[vptr(SelfRef)]
+        struct Pinned {
+            data: u32,
+            data_ptr: *const u32,
+            _pin: PhantomPinned,
+        }
+        impl SelfRef for Pinned {
+            fn value(self: Pin<&Self>) -> u32 {
+                assert_eq!(self.data_ptr, &self.data as *const u32);
+                unsafe { *self.data_ptr }
+            }
+        }
+
+        let mut f = Box::pin(Pinned {
+            data: 42,
+            data_ptr: core::ptr::null(),
+            _pin: PhantomPinned,
+            vptr_SelfRef: VPtr::new(),
+        });
+        unsafe {
+            let f = f.as_mut().get_unchecked_mut();
+            f.data_ptr = &f.data;
         }
+        let xx: Pin<ThinRef<dyn SelfRef>> = f.as_ref().as_pin_thin_ref();
+        let pinned: Pin<&dyn SelfRef> = ThinRef::get_pinned_ref(xx);
+        assert_eq!(pinned.value(), 42);
+
+        // The thin pointers are Unpin even if the object is not
+        fn assert_unpin<T: Unpin>() {}
+        assert_unpin::<ThinRef<dyn SelfRef>>();
+        assert_unpin::<ThinRefMut<dyn SelfRef>>();
+        assert_unpin::<ThinBox<dyn SelfRef>>();
+
+        let thin = ThinBox::<dyn SelfRef>::from_box(Box::new(Pinned {
+            data: 43,
+            data_ptr: core::ptr::null(),
+            _pin: PhantomPinned,
+            vptr_SelfRef: VPtr::new(),
+        }));
+        // Safety: the object is not moved out of the box
+        let mut thin: Pin<ThinBox<dyn SelfRef>> = unsafe { Pin::new_unchecked(thin) };
+        unsafe {
+            let f =
+                &mut *(&mut *thin.as_mut().get_unchecked_mut() as *mut dyn SelfRef as *mut Pinned);
+            f.data_ptr = &f.data;
+        }
+        // Moving the pinned ThinBox does not move the object
+        let moved = thin;
+        assert_eq!(moved.as_ref().value(), 43);
     }
 
     #[test]
-    fn with_lifetime() {
-        let mut f = WithLifeTime::default();
-        let x = 43;
-        f.foo = Some(&x);
-        assert_eq!(f.myfn(), 43);
+    fn thin_refs_of_slice() {
+        let v: Vec<Foobar2> = (1..=3)
+            .map(|q| Foobar2 {
+                q,
+                vptr_MyTrait: VPtr::new(),
+            })
+            .collect();
+        let refs: Vec<ThinRef<dyn MyTrait>> = crate::thin_refs_of_slice(&v);
+        assert_eq!(refs.len(), 3);
+        assert_eq!(refs.iter().map(|r| r.myfn()).sum::<u32>(), 5 + 6 + 7);
+        for (r, f) in refs.iter().zip(&v) {
+            assert!(core::ptr::eq(
+                ThinRef::downcast_ref::<Foobar2>(*r).unwrap(),
+                f
+            ));
+        }
 
-        let xx: ThinRef<dyn MyTrait> = f.as_thin_ref();
-        assert_eq!(xx.myfn(), 43);
+        let it = crate::thin_refs_iter::<_, dyn MyTrait>(&v[1..]);
+        assert_eq!(it.len(), 2);
+        assert_eq!(it.rev().map(|r| r.myfn()).collect::<Vec<_>>(), [7, 6]);
     }
 
-    #[vptr(MyTrait)]
-    struct Tuple(u32, u32);
+    #[test]
+    fn thin_box_into_inner() {
+        use crate::ThinBox;
+        use std::cell::Cell;
+        use std::rc::Rc;
 
-    impl MyTrait for Tuple {
-        fn myfn(&self) -> u32 {
-            self.1
+        #[vptr(MyTrait)]
+        struct Counted {
+            value: u32,
+            drops: Rc<Cell<u32>>,
+        }
+        impl MyTrait for Counted {
+            fn myfn(&self) -> u32 {
+                self.value
+            }
+        }
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                self.drops.set(self.drops.get() + 1);
+            }
         }
+
+        let drops = Rc::new(Cell::new(0));
+        let b = ThinBox::<dyn MyTrait>::from_box(Box::new(Counted {
+            value: 7,
+            drops: drops.clone(),
+            vptr_MyTrait: VPtr::new(),
+        }));
+        // Wrong type: the box is returned unchanged
+        let b = ThinBox::into_inner::<Foobar2>(b).err().unwrap();
+        assert_eq!(b.myfn(), 7);
+        assert_eq!(drops.get(), 0);
+
+        let c: Counted = ThinBox::into_inner(b).ok().unwrap();
+        assert_eq!(drops.get(), 0);
+        assert_eq!(c.value, 7);
+        // The VPtr still works after moving the object
+        assert_eq!(ThinRef::<dyn MyTrait>::from(&c).myfn(), 7);
+        drop(c);
+        assert_eq!(drops.get(), 1);
     }
+
     #[test]
-    fn tuple() {
-        let f = Tuple(42, 43, Default::default());
-        assert_eq!(f.myfn(), 43);
+    fn pin_mut_receiver() {
+        use core::future::Future;
+        use core::marker::PhantomPinned;
+        use core::pin::Pin;
+        use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
 
-        let xx: ThinRef<_> = f.as_thin_ref();
-        assert_eq!(xx.myfn(), 43);
+        // A poll-style trait with a self-referential implementation
+        trait Poller {
+            fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u32>;
+        }
+        #[vptr(Poller)]
+        struct Pending {
+            remaining: u32,
+            data_ptr: *const u32,
+            _pin: PhantomPinned,
+        }
+        impl Poller for Pending {
+            fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u32> {
+                let this = unsafe { self.get_unchecked_mut() };
+                if this.data_ptr.is_null() {
+                    this.data_ptr = &this.remaining;
+                }
+                assert_eq!(this.data_ptr, &this.remaining as *const u32);
+                if this.remaining == 0 {
+                    return Poll::Ready(42);
+                }
+                this.remaining -= 1;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+        // Adapt to a Future to check that it works with the real trait signatures
+        struct Adapter<'a>(Pin<ThinRefMut<'a, dyn Poller>>);
+        impl Future for Adapter<'_> {
+            type Output = u32;
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u32> {
+                // Pin::as_mut goes through DerefMut and gives a Pin<&mut dyn Poller>
+                self.0.as_mut().poll_next(cx)
+            }
+        }
+
+        fn noop_raw_waker() -> RawWaker {
+            fn clone(_: *const ()) -> RawWaker {
+                noop_raw_waker()
+            }
+            fn noop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+            RawWaker::new(core::ptr::null(), &VTABLE)
+        }
+        let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+        let mut cx = Context::from_waker(&waker);
+
+        let mut p = Box::pin(Pending {
+            remaining: 2,
+            data_ptr: core::ptr::null(),
+            _pin: PhantomPinned,
+            vptr_Poller: VPtr::new(),
+        });
+        let mut fut = Adapter(p.as_mut().as_pin_thin_ref_mut());
+        let mut fut = Pin::new(&mut fut);
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Ready(42));
+
+        // The projection for the full lifetime
+        let pinned: Pin<&mut dyn Poller> =
+            ThinRefMut::get_pinned_mut(p.as_mut().as_pin_thin_ref_mut());
+        assert_eq!(pinned.poll_next(&mut cx), Poll::Ready(42));
     }
 
-    #[vptr(MyTrait)]
-    struct Empty1;
+    #[test]
+    fn thin_box_pin() {
+        use crate::ThinBox;
+        use core::marker::PhantomPinned;
+        use core::pin::Pin;
 
-    impl MyTrait for Empty1 {
-        fn myfn(&self) -> u32 {
-            88
+        trait SelfRef {
+            fn setup(self: Pin<&mut Self>);
+            fn value(self: Pin<&Self>) -> u32;
+        }
+        #[vptr(SelfRef)]
+        struct Pinned {
+            data: u32,
+            data_ptr: *const u32,
+            _pin: PhantomPinned,
+        }
+        impl SelfRef for Pinned {
+            fn setup(self: Pin<&mut Self>) {
+                let this = unsafe { self.get_unchecked_mut() };
+                this.data_ptr = &this.data;
+            }
+            fn value(self: Pin<&Self>) -> u32 {
+                assert_eq!(self.data_ptr, &self.data as *const u32);
+                unsafe { *self.data_ptr }
+            }
         }
+
+        let mut b: Pin<ThinBox<dyn SelfRef>> = ThinBox::pin(Pinned {
+            data: 42,
+            data_ptr: core::ptr::null(),
+            _pin: PhantomPinned,
+            vptr_SelfRef: VPtr::new(),
+        });
+        b.as_mut().setup();
+        assert_eq!(b.as_ref().value(), 42);
+        let moved = b;
+        assert_eq!(moved.as_ref().value(), 42);
     }
 
     #[test]
-    fn empty_struct() {
-        let f = Empty1(VPtr::new());
-        assert_eq!(f.myfn(), 88);
+    fn into_pin_box() {
+        use crate::ThinBox;
+        use core::pin::Pin;
 
-        let xx: ThinRef<dyn MyTrait> = f.as_thin_ref();
-        assert_eq!(xx.myfn(), 88);
+        // Similar to Future::poll
+        trait Step {
+            fn step(self: Pin<&mut Self>) -> Option<u32>;
+        }
+        #[vptr(Step)]
+        struct Countdown(u32);
+        impl Step for Countdown {
+            fn step(mut self: Pin<&mut Self>) -> Option<u32> {
+                self.0 = self.0.checked_sub(1)?;
+                Some(self.0)
+            }
+        }
+
+        let b = ThinBox::<dyn Step>::from_box(Box::new(Countdown(3, VPtr::new())));
+        let mut pinned: Pin<Box<dyn Step>> = ThinBox::into_pin_box(b);
+        let mut results = Vec::new();
+        while let Some(x) = pinned.as_mut().step() {
+            results.push(x);
+        }
+        assert_eq!(results, [2, 1, 0]);
     }
 
-    #[vptr(std::fmt::Display)]
-    struct TestDisplay {
-        str: String,
+    #[test]
+    fn thin_ref_shared() {
+        fn from_mut(f: &mut Foobar3) -> u32 {
+            let xx: ThinRef<dyn MyTrait> = f.as_thin_ref_shared();
+            let yy = xx;
+            xx.myfn() + yy.myfn()
+        }
+        let mut f = Foobar3 {
+            q: 1,
+            ..Default::default()
+        };
+        assert_eq!(from_mut(&mut f), 10);
+        let xx: ThinRefMut<dyn MyTrait> = f.as_thin_ref_mut();
+        assert_eq!(xx.myfn(), 5);
     }
-    impl std::fmt::Display for TestDisplay {
-        fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            write!(fmt, "Test {}", self.str)
+
+    trait Counter {
+        fn incr(&mut self) -> u32;
+    }
+    #[vptr(Counter)]
+    #[derive(Default)]
+    struct Count {
+        c: u32,
+    }
+    impl Counter for Count {
+        fn incr(&mut self) -> u32 {
+            self.c += 1;
+            self.c
         }
     }
 
     #[test]
-    fn with_path() {
-        let x = TestDisplay {
-            str: "Hello".to_string(),
-            vptr_Display: Default::default(),
-        };
-        let xx: ThinRef<dyn std::fmt::Display> = x.as_thin_ref();
-        assert_eq!(xx.to_string(), "Test Hello");
+    fn reborrow() {
+        fn incr_thin(mut r: ThinRefMut<dyn Counter>) -> u32 {
+            r.incr()
+        }
+
+        let mut f = Count::default();
+        let mut xx: ThinRefMut<dyn Counter> = f.as_thin_ref_mut();
+        assert_eq!(incr_thin(xx.reborrow()), 1);
+        assert_eq!(incr_thin(xx.reborrow()), 2);
+        assert_eq!(xx.incr(), 3);
+        assert_eq!(f.c, 3);
     }
 
     #[test]
-    fn test_trait_with_gen() {
-        trait TraitWithGen<T> {
-            fn compute(&self, x: T) -> u32;
+    fn into_mut() {
+        fn to_fat<'a>(thin: ThinRefMut<'a, dyn Counter>) -> &'a mut dyn Counter {
+            ThinRefMut::into_mut(thin)
         }
-        #[vptr("TraitWithGen<u64>")]
-        struct TestTraitWithGen {
-            value: u32,
+
+        let mut f = Count::default();
+        let mut xx: ThinRefMut<dyn Counter> = f.as_thin_ref_mut();
+        assert_eq!(xx.incr(), 1);
+        let fat = to_fat(xx);
+        assert_eq!(fat.incr(), 2);
+        assert_eq!(fat.incr(), 3);
+        assert_eq!(f.c, 3);
+    }
+
+    #[test]
+    fn thin_ref_mut_to_shared() {
+        trait SetValue {
+            fn get(&self) -> u32;
+            fn set(&mut self, v: u32);
         }
-        impl TraitWithGen<u64> for TestTraitWithGen {
-            fn compute(&self, x: u64) -> u32 {
-                self.value + (x as u32)
+        #[vptr(SetValue)]
+        #[derive(Default)]
+        struct Value {
+            q: u32,
+        }
+        impl SetValue for Value {
+            fn get(&self) -> u32 {
+                self.q
+            }
+            fn set(&mut self, v: u32) {
+                self.q = v
             }
         }
-
-        let x = TestTraitWithGen {
-            value: 44,
-            vptr_TraitWithGen: Default::default(),
+        let mut f = Value {
+            q: 1,
+            ..Default::default()
         };
-        let xx: ThinRef<dyn TraitWithGen<u64>> = x.as_thin_ref();
-        assert_eq!(core::mem::size_of_val(&xx), core::mem::size_of::<usize>());
-        assert_eq!(xx.compute(66u64), 44 + 66);
+        let mut xx: ThinRefMut<dyn SetValue> = f.as_thin_ref_mut();
+        assert_eq!(ThinRefMut::as_shared(&xx).get(), 1);
+        xx.set(2);
+        assert_eq!(ThinRefMut::as_shared(&xx).get(), 2);
+
+        let shared: ThinRef<dyn SetValue> = ThinRefMut::into_thin_ref(xx);
+        let copy = shared;
+        assert_eq!(shared.get(), 2);
+        assert_eq!(copy.get(), 2);
+        assert_eq!(f.q, 2);
+    }
+
+    #[vptr(MyTrait)]
+    #[derive(Default, Clone, Copy)]
+    struct CopyStruct {
+        q: u32,
+    }
+
+    impl MyTrait for CopyStruct {
+        fn myfn(&self) -> u32 {
+            self.q
+        }
     }
 
     #[test]
-    fn copy() {
-        let f = Tuple(2, 3, Default::default());
-        let xx: ThinRef<_> = f.as_thin_ref();
-        let xx2 = xx;
-        assert_eq!(xx.myfn(), 3);
-        assert_eq!(xx2.myfn(), 3);
+    fn copy_struct() {
+        let original = CopyStruct {
+            q: 1,
+            ..Default::default()
+        };
+        let mut copy = original;
+        copy.q = 2;
+        #[allow(clippy::clone_on_copy)]
+        let mut clone = original.clone();
+        clone.q = 3;
+
+        let xx: ThinRef<dyn MyTrait> = original.as_thin_ref();
+        let yy: ThinRef<dyn MyTrait> = copy.as_thin_ref();
+        let zz: ThinRef<dyn MyTrait> = clone.as_thin_ref();
+        assert_eq!(xx.myfn(), 1);
+        assert_eq!(yy.myfn(), 2);
+        assert_eq!(zz.myfn(), 3);
+        assert_eq!(
+            &*yy as *const dyn MyTrait as *const u8,
+            &copy as *const _ as *const u8
+        );
     }
 
     #[test]
     fn pin() {
         use core::pin::Pin;
         {
-            let mut f = Foobar3::default();
-            f.q = 5;
+            let f = Foobar3 {
+                q: 5,
+                ..Default::default()
+            };
             let f: Pin<&Foobar3> = unsafe { Pin::new_unchecked(&f) };
             let xx: Pin<ThinRef<dyn MyTrait>> = f.as_pin_thin_ref();
             assert_eq!(xx.myfn(), 9);
         }
 
         {
-            let mut f = Foobar3::default();
-            f.q = 8;
+            let mut f = Foobar3 {
+                q: 8,
+                ..Default::default()
+            };
             let f: Pin<&mut Foobar3> = unsafe { Pin::new_unchecked(&mut f) };
             let xx: Pin<ThinRefMut<dyn MyTrait>> = f.as_pin_thin_ref_mut();
             assert_eq!(xx.myfn(), 12);
         }
     }
-
 }
@@ -0,0 +1,203 @@
+/* Copyright (C) 2019 Olivier Goffart <ogoffart@woboq.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+and/or sell copies of the Software, and to permit persons to whom the Software is furnished to do so,
+subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! A list of observers, notified through thin references
+
+use crate::{HasVPtr, ThinBox, ThinRef};
+use std::boxed::Box;
+use std::vec::Vec;
+
+/// Identifies an observer in an [`ObserverList`], to remove it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ObserverId(u64);
+
+/// A list of observers implementing `Trait`, which can be of different types
+///
+/// ```rust
+/// # use vptr::*;
+/// # use vptr::observers::ObserverList;
+/// # use std::cell::Cell;
+/// trait Listener { fn changed(&self, value: u32); }
+/// #[vptr(Listener)]
+/// #[derive(Default)]
+/// struct Printer { last: Cell<u32> }
+/// impl Listener for Printer { fn changed(&self, value: u32) { self.last.set(value) } }
+///
+/// let mut list = ObserverList::<dyn Listener>::new();
+/// let id = list.add(Printer::default());
+/// list.notify(|l| l.changed(42));
+/// let printer = list.remove(id).unwrap();
+/// assert_eq!(ThinRef::downcast_ref::<Printer>(ThinBox::as_thin_ref(&printer)).unwrap().last.get(), 42);
+/// assert!(list.is_empty());
+/// ```
+pub struct ObserverList<Trait: ?Sized + 'static> {
+    observers: Vec<(ObserverId, ThinBox<Trait>)>,
+    next_id: u64,
+}
+
+impl<Trait: ?Sized + 'static> ObserverList<Trait> {
+    /// Creates an empty list
+    pub fn new() -> Self {
+        ObserverList {
+            observers: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Box the observer and add it at the end of the list
+    pub fn add<T: HasVPtr<Trait> + 'static>(&mut self, observer: T) -> ObserverId {
+        self.add_box(ThinBox::from_box(Box::new(observer)))
+    }
+
+    /// Add an already boxed observer at the end of the list
+    pub fn add_box(&mut self, observer: ThinBox<Trait>) -> ObserverId {
+        let id = ObserverId(self.next_id);
+        self.next_id += 1;
+        self.observers.push((id, observer));
+        id
+    }
+
+    /// Remove the observer with the given id, and return it.
+    ///
+    /// Returns None if it was already removed.
+    pub fn remove(&mut self, id: ObserverId) -> Option<ThinBox<Trait>> {
+        let index = self.observers.iter().position(|(i, _)| *i == id)?;
+        Some(self.observers.remove(index).1)
+    }
+
+    /// The number of observers
+    pub fn len(&self) -> usize {
+        self.observers.len()
+    }
+
+    /// Returns true if there are no observers
+    pub fn is_empty(&self) -> bool {
+        self.observers.is_empty()
+    }
+
+    /// Iterate over thin references to the observers, in the order they were added
+    pub fn iter(&self) -> impl Iterator<Item = ThinRef<'_, Trait>> {
+        self.observers.iter().map(|(_, b)| ThinBox::as_thin_ref(b))
+    }
+
+    /// Call `f` for each observer, in the order they were added
+    pub fn notify(&self, mut f: impl FnMut(&Trait)) {
+        for (_, b) in &self.observers {
+            f(b)
+        }
+    }
+
+    /// Call `f` with a mutable reference to each observer, in the order they were added
+    pub fn notify_mut(&mut self, mut f: impl FnMut(&mut Trait)) {
+        for (_, b) in &mut self.observers {
+            f(b)
+        }
+    }
+}
+
+impl<Trait: ?Sized + 'static> Default for ObserverList<Trait> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{vptr, VPtr};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    mod vptr {
+        // Because otherwise, the generated code cannot access the vptr crate.
+        pub use crate::*;
+    }
+
+    trait Observer {
+        fn on_event(&self, event: &str);
+        fn reset(&mut self);
+    }
+
+    #[vptr(Observer)]
+    struct Logger {
+        log: Rc<RefCell<Vec<String>>>,
+    }
+    impl Observer for Logger {
+        fn on_event(&self, event: &str) {
+            self.log.borrow_mut().push(format!("logger: {}", event));
+        }
+        fn reset(&mut self) {
+            self.log.borrow_mut().clear();
+        }
+    }
+
+    #[vptr(Observer)]
+    struct Counter {
+        count: Rc<RefCell<u32>>,
+        resets: u32,
+    }
+    impl Observer for Counter {
+        fn on_event(&self, _: &str) {
+            *self.count.borrow_mut() += 1;
+        }
+        fn reset(&mut self) {
+            self.resets += 1;
+        }
+    }
+
+    #[test]
+    fn notify() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let count = Rc::new(RefCell::new(0));
+        let mut list = ObserverList::<dyn Observer>::default();
+        let logger = list.add(Logger {
+            log: log.clone(),
+            vptr_Observer: VPtr::new(),
+        });
+        let counter = list.add(Counter {
+            count: count.clone(),
+            resets: 0,
+            vptr_Observer: VPtr::new(),
+        });
+        assert_ne!(logger, counter);
+        assert_eq!(list.len(), 2);
+
+        list.notify(|o| o.on_event("a"));
+        list.notify(|o| o.on_event("b"));
+        assert_eq!(*log.borrow(), ["logger: a", "logger: b"]);
+        assert_eq!(*count.borrow(), 2);
+
+        list.notify_mut(|o| o.reset());
+        assert!(log.borrow().is_empty());
+
+        let removed = list.remove(logger).unwrap();
+        assert!(ThinRef::downcast_ref::<Logger>(ThinBox::as_thin_ref(&removed)).is_some());
+        assert!(list.remove(logger).is_none());
+        list.notify(|o| o.on_event("c"));
+        assert!(log.borrow().is_empty());
+        assert_eq!(*count.borrow(), 3);
+
+        let c = list.iter().next().unwrap();
+        assert_eq!(ThinRef::downcast_ref::<Counter>(c).unwrap().resets, 1);
+
+        // Ids are not reused
+        let again = list.add_box(removed);
+        assert_ne!(again, logger);
+        assert_eq!(list.len(), 2);
+    }
+}
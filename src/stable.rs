@@ -0,0 +1,206 @@
+/* Copyright (C) 2019 Olivier Goffart <ogoffart@woboq.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+and/or sell copies of the Software, and to permit persons to whom the Software is furnished to do so,
+subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! Thin references with an ABI stable vtable, with the `abi_stable` feature
+//!
+//! The layout of the Rust vtables is not stable, so a [`ThinRef`](crate::ThinRef) must not be
+//! dereferenced by code compiled separately, such as a plugin loaded at run time. A
+//! [`StableThinRef`] instead points to a [`StableVPtr`] field, which points to a `#[repr(C)]`
+//! vtable of `extern "C"` functions declared by the user. The host and the plugins only need to
+//! agree on the declaration of this vtable, and the types of this module implement
+//! [`StableAbi`] so that `abi_stable` can check their layout when loading a library.
+//!
+//! The `stable(Trait, VTable, "fn method(&self, ...)", ...)` option of the `#[vptr]` macro adds
+//! a `vptr_VTable` field to the struct, and generates the functions of the vtable, which call
+//! the listed methods of the trait. The fields of the vtable must be named after the methods,
+//! and have the type `unsafe extern "C" fn(*const c_void, ...)` for methods taking `&self`, or
+//! `unsafe extern "C" fn(*mut c_void, ...)` for `&mut self`. The first argument is the raw
+//! pointer of the [`StableThinRef`]. Like `c_abi`, this is limited to methods without generic
+//! parameters, whose arguments and return type can be passed to C. It is only supported for
+//! structs with named fields and without lifetimes.
+//!
+//! ```rust
+//! # use vptr::*;
+//! use abi_stable::StableAbi;
+//! use std::ffi::c_void;
+//!
+//! /// The vtable shared by the host and the plugins
+//! #[repr(C)]
+//! #[derive(StableAbi)]
+//! pub struct ShapeVTable {
+//!     pub area: unsafe extern "C" fn(*const c_void) -> f32,
+//! }
+//!
+//! trait Shape { fn area(&self) -> f32; }
+//! #[vptr(stable(Shape, ShapeVTable, "fn area(&self) -> f32"))]
+//! struct Rectangle { w: f32, h : f32 }
+//! impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+//!
+//! let r = Rectangle { w: 5., h: 10., vptr_ShapeVTable: StableVPtr::new() };
+//! let thin: StableThinRef<ShapeVTable> = r.as_stable_thin_ref();
+//! let raw = StableThinRef::into_raw(thin);
+//! // Only `raw` and the ShapeVTable are needed to call the function
+//! let thin = unsafe { StableThinRef::<ShapeVTable>::from_raw(raw) };
+//! assert_eq!(unsafe { (StableThinRef::vtable(thin).area)(raw) }, 50.);
+//! ```
+//!
+//! The `StableThinRef` is a shared reference. To call the functions taking a `*mut c_void`, the
+//! pointer to the field must be derived from a mutable pointer to the whole object, for
+//! example with `core::ptr::addr_of_mut!((*object).vptr_VTable)`.
+
+use abi_stable::StableAbi;
+use core::ffi::c_void;
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+/// The data pointed by a [`StableVPtr`] field, generated by the `#[vptr]` macro
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct StableVTableData<VTable> {
+    /// Offset, in byte, of the StableVPtr field within the struct
+    pub offset: isize,
+    /// The vtable of `extern "C"` functions
+    pub vtable: VTable,
+}
+
+/// This trait indicates that the type has a [`StableVPtr`] field to the vtable `VTable`
+///
+/// You should not implement this trait yourself, it is implemented by the `vptr` macro
+///
+/// # Safety
+///
+/// `init` must return the offset of the field returned by `get_stable_vptr`, and a vtable
+/// whose functions expect a pointer to this field of an object of this type.
+pub unsafe trait HasStableVPtr<VTable: 'static> {
+    /// The StableVTableData of this type
+    fn init() -> &'static StableVTableData<VTable>;
+
+    /// Return a reference to the StableVPtr within Self
+    fn get_stable_vptr(&self) -> &StableVPtr<Self, VTable>
+    where
+        Self: Sized;
+
+    /// Return a stable thin reference to self
+    fn as_stable_thin_ref(&self) -> StableThinRef<'_, VTable>
+    where
+        Self: Sized,
+    {
+        // Derive the pointer from the pointer to the whole object (see ThinRef::ptr)
+        let p = (self as *const Self as *const u8).wrapping_offset(Self::init().offset);
+        StableThinRef {
+            ptr: unsafe { NonNull::new_unchecked(p as *mut &'static StableVTableData<VTable>) },
+            phantom: PhantomData,
+        }
+    }
+}
+
+/// A field pointing to the StableVTableData of the type `T` for the vtable `VTable`
+///
+/// Like the VPtr, all the StableVPtr of a type are equal.
+#[repr(transparent)]
+pub struct StableVPtr<T: HasStableVPtr<VTable>, VTable: 'static> {
+    data: &'static StableVTableData<VTable>,
+    phantom: PhantomData<fn() -> T>,
+}
+
+impl<T: HasStableVPtr<VTable>, VTable: 'static> StableVPtr<T, VTable> {
+    /// Creates a new StableVPtr initialized to the StableVTableData of `T`
+    pub fn new() -> Self {
+        StableVPtr {
+            data: T::init(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T: HasStableVPtr<VTable>, VTable: 'static> Default for StableVPtr<T, VTable> {
+    fn default() -> Self {
+        StableVPtr::new()
+    }
+}
+
+impl<T: HasStableVPtr<VTable>, VTable: 'static> Clone for StableVPtr<T, VTable> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: HasStableVPtr<VTable>, VTable: 'static> Copy for StableVPtr<T, VTable> {}
+
+impl<T: HasStableVPtr<VTable>, VTable: 'static> PartialEq for StableVPtr<T, VTable> {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl<T: HasStableVPtr<VTable>, VTable: 'static> Eq for StableVPtr<T, VTable> {}
+
+impl<T: HasStableVPtr<VTable>, VTable: 'static> core::hash::Hash for StableVPtr<T, VTable> {
+    fn hash<H: core::hash::Hasher>(&self, _: &mut H) {}
+}
+
+impl<T: HasStableVPtr<VTable>, VTable: 'static> core::fmt::Debug for StableVPtr<T, VTable> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.pad("StableVPtr")
+    }
+}
+
+/// A thin reference to an object with a [`StableVPtr`] field for the vtable `VTable`
+///
+/// It has the size of a pointer, and its raw pointer can be given to code compiled separately,
+/// which calls the functions of the vtable with it. See the [module documentation](self).
+#[repr(transparent)]
+#[derive(StableAbi)]
+pub struct StableThinRef<'a, VTable: 'static> {
+    ptr: NonNull<&'static StableVTableData<VTable>>,
+    phantom: PhantomData<&'a ()>,
+}
+
+impl<'a, VTable: 'static> Clone for StableThinRef<'a, VTable> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, VTable: 'static> Copy for StableThinRef<'a, VTable> {}
+
+impl<'a, VTable: 'static> StableThinRef<'a, VTable> {
+    /// The vtable of the object
+    pub fn vtable(this: Self) -> &'static VTable {
+        unsafe { &(*this.ptr.as_ptr()).vtable }
+    }
+
+    /// The raw pointer to the StableVPtr field, to be given as first argument to the functions
+    /// of the vtable
+    pub fn into_raw(this: Self) -> *const c_void {
+        this.ptr.as_ptr() as *const c_void
+    }
+
+    /// Create a stable thin reference from a pointer returned by [`StableThinRef::into_raw`]
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to the StableVPtr field for `VTable` of an object which is
+    /// borrowed for the lifetime `'a`
+    pub unsafe fn from_raw(ptr: *const c_void) -> Self {
+        StableThinRef {
+            ptr: NonNull::new_unchecked(ptr as *mut &'static StableVTableData<VTable>),
+            phantom: PhantomData,
+        }
+    }
+}
@@ -0,0 +1,628 @@
+/* Copyright (C) 2019 Olivier Goffart <ogoffart@woboq.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+and/or sell copies of the Software, and to permit persons to whom the Software is furnished to do so,
+subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! Collections of thin boxes
+
+use crate::{HasVPtr, ThinBox, ThinRef, ThinRefMut};
+use core::ops::RangeBounds;
+use std::boxed::Box;
+use std::vec::Vec;
+
+/// A vector of `ThinBox<Trait>`
+///
+/// Each element has the size of a pointer
+///
+/// ```rust
+/// # use vptr::*;
+/// trait Shape { fn area(&self) -> f32; }
+/// #[vptr(Shape)]
+/// #[derive(Default)]
+/// struct Rectangle { w: f32, h : f32 }
+/// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+///
+/// let mut v = ThinVec::<dyn Shape>::new();
+/// v.push(Rectangle { w: 5., h: 10., ..Default::default() });
+/// v.push(Rectangle { w: 1., h: 2., ..Default::default() });
+/// v.push(Rectangle { w: 3., h: 3., ..Default::default() });
+/// v.retain(|x| x.area() > 5.);
+/// assert_eq!(v.iter().map(|x| x.area()).collect::<Vec<_>>(), [50., 9.]);
+/// ```
+///
+/// Like for `Vec<Box<dyn Trait>>`, the elements can only be accessed from several threads if
+/// the trait object is `Sync`, so the trait needs a `Sync` bound (or a `Sync` supertrait).
+/// The slice returned by [`ThinVec::as_ref_slice`] can then be split between threads:
+///
+/// ```rust
+/// # use vptr::*;
+/// trait Shape { fn area(&self) -> f32; }
+/// #[vptr(Shape + Sync)]
+/// #[derive(Default)]
+/// struct Rectangle { w: f32, h : f32 }
+/// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+///
+/// let mut v = ThinVec::<dyn Shape + Sync>::new();
+/// for i in 0..100 {
+///     v.push(Rectangle { w: i as f32, h: 1., ..Default::default() });
+/// }
+/// let total: f32 = std::thread::scope(|s| {
+///     let handles: Vec<_> = v
+///         .as_ref_slice()
+///         .chunks(10)
+///         .map(|chunk| s.spawn(move || chunk.iter().map(|x| x.area()).sum::<f32>()))
+///         .collect();
+///     handles.into_iter().map(|h| h.join().unwrap()).sum()
+/// });
+/// assert_eq!(total, 4950.);
+/// ```
+pub struct ThinVec<Trait: ?Sized + 'static> {
+    vec: Vec<ThinBox<Trait>>,
+}
+
+impl<Trait: ?Sized + 'static> ThinVec<Trait> {
+    /// Creates an empty vector
+    pub fn new() -> Self {
+        ThinVec { vec: Vec::new() }
+    }
+
+    /// Box the value and append it to the vector
+    pub fn push<T: HasVPtr<Trait> + 'static>(&mut self, value: T) {
+        self.push_box(ThinBox::from_box(Box::new(value)))
+    }
+
+    /// Append a ThinBox to the vector
+    pub fn push_box(&mut self, b: ThinBox<Trait>) {
+        self.vec.push(b)
+    }
+
+    /// The number of elements in the vector
+    pub fn len(&self) -> usize {
+        self.vec.len()
+    }
+
+    /// Returns true if the vector contains no elements
+    pub fn is_empty(&self) -> bool {
+        self.vec.is_empty()
+    }
+
+    /// Returns a thin reference to the element at the given index, or None if out of bounds
+    pub fn get(&self, index: usize) -> Option<ThinRef<'_, Trait>> {
+        self.vec.get(index).map(ThinBox::as_thin_ref)
+    }
+
+    /// Iterate over thin references to the elements
+    pub fn iter(&self) -> impl Iterator<Item = ThinRef<'_, Trait>> {
+        self.vec.iter().map(ThinBox::as_thin_ref)
+    }
+
+    /// Iterate over mutable thin references to the elements
+    ///
+    /// ```rust
+    /// # use vptr::*;
+    /// trait Shape { fn scale(&mut self, f: f32); fn area(&self) -> f32; }
+    /// #[vptr(Shape)]
+    /// #[derive(Default)]
+    /// struct Rectangle { w: f32, h : f32 }
+    /// impl Shape for Rectangle {
+    ///     fn scale(&mut self, f: f32) { self.w *= f; self.h *= f; }
+    ///     fn area(&self) -> f32 { self.w * self.h }
+    /// }
+    /// let mut v = thin_vec![dyn Shape; Rectangle { w: 1., h: 2., ..Default::default() }];
+    /// for mut x in v.iter_mut() {
+    ///     x.scale(2.);
+    /// }
+    /// assert_eq!(v.get(0).unwrap().area(), 8.);
+    /// ```
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = ThinRefMut<'_, Trait>> {
+        self.vec.iter_mut().map(ThinBox::as_thin_ref_mut)
+    }
+
+    /// View the elements as a slice of thin references, without copying them
+    ///
+    /// ```rust
+    /// # use vptr::*;
+    /// trait Shape { fn area(&self) -> f32; }
+    /// #[vptr(Shape)]
+    /// #[derive(Default)]
+    /// struct Rectangle { w: f32, h : f32 }
+    /// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+    ///
+    /// fn total_area(list: &[ThinRef<dyn Shape>]) -> f32 {
+    ///     list.iter().map(|x| x.area()).sum()
+    /// }
+    /// let mut v = ThinVec::<dyn Shape>::new();
+    /// v.push(Rectangle { w: 5., h: 10., ..Default::default() });
+    /// v.push(Rectangle { w: 1., h: 2., ..Default::default() });
+    /// assert_eq!(total_area(v.as_ref_slice()), 52.);
+    /// ```
+    pub fn as_ref_slice(&self) -> &[ThinRef<'_, Trait>] {
+        // Safety: ThinBox and ThinRef are both repr(transparent) over the pointer to the VPtr
+        // field, and the objects are borrowed for the lifetime of the slice
+        unsafe {
+            core::slice::from_raw_parts(
+                self.vec.as_ptr() as *const ThinRef<'_, Trait>,
+                self.vec.len(),
+            )
+        }
+    }
+
+    /// Only keep the elements for which the predicate returns true.
+    /// The other elements are dropped.
+    pub fn retain(&mut self, mut f: impl FnMut(ThinRef<'_, Trait>) -> bool) {
+        self.vec.retain(|b| f(ThinBox::as_thin_ref(b)))
+    }
+
+    /// Removes and returns the element at the given index, shifting the following elements
+    ///
+    /// Panics if the index is out of bounds
+    pub fn remove(&mut self, index: usize) -> ThinBox<Trait> {
+        self.vec.remove(index)
+    }
+
+    /// Removes the elements in the range and returns them as an iterator.
+    /// The elements which are not consumed by the iterator are dropped.
+    pub fn drain(
+        &mut self,
+        range: impl RangeBounds<usize>,
+    ) -> impl Iterator<Item = ThinBox<Trait>> + '_ {
+        self.vec.drain(range)
+    }
+
+    /// Sort the elements so that the elements of the same type are next to each other
+    ///
+    /// Calling the same method on consecutive objects of the same type is faster as it calls
+    /// the same function. The sort is stable, so the order of the elements of the same type is
+    /// preserved. See [`ThinRef::by_vtable_key`]
+    pub fn sort_by_type(&mut self) {
+        self.vec
+            .sort_by_key(|b| ThinRef::by_vtable_key(ThinBox::as_thin_ref(b)))
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<Trait: ?Sized + Sync + 'static> ThinVec<Trait> {
+    /// Parallel iterator over thin references to the elements, with the `rayon` feature
+    ///
+    /// The trait object must be `Sync`, so the trait needs a `Sync` bound (or a `Sync`
+    /// supertrait), as for sharing the elements between threads.
+    ///
+    /// ```rust
+    /// # use vptr::*;
+    /// use rayon::prelude::*;
+    /// trait Shape { fn area(&self) -> f32; }
+    /// #[vptr(Shape + Sync)]
+    /// #[derive(Default)]
+    /// struct Rectangle { w: f32, h : f32 }
+    /// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+    ///
+    /// let mut v = ThinVec::<dyn Shape + Sync>::new();
+    /// for i in 0..100 {
+    ///     v.push(Rectangle { w: i as f32, h: 1., ..Default::default() });
+    /// }
+    /// assert_eq!(v.par_iter().map(|x| x.area()).sum::<f32>(), 4950.);
+    /// ```
+    pub fn par_iter(&self) -> <&Self as rayon::iter::IntoParallelIterator>::Iter {
+        rayon::iter::IntoParallelIterator::into_par_iter(self)
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<'a, Trait: ?Sized + Sync + 'static> rayon::iter::IntoParallelIterator for &'a ThinVec<Trait> {
+    type Iter = rayon::iter::Copied<rayon::slice::Iter<'a, ThinRef<'a, Trait>>>;
+    type Item = ThinRef<'a, Trait>;
+
+    fn into_par_iter(self) -> Self::Iter {
+        use rayon::iter::ParallelIterator;
+        rayon::iter::IntoParallelIterator::into_par_iter(self.as_ref_slice()).copied()
+    }
+}
+
+impl<Trait: ?Sized + 'static> Default for ThinVec<Trait> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Trait: ?Sized + 'static> From<Vec<ThinBox<Trait>>> for ThinVec<Trait> {
+    fn from(vec: Vec<ThinBox<Trait>>) -> Self {
+        ThinVec { vec }
+    }
+}
+
+impl<Trait: ?Sized + 'static> From<ThinVec<Trait>> for Vec<ThinBox<Trait>> {
+    fn from(v: ThinVec<Trait>) -> Self {
+        v.vec
+    }
+}
+
+enum Storage<Trait: ?Sized + 'static, const N: usize> {
+    /// The first `len` slots are `Some`
+    Inline {
+        slots: [Option<ThinBox<Trait>>; N],
+        len: usize,
+    },
+    Heap(Vec<ThinBox<Trait>>),
+}
+
+/// A vector of `ThinBox<Trait>` which stores up to `N` elements inline
+///
+/// Each slot has the size of a pointer, so up to `N` objects can be held without allocating
+/// the vector itself (the objects are still allocated in their own box). When more than `N`
+/// elements are pushed, the elements are moved to the heap.
+///
+/// ```rust
+/// # use vptr::*;
+/// trait Shape { fn area(&self) -> f32; }
+/// #[vptr(Shape)]
+/// #[derive(Default)]
+/// struct Rectangle { w: f32, h : f32 }
+/// impl Shape for Rectangle { fn area(&self) -> f32 { self.w * self.h } }
+///
+/// let mut v = ThinSmallVec::<dyn Shape, 4>::new();
+/// v.push(Rectangle { w: 5., h: 10., ..Default::default() });
+/// v.push(Rectangle { w: 1., h: 2., ..Default::default() });
+/// assert!(!v.spilled());
+/// assert_eq!(v.iter().map(|x| x.area()).sum::<f32>(), 52.);
+/// ```
+pub struct ThinSmallVec<Trait: ?Sized + 'static, const N: usize> {
+    storage: Storage<Trait, N>,
+}
+
+impl<Trait: ?Sized + 'static, const N: usize> ThinSmallVec<Trait, N> {
+    /// Creates an empty vector
+    pub fn new() -> Self {
+        ThinSmallVec {
+            storage: Storage::Inline {
+                slots: [(); N].map(|_| None),
+                len: 0,
+            },
+        }
+    }
+
+    /// Box the value and append it to the vector
+    pub fn push<T: HasVPtr<Trait> + 'static>(&mut self, value: T) {
+        self.push_box(ThinBox::from_box(Box::new(value)))
+    }
+
+    /// Append a ThinBox to the vector
+    pub fn push_box(&mut self, b: ThinBox<Trait>) {
+        match &mut self.storage {
+            Storage::Inline { slots, len } if *len < N => {
+                slots[*len] = Some(b);
+                *len += 1;
+            }
+            Storage::Inline { slots, .. } => {
+                let mut v: Vec<_> = slots.iter_mut().filter_map(Option::take).collect();
+                v.push(b);
+                self.storage = Storage::Heap(v);
+            }
+            Storage::Heap(v) => v.push(b),
+        }
+    }
+
+    /// The number of elements in the vector
+    pub fn len(&self) -> usize {
+        match &self.storage {
+            Storage::Inline { len, .. } => *len,
+            Storage::Heap(v) => v.len(),
+        }
+    }
+
+    /// Returns true if the vector contains no elements
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns true if the elements were moved to the heap
+    pub fn spilled(&self) -> bool {
+        matches!(self.storage, Storage::Heap(_))
+    }
+
+    /// Iterate over thin references to the elements
+    pub fn iter(&self) -> impl Iterator<Item = ThinRef<'_, Trait>> {
+        let (inline, heap): (&[Option<ThinBox<Trait>>], &[ThinBox<Trait>]) = match &self.storage {
+            Storage::Inline { slots, len } => (&slots[..*len], &[]),
+            Storage::Heap(v) => (&[], v),
+        };
+        inline
+            .iter()
+            .flatten()
+            .chain(heap.iter())
+            .map(ThinBox::as_thin_ref)
+    }
+}
+
+impl<Trait: ?Sized + 'static, const N: usize> Default for ThinSmallVec<Trait, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{vptr, VPtr};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    mod vptr {
+        // Because otherwise, the generated code cannot access the vptr crate.
+        pub use crate::*;
+    }
+
+    trait MyTrait {
+        fn myfn(&self) -> u32;
+    }
+
+    #[vptr(MyTrait)]
+    struct Counted {
+        value: u32,
+        drops: Rc<Cell<u32>>,
+    }
+    impl MyTrait for Counted {
+        fn myfn(&self) -> u32 {
+            self.value
+        }
+    }
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    fn counted(value: u32, drops: &Rc<Cell<u32>>) -> Counted {
+        Counted {
+            value,
+            drops: drops.clone(),
+            vptr_MyTrait: VPtr::new(),
+        }
+    }
+
+    #[test]
+    fn thin_vec() {
+        let drops = Rc::new(Cell::new(0));
+        let mut v = ThinVec::<dyn MyTrait>::new();
+        for i in 0..10 {
+            v.push(counted(i, &drops));
+        }
+        assert_eq!(v.len(), 10);
+
+        v.retain(|x| x.myfn() % 3 != 0);
+        assert_eq!(drops.get(), 4);
+        assert_eq!(
+            v.iter().map(|x| x.myfn()).collect::<Vec<_>>(),
+            [1, 2, 4, 5, 7, 8]
+        );
+
+        let removed = v.remove(1);
+        assert_eq!(removed.myfn(), 2);
+        assert_eq!(drops.get(), 4);
+        drop(removed);
+        assert_eq!(drops.get(), 5);
+        assert_eq!(v.get(1).map(|x| x.myfn()), Some(4));
+
+        // Only the first drained element is consumed, the others are dropped with the iterator
+        let first = v.drain(1..4).next().unwrap();
+        assert_eq!(first.myfn(), 4);
+        assert_eq!(drops.get(), 7);
+        assert_eq!(v.iter().map(|x| x.myfn()).collect::<Vec<_>>(), [1, 8]);
+        fn sum(list: &[ThinRef<dyn MyTrait>]) -> u32 {
+            list.iter().map(|x| x.myfn()).sum()
+        }
+        assert_eq!(sum(v.as_ref_slice()), 9);
+        assert_eq!(
+            ThinRef::into_raw(v.as_ref_slice()[1]),
+            ThinRef::into_raw(v.get(1).unwrap())
+        );
+        drop(first);
+        assert_eq!(drops.get(), 8);
+        drop(v);
+        assert_eq!(drops.get(), 10);
+    }
+
+    #[vptr(MyTrait)]
+    struct Other(u32);
+    impl MyTrait for Other {
+        fn myfn(&self) -> u32 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn thin_vec_macro() {
+        let drops = Rc::new(Cell::new(0));
+        let v = crate::thin_vec![dyn MyTrait + 'static; counted(1, &drops), counted(2, &drops), Other(3, VPtr::new())];
+        assert_eq!(v.iter().map(|x| x.myfn()).sum::<u32>(), 6);
+        let w: ThinVec<dyn MyTrait> = crate::thin_vec![Other(4, VPtr::new()), counted(5, &drops),];
+        assert_eq!(w.iter().map(|x| x.myfn()).collect::<Vec<_>>(), [4, 5]);
+        drop((v, w));
+        assert_eq!(drops.get(), 3);
+    }
+
+    #[test]
+    fn iter_mut() {
+        trait Accumulate {
+            fn add(&mut self, x: u32);
+            fn get(&self) -> u32;
+        }
+        #[vptr(Accumulate)]
+        struct Sum(u32);
+        impl Accumulate for Sum {
+            fn add(&mut self, x: u32) {
+                self.0 += x;
+            }
+            fn get(&self) -> u32 {
+                self.0
+            }
+        }
+        #[vptr(Accumulate)]
+        struct Last(u32, u32);
+        impl Accumulate for Last {
+            fn add(&mut self, x: u32) {
+                self.0 = x;
+                self.1 += 1;
+            }
+            fn get(&self) -> u32 {
+                self.0 * 100 + self.1
+            }
+        }
+
+        let mut v = ThinVec::<dyn Accumulate>::new();
+        v.push(Sum(1, VPtr::new()));
+        v.push(Last(0, 0, VPtr::new()));
+        v.push(Sum(10, VPtr::new()));
+        for round in 1..=3 {
+            for (i, mut x) in v.iter_mut().enumerate() {
+                x.add(round * (i as u32 + 1));
+            }
+        }
+        // Each element was only modified through its own reference
+        assert_eq!(v.iter().map(|x| x.get()).collect::<Vec<_>>(), [7, 603, 28]);
+        let refs: Vec<_> = v.iter_mut().map(ThinRefMut::into_raw).collect();
+        assert_eq!(refs.len(), 3);
+        assert!(refs.windows(2).all(|w| w[0] != w[1]));
+    }
+
+    #[test]
+    fn parallel() {
+        trait Shape {
+            fn area(&self) -> u64;
+        }
+        #[vptr(Shape + Sync)]
+        struct Square(u64);
+        impl Shape for Square {
+            fn area(&self) -> u64 {
+                self.0 * self.0
+            }
+        }
+        #[vptr(Shape + Sync)]
+        struct Rect(u64, u64);
+        impl Shape for Rect {
+            fn area(&self) -> u64 {
+                self.0 * self.1
+            }
+        }
+
+        fn assert_sync<T: Sync + Send>(_: &T) {}
+        let mut v = ThinVec::<dyn Shape + Sync>::new();
+        for i in 0..1000 {
+            if i % 2 == 0 {
+                v.push(Square(i, VPtr::new()));
+            } else {
+                v.push(Rect(i, 2, VPtr::new()));
+            }
+        }
+        assert_sync(&v.as_ref_slice()[0]);
+        let expected: u64 = v.iter().map(|x| x.area()).sum();
+        let total: u64 = std::thread::scope(|s| {
+            let handles: Vec<_> = v
+                .as_ref_slice()
+                .chunks(64)
+                .map(|chunk| s.spawn(move || chunk.iter().map(|x| x.area()).sum::<u64>()))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+        assert_eq!(total, expected);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_iter() {
+        use rayon::iter::{IntoParallelIterator, ParallelIterator};
+        trait Shape {
+            fn area(&self) -> u64;
+        }
+        #[vptr(Shape + Sync)]
+        struct Square(u64);
+        impl Shape for Square {
+            fn area(&self) -> u64 {
+                self.0 * self.0
+            }
+        }
+        #[vptr(Shape + Sync)]
+        struct Rect(u64, u64);
+        impl Shape for Rect {
+            fn area(&self) -> u64 {
+                self.0 * self.1
+            }
+        }
+
+        let mut v = ThinVec::<dyn Shape + Sync>::new();
+        for i in 0..1000 {
+            if i % 2 == 0 {
+                v.push(Square(i, VPtr::new()));
+            } else {
+                v.push(Rect(i, 2, VPtr::new()));
+            }
+        }
+        let expected: u64 = v.iter().map(|x| x.area()).sum();
+        assert_eq!(v.par_iter().map(|x| x.area()).sum::<u64>(), expected);
+        assert_eq!((&v).into_par_iter().count(), 1000);
+    }
+
+    #[test]
+    fn sort_by_type() {
+        let drops = Rc::new(Cell::new(0));
+        let mut v = ThinVec::<dyn MyTrait>::new();
+        for i in 0..10 {
+            if i % 3 == 0 {
+                v.push(counted(i, &drops));
+            } else {
+                v.push(Other(i, VPtr::new()));
+            }
+        }
+        v.sort_by_type();
+        let keys: Vec<_> = v.iter().map(ThinRef::by_vtable_key).collect();
+        assert_eq!(keys.windows(2).filter(|w| w[0] != w[1]).count(), 1);
+        let values: Vec<_> = v.iter().map(|x| x.myfn()).collect();
+        let counted_first = values[0] == 0;
+        let expected: &[u32] = if counted_first {
+            &[0, 3, 6, 9, 1, 2, 4, 5, 7, 8]
+        } else {
+            &[1, 2, 4, 5, 7, 8, 0, 3, 6, 9]
+        };
+        assert_eq!(values, expected);
+        drop(v);
+        assert_eq!(drops.get(), 4);
+    }
+
+    #[test]
+    fn small_vec() {
+        let drops = Rc::new(Cell::new(0));
+        let mut v = ThinSmallVec::<dyn MyTrait, 2>::new();
+        assert!(v.is_empty());
+        v.push(counted(1, &drops));
+        v.push(counted(2, &drops));
+        assert!(!v.spilled());
+        assert_eq!(v.len(), 2);
+        assert_eq!(v.iter().map(|x| x.myfn()).collect::<Vec<_>>(), [1, 2]);
+
+        v.push(counted(3, &drops));
+        v.push(counted(4, &drops));
+        assert!(v.spilled());
+        assert_eq!(v.len(), 4);
+        assert_eq!(v.iter().map(|x| x.myfn()).collect::<Vec<_>>(), [1, 2, 3, 4]);
+        assert_eq!(drops.get(), 0);
+        drop(v);
+        assert_eq!(drops.get(), 4);
+
+        let mut v = ThinSmallVec::<dyn MyTrait, 3>::default();
+        v.push(counted(1, &drops));
+        drop(v);
+        assert_eq!(drops.get(), 5);
+    }
+}
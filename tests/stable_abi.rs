@@ -0,0 +1,151 @@
+//! Use a StableThinRef across a plugin boundary
+//!
+//! The `plugin` module stands for a dynamic library built separately: the host only sees its
+//! `extern "C"` functions, the raw pointers, and the declaration of the vtable.
+
+#![cfg(feature = "abi_stable")]
+
+use abi_stable::abi_stability::abi_checking::check_layout_compatibility;
+use abi_stable::type_layout::TypeLayout;
+use abi_stable::StableAbi;
+use std::ffi::c_void;
+use vptr::StableThinRef;
+
+/// The interface shared by the host and the plugin
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct CounterVTable {
+    pub get: unsafe extern "C" fn(*const c_void) -> u32,
+    pub add: unsafe extern "C" fn(*mut c_void, u32),
+    pub name: unsafe extern "C" fn(*const c_void) -> *const u8,
+}
+
+/// Another version of the interface, which is not compatible
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct OtherCounterVTable {
+    pub get: unsafe extern "C" fn(*const c_void) -> u64,
+}
+
+mod plugin {
+    use super::CounterVTable;
+    use std::ffi::c_void;
+    use vptr::*;
+
+    trait Counter {
+        fn get(&self) -> u32;
+        fn add(&mut self, n: u32);
+        fn name(&self) -> *const u8;
+    }
+
+    #[vptr(stable(
+        Counter,
+        CounterVTable,
+        "fn get(&self) -> u32",
+        "fn add(&mut self, n: u32)",
+        "fn name(&self) -> *const u8"
+    ))]
+    struct Count {
+        value: u32,
+    }
+    impl Counter for Count {
+        fn get(&self) -> u32 {
+            self.value
+        }
+        fn add(&mut self, n: u32) {
+            self.value += n
+        }
+        fn name(&self) -> *const u8 {
+            b"count\0".as_ptr()
+        }
+    }
+
+    #[vptr(stable(
+        Counter,
+        CounterVTable,
+        "fn get(&self) -> u32",
+        "fn add(&mut self, n: u32)",
+        "fn name(&self) -> *const u8"
+    ))]
+    struct Twice {
+        #[allow(dead_code)]
+        padding: [u64; 3],
+        value: u32,
+    }
+    impl Counter for Twice {
+        fn get(&self) -> u32 {
+            self.value * 2
+        }
+        fn add(&mut self, n: u32) {
+            self.value += n
+        }
+        fn name(&self) -> *const u8 {
+            b"twice\0".as_ptr()
+        }
+    }
+
+    /// Returns the raw pointer of a new counter, to be freed with `plugin_free`
+    pub extern "C" fn plugin_new(twice: bool, value: u32) -> *mut c_void {
+        unsafe fn raw<T: HasStableVPtr<CounterVTable>>(object: Box<T>) -> *mut c_void {
+            let object = Box::into_raw(object);
+            (object as *mut u8).wrapping_offset(T::init().offset) as *mut c_void
+        }
+        unsafe {
+            if twice {
+                raw(Box::new(Twice {
+                    padding: [0; 3],
+                    value,
+                    vptr_CounterVTable: StableVPtr::new(),
+                }))
+            } else {
+                raw(Box::new(Count {
+                    value,
+                    vptr_CounterVTable: StableVPtr::new(),
+                }))
+            }
+        }
+    }
+
+    pub extern "C" fn plugin_free(this: *mut c_void, twice: bool) {
+        unsafe fn free<T: HasStableVPtr<CounterVTable>>(this: *mut c_void) {
+            drop(Box::from_raw(
+                (this as *mut u8).wrapping_offset(-T::init().offset) as *mut T,
+            ));
+        }
+        unsafe {
+            if twice {
+                free::<Twice>(this)
+            } else {
+                free::<Count>(this)
+            }
+        }
+    }
+
+    /// The layout that the plugin was compiled with
+    pub extern "C" fn plugin_layout() -> &'static abi_stable::type_layout::TypeLayout {
+        <StableThinRef<'static, CounterVTable> as abi_stable::StableAbi>::LAYOUT
+    }
+}
+
+#[test]
+fn plugin() {
+    let expected: &'static TypeLayout = <StableThinRef<'static, CounterVTable>>::LAYOUT;
+    check_layout_compatibility(expected, plugin::plugin_layout()).unwrap();
+    let other: &'static TypeLayout = <StableThinRef<'static, OtherCounterVTable>>::LAYOUT;
+    assert!(check_layout_compatibility(other, plugin::plugin_layout()).is_err());
+
+    for (twice, factor, name) in [(false, 1, &b"count"[..]), (true, 2, &b"twice"[..])] {
+        let raw = plugin::plugin_new(twice, 3);
+        let thin = unsafe { StableThinRef::<CounterVTable>::from_raw(raw) };
+        assert_eq!(std::mem::size_of_val(&thin), std::mem::size_of::<usize>());
+        let vtable = StableThinRef::vtable(thin);
+        unsafe {
+            assert_eq!((vtable.get)(raw), 3 * factor);
+            (vtable.add)(raw, 4);
+            assert_eq!((vtable.get)(raw), 7 * factor);
+            let n = std::ffi::CStr::from_ptr((vtable.name)(raw) as *const std::ffi::c_char);
+            assert_eq!(n.to_bytes(), name);
+        }
+        plugin::plugin_free(raw, twice);
+    }
+}